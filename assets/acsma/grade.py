@@ -1,3 +1,4 @@
+import json
 import sys
 
 verbose = False
@@ -48,8 +49,35 @@ def main(in_file, out_file):
         )
 
 
+def perf(report_file, min_kbps):
+    # The output of `racsma perf --json`, one report per line with the summary last.
+    with open(report_file) as f:
+        reports = [json.loads(line) for line in f if line.startswith("{")]
+
+    summary = [report for report in reports if report["kind"] == "summary"]
+    if not summary:
+        print("FAIL: No summary in the report")
+        return
+    summary = summary[-1]
+
+    rtt = summary["rtt_ms"]
+    print(
+        f"Sent {summary['throughput_kbps']:.2f} kbps, "
+        f"received {summary['goodput_kbps']:.2f} kbps, "
+        f"loss {summary['loss_rate']}, "
+        f"retransmissions {summary['retransmission_rate']}, "
+        f"rtt p50 {rtt['p50']} ms p99 {rtt['p99']} ms"
+    )
+    if summary["throughput_kbps"] >= min_kbps:
+        print(f"PASS: Throughput above {min_kbps} kbps")
+    else:
+        print(f"FAIL: Throughput below {min_kbps} kbps")
+
+
 if __name__ == "__main__":
-    if sys.argv[1] == "-v":
+    if sys.argv[1] == "--perf":
+        perf(sys.argv[2], float(sys.argv[3]) if len(sys.argv) > 3 else 0)
+    elif sys.argv[1] == "-v":
         verbose = True
         main(sys.argv[2], sys.argv[3])
     else:
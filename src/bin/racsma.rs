@@ -1,17 +1,25 @@
 use anyhow::Result;
 use bitvec::prelude::*;
-use clap::{Parser, Subcommand, ValueEnum};
-use rathernet::racsma::{AcsmaIoSocket, AcsmaIoStream, AcsmaSocketConfig, AcsmaStreamConfig};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+#[cfg(feature = "sim")]
+use rathernet::racsma::AcsmaSimScenarioFile;
+use rathernet::racsma::{
+    parse_address, AcsmaCaptureWriter, AcsmaIoSocket, AcsmaIoStream, AcsmaPerfConfig,
+    AcsmaPerfMode, AcsmaPerfPattern, AcsmaPerfReport, AcsmaSocketConfig, AcsmaStreamConfig,
+    AcsmaTimingConfig,
+};
 use rathernet::rather::builtin::PAYLOAD_BITS_LEN;
 use rathernet::rather::{AtherInputStream, AtherOutputStream, AtherStreamConfig};
 use rathernet::raudio::{AsioDevice, AudioInputStream, AudioOutputStream};
 use rodio::DeviceTrait;
 use rodio::SupportedStreamConfig;
 use std::fs::{self, File};
-use std::io;
+use std::io::{self, BufWriter};
 use std::net::Ipv4Addr;
 use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::signal;
 use tokio_stream::StreamExt;
 
 #[derive(Parser, Debug)]
@@ -101,8 +109,14 @@ enum Commands {
         /// The peer address that will receive the file.
         #[clap(short, long, default_value = "0", value_parser = parse_address)]
         peer: usize,
+        /// The number of frames that may be in flight at once.
+        #[clap(short, long)]
+        window: Option<usize>,
+        #[command(flatten)]
+        timing: TimingArgs,
     },
-    /// Measure the performance of the acsma.
+    /// Measure the performance of the acsma. Run it on both ends, in `both` mode for traffic in
+    /// both directions at once.
     Perf {
         /// The device used to send the bits.
         #[clap(short, long)]
@@ -113,6 +127,26 @@ enum Commands {
         /// The peer address that will receive the bits.
         #[clap(short, long, default_value = "0", value_parser = parse_address)]
         peer: usize,
+        /// The number of frames that may be in flight at once.
+        #[clap(short, long)]
+        window: Option<usize>,
+        /// How long to run in seconds, 0 to run until interrupted.
+        #[clap(long, default_value = "10")]
+        time: u64,
+        /// The payload of the frames.
+        #[clap(long, default_value = "zeros")]
+        pattern: PerfPattern,
+        /// Whether to send, only receive, or both.
+        #[clap(short, long, default_value = "send")]
+        mode: PerfMode,
+        /// The interval between two reports in milliseconds.
+        #[clap(long, default_value = "1000")]
+        interval: u64,
+        /// Prints the reports as JSON lines, the last one being the summary.
+        #[clap(long, default_value = "false")]
+        json: bool,
+        #[command(flatten)]
+        timing: TimingArgs,
     },
     /// Keep the acsma running to serve activities from peers
     Serve {
@@ -125,6 +159,31 @@ enum Commands {
         /// The ip address that will be used to serve the activities.
         #[clap(short, long)]
         ip: Option<Ipv4Addr>,
+        /// The number of frames that may be in flight at once.
+        #[clap(short, long)]
+        window: Option<usize>,
+        #[command(flatten)]
+        timing: TimingArgs,
+    },
+    /// Serve like `serve` while printing the statistics of the socket.
+    Stats {
+        /// The device used to send the bits.
+        #[clap(short, long)]
+        device: Option<String>,
+        /// The address that will be used to send the bits.
+        #[clap(short, long, default_value = "0", value_parser = parse_address)]
+        address: usize,
+        /// The ip address that will be used to serve the activities.
+        #[clap(short, long)]
+        ip: Option<Ipv4Addr>,
+        /// The number of frames that may be in flight at once.
+        #[clap(short, long)]
+        window: Option<usize>,
+        /// The interval between two prints in milliseconds.
+        #[clap(long, default_value = "1000")]
+        interval: u64,
+        #[command(flatten)]
+        timing: TimingArgs,
     },
     /// Ping a peer to check if it is alive.
     Ping {
@@ -137,6 +196,8 @@ enum Commands {
         /// The peer address that will be pinged.
         #[clap(short, long, default_value = "0", value_parser = parse_address)]
         peer: usize,
+        #[command(flatten)]
+        timing: TimingArgs,
     },
     /// Arp a peer to check get its ip address.
     Arp {
@@ -146,12 +207,120 @@ enum Commands {
         /// The address that will be used to arp the peer.
         #[clap(short, long, default_value = "0", value_parser = parse_address)]
         address: usize,
-        /// The target ip address that will be arped.
+        /// The target ip addresses that will be arped.
+        #[arg(required_unless_present_any = ["show", "flush"])]
+        targets: Vec<Ipv4Addr>,
+        /// Shows the ARP cache of the socket after resolving the targets, including the entries
+        /// learned from frames overheard meanwhile.
+        #[clap(short, long, default_value = "false")]
+        show: bool,
+        /// Flushes the ARP cache of the socket before resolving the targets.
+        #[clap(short, long, default_value = "false")]
+        flush: bool,
+        #[command(flatten)]
+        timing: TimingArgs,
+    },
+    /// Send neighbor discovery hellos while printing the neighbor table of the socket.
+    Neighbors {
+        /// The device used to send the bits.
+        #[clap(short, long)]
+        device: Option<String>,
+        /// The address that will be used to send the bits.
+        #[clap(short, long, default_value = "0", value_parser = parse_address)]
+        address: usize,
+        /// The interval between two hellos in milliseconds.
+        #[clap(long, default_value = "2000")]
+        hello: u64,
+        /// The interval between two prints in milliseconds.
+        #[clap(long, default_value = "1000")]
+        interval: u64,
+        #[command(flatten)]
+        timing: TimingArgs,
+    },
+    /// Serve like `serve` while writing every frame on the medium to a pcapng file. Frames carry
+    /// a pseudo-header of 24 bytes under the link type USER0 (147), to look into the packets of
+    /// rateway, decode the link type as `ip` with a header size of 24.
+    #[command(arg_required_else_help = true)]
+    Capture {
+        /// The pcapng file to write the frames to.
+        #[arg(required = true)]
+        file: PathBuf,
+        /// The device used to listen to the medium.
+        #[clap(short, long)]
+        device: Option<String>,
+        /// The address that will be used to serve the activities.
+        #[clap(short, long, default_value = "0", value_parser = parse_address)]
+        address: usize,
+        /// Stop after that many seconds instead of on Ctrl-C.
+        #[clap(short, long)]
+        time: Option<u64>,
+        #[command(flatten)]
+        timing: TimingArgs,
+    },
+    /// Run nodes against a simulated medium under a virtual clock, as described by a scenario
+    /// file, and report throughput, fairness and collisions. Fails if the report misses the
+    /// expectations of the scenario, so that scenarios can be run as regression tests.
+    #[cfg(feature = "sim")]
+    #[command(arg_required_else_help = true)]
+    Simulate {
+        /// The path to the scenario file.
         #[arg(required = true)]
-        target: Ipv4Addr,
+        scenario: PathBuf,
     },
 }
 
+/// Overrides of the MAC timings, which default to the builtin ones scaled to the bit rate.
+#[derive(Args, Debug)]
+struct TimingArgs {
+    /// The backoff slot in milliseconds.
+    #[clap(long)]
+    slot: Option<u64>,
+    /// The ACK timeout in milliseconds.
+    #[clap(long)]
+    ack_timeout: Option<u64>,
+    /// How long to listen after receiving a data frame before sending, in milliseconds.
+    #[clap(long)]
+    receive_timeout: Option<u64>,
+    /// The number of resends before a frame fails with a link error.
+    #[clap(long)]
+    max_resends: Option<usize>,
+    /// The upper bound of the backoff range in slots.
+    #[clap(long)]
+    max_range: Option<usize>,
+    /// The energy below which the medium is considered free.
+    #[clap(long)]
+    free_threshold: Option<f32>,
+    /// The energy above which a transmission is considered to collide.
+    #[clap(long)]
+    collision_threshold: Option<f32>,
+}
+
+impl TimingArgs {
+    fn apply(&self, timing: &mut AcsmaTimingConfig) {
+        if let Some(slot) = self.slot {
+            timing.slot = Duration::from_millis(slot);
+        }
+        if let Some(ack_timeout) = self.ack_timeout {
+            timing.ack_timeout = Duration::from_millis(ack_timeout);
+        }
+        if let Some(receive_timeout) = self.receive_timeout {
+            timing.receive_timeout = Duration::from_millis(receive_timeout);
+        }
+        if let Some(max_resends) = self.max_resends {
+            timing.max_resends = max_resends;
+        }
+        if let Some(max_range) = self.max_range {
+            timing.max_range = max_range;
+        }
+        if let Some(free_threshold) = self.free_threshold {
+            timing.free_threshold = free_threshold;
+        }
+        if let Some(collision_threshold) = self.collision_threshold {
+            timing.collision_threshold = collision_threshold;
+        }
+    }
+}
+
 #[derive(ValueEnum, Clone, Copy, Debug)]
 enum CalibrateType {
     Read,
@@ -159,12 +328,39 @@ enum CalibrateType {
     Duplex,
 }
 
-fn parse_address(src: &str) -> Result<usize> {
-    let num = src.parse::<usize>()?;
-    if num < 16 {
-        Ok(num)
-    } else {
-        Err(RacsmaError::InvalidAddress(num).into())
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PerfPattern {
+    Zeros,
+    Ones,
+    Alternating,
+    Random,
+}
+
+impl From<PerfPattern> for AcsmaPerfPattern {
+    fn from(value: PerfPattern) -> Self {
+        match value {
+            PerfPattern::Zeros => Self::Zeros,
+            PerfPattern::Ones => Self::Ones,
+            PerfPattern::Alternating => Self::Alternating,
+            PerfPattern::Random => Self::Random,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum PerfMode {
+    Send,
+    Receive,
+    Both,
+}
+
+impl From<PerfMode> for AcsmaPerfMode {
+    fn from(value: PerfMode) -> Self {
+        match value {
+            PerfMode::Send => Self::Send,
+            PerfMode::Receive => Self::Receive,
+            PerfMode::Both => Self::Both,
+        }
     }
 }
 
@@ -172,8 +368,6 @@ fn parse_address(src: &str) -> Result<usize> {
 enum RacsmaError {
     #[error("Invalid character in file (expect 0 or 1, found `{0}`)")]
     InvalidChar(char),
-    #[error("Invalid address (expect 0-15, found `{0}`)")]
-    InvalidAddress(usize),
 }
 
 fn create_device(device: Option<String>) -> Result<AsioDevice> {
@@ -242,6 +436,42 @@ fn dump_bits(mut buf: BitVec, file: Option<PathBuf>, chars: bool) -> Result<()>
     Ok(())
 }
 
+/// Serve activities from peers, printing the statistics of the socket at the given interval in
+/// milliseconds if any.
+async fn serve(
+    device: Option<String>,
+    address: usize,
+    ip: Option<Ipv4Addr>,
+    window: Option<usize>,
+    timing: TimingArgs,
+    interval: Option<u64>,
+) -> Result<()> {
+    let device = create_device(device)?;
+    let stream_config = create_stream_config(&device)?;
+    let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
+
+    let ip = ip.map(|ip| u32::from_be_bytes(ip.octets()) as usize);
+    let mut socket_config = AcsmaSocketConfig::new(address, ip, ather_config);
+    timing.apply(&mut socket_config.timing);
+    if let Some(window) = window {
+        socket_config.window = window;
+    }
+    let (_, mut rx_socket, _) = AcsmaIoSocket::try_from_device(socket_config, &device).await?;
+
+    if let Some(interval) = interval {
+        let stats = rx_socket.stats_handle();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(interval)).await;
+                // Clear the terminal, so that the view stays in place
+                print!("\x1B[2J\x1B[H");
+                println!("{}", stats.snapshot());
+            }
+        });
+    }
+    rx_socket.serve().await
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -352,14 +582,20 @@ async fn main() -> Result<()> {
             chars,
             address,
             peer,
+            window,
+            timing,
         } => {
             let device = create_device(device)?;
             let stream_config = create_stream_config(&device)?;
             let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
 
-            let socket_config = AcsmaSocketConfig::new(address, None, ather_config);
-            let (tx_socket, mut rx_socket) =
-                AcsmaIoSocket::try_from_device(socket_config, &device)?;
+            let mut socket_config = AcsmaSocketConfig::new(address, None, ather_config);
+            timing.apply(&mut socket_config.timing);
+            if let Some(window) = window {
+                socket_config.window = window;
+            }
+            let (tx_socket, mut rx_socket, _) =
+                AcsmaIoSocket::try_from_device(socket_config, &device).await?;
 
             let bits = load_bits(source, chars)?;
             let (_, buf) = tokio::try_join!(tx_socket.write(peer, &bits), rx_socket.read(peer))?;
@@ -369,60 +605,224 @@ async fn main() -> Result<()> {
             device,
             address,
             peer,
+            window,
+            time,
+            pattern,
+            mode,
+            interval,
+            json,
+            timing,
         } => {
             let device = create_device(device)?;
             let stream_config = create_stream_config(&device)?;
             let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
 
-            let socket_config = AcsmaSocketConfig::new(address, None, ather_config);
-            let (tx_socket, _) = AcsmaIoSocket::try_from_device(socket_config, &device)?;
+            let mut socket_config = AcsmaSocketConfig::new(address, None, ather_config);
+            timing.apply(&mut socket_config.timing);
+            if let Some(window) = window {
+                socket_config.window = window;
+            }
+            let (tx_socket, mut rx_socket, _) =
+                AcsmaIoSocket::try_from_device(socket_config, &device).await?;
 
-            tx_socket.perf(peer).await?;
+            let duration = (time > 0).then(|| Duration::from_secs(time));
+            let mut perf_config = AcsmaPerfConfig::new(duration, pattern.into(), mode.into());
+            perf_config.interval = Some(Duration::from_millis(interval));
+            let on_interval = |interval: &AcsmaPerfReport| {
+                if json {
+                    println!("{}", interval.to_json("interval"));
+                } else {
+                    println!("{}", interval);
+                }
+            };
+            // The frames of the peer are only counted, they are no packets.
+            let report = tokio::select! {
+                report = tx_socket.perf(peer, perf_config, on_interval) => report?,
+                result = rx_socket.serve() => return result,
+            };
+            if json {
+                println!("{}", report.to_json("summary"));
+            } else {
+                println!("Summary:\n{}", report);
+            }
         }
         Commands::Serve {
             device,
             address,
             ip,
+            window,
+            timing,
+        } => {
+            serve(device, address, ip, window, timing, None).await?;
+        }
+        Commands::Stats {
+            device,
+            address,
+            ip,
+            window,
+            interval,
+            timing,
+        } => {
+            serve(device, address, ip, window, timing, Some(interval)).await?;
+        }
+        Commands::Ping {
+            device,
+            address,
+            peer,
+            timing,
         } => {
             let device = create_device(device)?;
             let stream_config = create_stream_config(&device)?;
             let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
 
-            let ip = ip.map(|ip| u32::from_be_bytes(ip.octets()) as usize);
-            let socket_config = AcsmaSocketConfig::new(address, ip, ather_config);
-            let (_, mut rx_socket) = AcsmaIoSocket::try_from_device(socket_config, &device)?;
+            let mut socket_config = AcsmaSocketConfig::new(address, None, ather_config);
+            timing.apply(&mut socket_config.timing);
+            let (tx_socket, _, _) = AcsmaIoSocket::try_from_device(socket_config, &device).await?;
+            tx_socket.ping(peer).await?;
+        }
+        Commands::Arp {
+            device,
+            address,
+            targets,
+            show,
+            flush,
+            timing,
+        } => {
+            let device = create_device(device)?;
+            let stream_config = create_stream_config(&device)?;
+            let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
 
-            rx_socket.serve().await?;
+            let mut socket_config = AcsmaSocketConfig::new(address, None, ather_config);
+            timing.apply(&mut socket_config.timing);
+            let (tx_socket, _, socket) =
+                AcsmaIoSocket::try_from_device(socket_config, &device).await?;
+
+            if flush {
+                tx_socket.flush_arp();
+            }
+            for ip in targets {
+                let target = u32::from_be_bytes(ip.octets()) as usize;
+                println!("Who has {}? Tell {}", ip, address);
+                match tx_socket.arp(target).await {
+                    Ok(result) => println!("{} is at {}", ip, result),
+                    Err(err) => println!("{}: {}", ip, err),
+                }
+            }
+
+            if show {
+                for entry in tx_socket.arp_entries() {
+                    let ip = Ipv4Addr::from(entry.ip as u32);
+                    match entry.mac {
+                        Some(mac) => println!("{} at {} ({} s)", ip, mac, entry.ttl.as_secs()),
+                        None => println!("{} unreachable ({} s)", ip, entry.ttl.as_secs()),
+                    }
+                }
+            }
+            socket.shutdown().await?;
         }
-        Commands::Ping {
+        Commands::Neighbors {
             device,
             address,
-            peer,
+            hello,
+            interval,
+            timing,
         } => {
             let device = create_device(device)?;
             let stream_config = create_stream_config(&device)?;
             let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
 
-            let socket_config = AcsmaSocketConfig::new(address, None, ather_config);
-            let (tx_socket, _) = AcsmaIoSocket::try_from_device(socket_config, &device)?;
-            tx_socket.ping(peer).await?;
+            let mut socket_config = AcsmaSocketConfig::new(address, None, ather_config);
+            timing.apply(&mut socket_config.timing);
+            socket_config.hello = Some(Duration::from_millis(hello));
+            let (tx_socket, mut rx_socket, _) =
+                AcsmaIoSocket::try_from_device(socket_config, &device).await?;
+
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(interval)).await;
+                    // Clear the terminal, so that the view stays in place
+                    print!("\x1B[2J\x1B[H");
+                    println!("mac\theard\trx\ttx\tetx\tsnr\trate");
+                    for neighbor in tx_socket.neighbors() {
+                        let show = |value: Option<f32>| {
+                            value.map_or("-".to_owned(), |value| format!("{:.2}", value))
+                        };
+                        println!(
+                            "{}\t{} ms\t{}\t{}\t{}\t{}\t{}",
+                            neighbor.mac,
+                            neighbor.last_heard.as_millis(),
+                            show(neighbor.reverse_ratio),
+                            show(neighbor.forward_ratio),
+                            show(neighbor.etx()),
+                            show(neighbor.snr),
+                            neighbor
+                                .bit_rate
+                                .map_or("-".to_owned(), |bit_rate| bit_rate.to_string()),
+                        );
+                    }
+                }
+            });
+            rx_socket.serve().await?;
         }
-        Commands::Arp {
+        Commands::Capture {
+            file,
             device,
             address,
-            target: ip,
+            time,
+            timing,
         } => {
             let device = create_device(device)?;
             let stream_config = create_stream_config(&device)?;
             let ather_config = AtherStreamConfig::new(24000, stream_config.clone());
 
-            let socket_config = AcsmaSocketConfig::new(address, None, ather_config);
-            let (tx_socket, _) = AcsmaIoSocket::try_from_device(socket_config, &device)?;
+            let bit_rate = ather_config.bit_rate;
+            let mut socket_config = AcsmaSocketConfig::new(address, None, ather_config);
+            timing.apply(&mut socket_config.timing);
+            let (_, mut rx_socket, socket) =
+                AcsmaIoSocket::try_from_device(socket_config, &device).await?;
+
+            let mut records = socket.capture();
+            let mut writer =
+                AcsmaCaptureWriter::new(BufWriter::new(File::create(file)?), bit_rate)?;
+            let stop = async {
+                match time {
+                    Some(time) => tokio::time::sleep(Duration::from_secs(time)).await,
+                    None => {
+                        let _ = signal::ctrl_c().await;
+                    }
+                }
+            };
+            tokio::pin!(stop);
+            let mut count = 0usize;
+            loop {
+                tokio::select! {
+                    Some(record) = records.recv() => {
+                        // Flush every frame, so that the file can be followed while capturing
+                        writer.write(&record)?;
+                        writer.flush()?;
+                        count += 1;
+                    }
+                    result = rx_socket.serve() => {
+                        result?;
+                        break;
+                    }
+                    _ = &mut stop => break,
+                }
+            }
+            println!("Captured {} frames", count);
+            socket.shutdown().await?;
+        }
+        #[cfg(feature = "sim")]
+        Commands::Simulate { scenario } => {
+            let scenario = AcsmaSimScenarioFile::parse(&fs::read_to_string(scenario)?)?;
 
-            let target = u32::from_be_bytes(ip.octets()) as usize;
-            println!("Who has {}? Tell {}", ip, address);
-            let result = tx_socket.arp(target).await?;
-            println!("{} is at {}", ip, result);
+            let sim_scenario = scenario.translate();
+            // The simulation runs on a runtime of its own, with a paused clock.
+            let report = tokio::task::spawn_blocking(move || sim_scenario.run()).await??;
+            println!("{}", report);
+            if let Some(expect) = &scenario.expect {
+                expect.check(&report)?;
+            }
         }
     }
     Ok(())
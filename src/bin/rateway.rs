@@ -3,7 +3,10 @@ use clap::{Parser, Subcommand};
 use cpal::SupportedStreamConfig;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use rathernet::{
-    racsma::AcsmaSocketConfig,
+    racsma::{
+        builtin::SOCKET_TDMA_SLOT, parse_address, parse_psk, AcsmaQueueDiscipline,
+        AcsmaSocketConfig, AcsmaSocketMode, AcsmaTdmaConfig,
+    },
     rateway::{tools::ping, AtewayAdapterConfig, AtewayIoAdaper, AtewayIoNat, AtewayNatConfig},
     rather::AtherStreamConfig,
     raudio::AsioDevice,
@@ -21,8 +24,9 @@ use std::{
 use tokio::{
     io::AsyncWriteExt,
     net::{TcpListener, TcpSocket, UdpSocket},
-    time,
+    signal, time,
 };
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 #[clap(name = "rateway", version = "0.1.0", author = "Rathernet")]
@@ -149,6 +153,16 @@ fn create_stream_config(device: &AsioDevice) -> Result<SupportedStreamConfig> {
     Ok(stream_config)
 }
 
+/// Shut a gateway down gracefully on Ctrl-C instead of killing it with its audio streams open.
+fn cancel_on_ctrl_c(token: CancellationToken) {
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            log::info!("Shutting down");
+            token.cancel();
+        }
+    });
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -164,6 +178,7 @@ async fn main() -> Result<()> {
 
             let adapter_config = translate_adapter(config, ather_config);
             let adapter = AtewayIoAdaper::new(adapter_config, device);
+            cancel_on_ctrl_c(adapter.token());
             adapter.await?;
         }
         SubCommand::Nat { config } => {
@@ -176,6 +191,7 @@ async fn main() -> Result<()> {
 
             let nat_config = translate_nat(config, ather_config)?;
             let nat = AtewayIoNat::new(nat_config, device);
+            cancel_on_ctrl_c(nat.token());
             nat.await?;
         }
         SubCommand::Udp { cmd } => match cmd {
@@ -360,6 +376,36 @@ struct RatewaySocketConfig {
     #[serde(rename = "mac", deserialize_with = "deserialize_mac")]
     address: usize,
     device: Option<String>,
+    window: Option<usize>,
+    exclusive: Option<bool>,
+    rts_threshold: Option<usize>,
+    block_ack: Option<bool>,
+    #[serde(rename = "hello")]
+    hello_ms: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_psk")]
+    psk: Option<[u8; 32]>,
+    queue_len: Option<usize>,
+    codel: Option<bool>,
+    #[serde(rename = "slot")]
+    slot_ms: Option<u64>,
+    #[serde(rename = "ack_timeout")]
+    ack_timeout_ms: Option<u64>,
+    #[serde(rename = "receive_timeout")]
+    receive_timeout_ms: Option<u64>,
+    max_resends: Option<usize>,
+    max_range: Option<usize>,
+    free_threshold: Option<f32>,
+    collision_threshold: Option<f32>,
+    tdma: Option<RatewayTdmaConfig>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct RatewayTdmaConfig {
+    coordinator: Option<bool>,
+    #[serde(rename = "slot")]
+    slot_ms: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_macs")]
+    stations: Vec<usize>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -384,11 +430,7 @@ fn translate_adapter(
         config.address,
         config.netmask,
         config.gateway,
-        AcsmaSocketConfig::new(
-            config.socket_config.address,
-            Some(u32::from_be_bytes(config.address.octets()) as usize),
-            ather_config,
-        ),
+        translate_socket(&config.socket_config, config.address, ather_config),
     )
 }
 
@@ -411,19 +453,96 @@ fn translate_nat(
         config.address,
         config.netmask,
         config.host,
-        AcsmaSocketConfig::new(
-            config.socket_config.address,
-            Some(u32::from_be_bytes(config.address.octets()) as usize),
-            ather_config,
-        ),
+        translate_socket(&config.socket_config, config.address, ather_config),
         route_config,
     ))
 }
 
+fn translate_socket(
+    config: &RatewaySocketConfig,
+    address: Ipv4Addr,
+    ather_config: AtherStreamConfig,
+) -> AcsmaSocketConfig {
+    let mut socket_config = AcsmaSocketConfig::new(
+        config.address,
+        Some(u32::from_be_bytes(address.octets()) as usize),
+        ather_config,
+    );
+    if let Some(window) = config.window {
+        socket_config.window = window;
+    }
+    if let Some(exclusive) = config.exclusive {
+        socket_config.exclusive = exclusive;
+    }
+    socket_config.rts_threshold = config.rts_threshold;
+    if let Some(block_ack) = config.block_ack {
+        socket_config.block_ack = block_ack;
+    }
+    socket_config.hello = config.hello_ms.map(Duration::from_millis);
+    socket_config.psk = config.psk;
+    if let Some(queue_len) = config.queue_len {
+        socket_config.queue_len = queue_len;
+    }
+    if config.codel == Some(false) {
+        socket_config.discipline = AcsmaQueueDiscipline::TailDrop;
+    }
+    let timing = &mut socket_config.timing;
+    if let Some(slot_ms) = config.slot_ms {
+        timing.slot = Duration::from_millis(slot_ms);
+    }
+    if let Some(ack_timeout_ms) = config.ack_timeout_ms {
+        timing.ack_timeout = Duration::from_millis(ack_timeout_ms);
+    }
+    if let Some(receive_timeout_ms) = config.receive_timeout_ms {
+        timing.receive_timeout = Duration::from_millis(receive_timeout_ms);
+    }
+    if let Some(max_resends) = config.max_resends {
+        timing.max_resends = max_resends;
+    }
+    if let Some(max_range) = config.max_range {
+        timing.max_range = max_range;
+    }
+    if let Some(free_threshold) = config.free_threshold {
+        timing.free_threshold = free_threshold;
+    }
+    if let Some(collision_threshold) = config.collision_threshold {
+        timing.collision_threshold = collision_threshold;
+    }
+    if let Some(tdma) = &config.tdma {
+        socket_config.mode = AcsmaSocketMode::Tdma(AcsmaTdmaConfig::new(
+            tdma.coordinator.unwrap_or(false),
+            tdma.slot_ms
+                .map(Duration::from_millis)
+                .unwrap_or(SOCKET_TDMA_SLOT),
+            tdma.stations.clone(),
+        ));
+    }
+    socket_config
+}
+
 fn deserialize_mac<'de, D>(deserializer: D) -> Result<usize, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let mac = String::deserialize(deserializer)?;
-    mac.parse::<usize>().map_err(Error::custom)
+    parse_address(&mac).map_err(Error::custom)
+}
+
+fn deserialize_psk<'de, D>(deserializer: D) -> Result<Option<[u8; 32]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let psk = Option::<String>::deserialize(deserializer)?;
+    psk.map(|psk| parse_psk(&psk).map_err(Error::custom))
+        .transpose()
+}
+
+fn deserialize_macs<'de, D>(deserializer: D) -> Result<Vec<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let macs = Vec::<String>::deserialize(deserializer)?;
+    macs.iter()
+        .map(|mac| parse_address(mac).map_err(Error::custom))
+        .collect()
 }
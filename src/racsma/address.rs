@@ -0,0 +1,44 @@
+use super::{builtin::SOCKET_BROADCAST_ADDRESS, AcsmaIoError};
+use anyhow::Result;
+
+/// Parse a MAC address. Addresses can be written in decimal (`42`), in hexadecimal (`0x2a`) or
+/// as colon separated hexadecimal bytes (`00:2a`). The broadcast address is reserved and cannot
+/// be assigned to a station.
+pub fn parse_address(src: &str) -> Result<usize> {
+    let src = src.trim();
+    let address = if let Some(hex) = src.strip_prefix("0x").or_else(|| src.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16).ok()
+    } else if src.contains(':') {
+        src.split(':').try_fold(0usize, |acc, byte| {
+            if byte.is_empty() || byte.len() > 2 {
+                return None;
+            }
+            let byte = usize::from_str_radix(byte, 16).ok()?;
+            acc.checked_mul(1 << 8).map(|acc| acc | byte)
+        })
+    } else {
+        src.parse::<usize>().ok()
+    };
+
+    match address {
+        Some(address) if address < SOCKET_BROADCAST_ADDRESS => Ok(address),
+        _ => Err(AcsmaIoError::InvalidAddress(src.to_owned()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("1").unwrap(), 1);
+        assert_eq!(parse_address("0x1f2a").unwrap(), 0x1f2a);
+        assert_eq!(parse_address("1f:2a").unwrap(), 0x1f2a);
+        assert_eq!(parse_address("00:2a").unwrap(), 0x2a);
+        assert!(parse_address("0xffff").is_err());
+        assert!(parse_address("1:2:3").is_err());
+        assert!(parse_address("1::2").is_err());
+        assert!(parse_address("mac").is_err());
+    }
+}
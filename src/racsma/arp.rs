@@ -0,0 +1,73 @@
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// An entry of the ARP cache as seen from outside. `mac` is `None` for negative entries, i.e.
+/// addresses that recently failed to resolve.
+#[derive(Debug, Clone)]
+pub struct AcsmaArpEntry {
+    pub ip: usize,
+    pub mac: Option<usize>,
+    pub ttl: Duration,
+}
+
+/// Cache of IP to MAC address resolutions owned by a socket. Resolved addresses live for `ttl`,
+/// failed ones for `negative_ttl`, so that unreachable hosts do not cost a broadcast round-trip on
+/// every packet either.
+pub struct AcsmaArpCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    entries: HashMap<usize, (Option<usize>, Instant)>,
+}
+
+impl AcsmaArpCache {
+    pub fn new(ttl: Duration, negative_ttl: Duration) -> Self {
+        Self {
+            ttl,
+            negative_ttl,
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl AcsmaArpCache {
+    /// Look up an address. Returns `Some(None)` if the address is known to be unreachable.
+    pub fn get(&mut self, ip: usize) -> Option<Option<usize>> {
+        self.expire();
+        self.entries.get(&ip).map(|(mac, _)| *mac)
+    }
+
+    pub fn insert(&mut self, ip: usize, mac: usize) {
+        self.entries
+            .insert(ip, (Some(mac), Instant::now() + self.ttl));
+    }
+
+    pub fn insert_negative(&mut self, ip: usize) {
+        self.entries
+            .insert(ip, (None, Instant::now() + self.negative_ttl));
+    }
+
+    pub fn entries(&mut self) -> Vec<AcsmaArpEntry> {
+        self.expire();
+        let now = Instant::now();
+        let mut entries = self
+            .entries
+            .iter()
+            .map(|(ip, (mac, expiry))| AcsmaArpEntry {
+                ip: *ip,
+                mac: *mac,
+                ttl: expiry.saturating_duration_since(now),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.ip);
+        entries
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.clear();
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.entries.retain(|_, (_, expiry)| *expiry > now);
+    }
+}
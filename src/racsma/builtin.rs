@@ -8,6 +8,11 @@
 //! CSMA/CA: the frame structure of CSMA/CA resides in the payload of ather frames.
 //! | Dest (ADDRESS_BITS_LEN) | Src (ADDRESS_BITS_LEN) | Seq (SEQ_BITS_LEN) | Type (TYPE_BITS_LEN) |
 //! | Flag (FLAG_BITS_LEN) | Payload (<= PAYLOAD_BITS_LEN) | Parity (PARITY_BITS_LEN) |
+//! Frames whose addresses do not fit into ADDRESS_BITS_LEN set the `EXT` flag and carry a
+//! versioned extension right after the flag field:
+//! | Version (EXT_VERSION_BITS_LEN) | Dest (EXT_ADDRESS_BITS_LEN) | Src (EXT_ADDRESS_BITS_LEN) |
+//! The legacy address fields of such frames hold LEGACY_EXT_ADDRESS, which no station uses, so
+//! that legacy nodes drop them.
 
 use crate::rather::builtin::PAYLOAD_BITS_LEN as ATHER_PAYLOAD_BITS_LEN;
 use crc::{Crc, CRC_16_IBM_SDLC};
@@ -17,32 +22,116 @@ pub const ADDRESS_BITS_LEN: usize = 4;
 pub const SEQ_BITS_LEN: usize = 8;
 pub const TYPE_BITS_LEN: usize = 4;
 pub const FLAG_BITS_LEN: usize = 4;
+pub const HEADER_BITS_LEN: usize =
+    ADDRESS_BITS_LEN + ADDRESS_BITS_LEN + SEQ_BITS_LEN + TYPE_BITS_LEN + FLAG_BITS_LEN;
+
+pub const EXT_VERSION: usize = 1;
+pub const EXT_VERSION_BITS_LEN: usize = 4;
+pub const EXT_ADDRESS_BITS_LEN: usize = 16;
+pub const EXT_HEADER_BITS_LEN: usize =
+    EXT_VERSION_BITS_LEN + EXT_ADDRESS_BITS_LEN + EXT_ADDRESS_BITS_LEN;
+
+/// RTS and CTS frames carry the reserved duration in milliseconds.
+pub const NAV_BITS_LEN: usize = 16;
+/// Beacons carry the slot length like a NAV, followed by the number of stations and their
+/// addresses in EXT_ADDRESS_BITS_LEN bits each.
+pub const BEACON_COUNT_BITS_LEN: usize = 8;
+pub const BLOCK_ACK_BITMAP_LEN: usize = 16;
+/// Neighbor discovery hellos carry the bit rate of the sender, followed by the number of
+/// neighbors like a beacon and, for each of them, its address and the delivery ratio of its hellos
+/// in HELLO_RATIO_BITS_LEN bits.
+pub const HELLO_RATE_BITS_LEN: usize = 24;
+pub const HELLO_RATIO_BITS_LEN: usize = 8;
+/// ARP requests carry the target IP and the IP of the requester, 0 while the requester probes
+/// for its own IP, in ARP_IP_BITS_LEN bits each.
+pub const ARP_IP_BITS_LEN: usize = 32;
 
 pub const PARITY_ALGORITHM: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_SDLC);
 pub const PARITY_BITS_LEN: usize = 16;
 
-pub const PAYLOAD_BITS_LEN: usize = ATHER_PAYLOAD_BITS_LEN
-    - ADDRESS_BITS_LEN
-    - ADDRESS_BITS_LEN
-    - SEQ_BITS_LEN
-    - TYPE_BITS_LEN
-    - FLAG_BITS_LEN
-    - PARITY_BITS_LEN;
+/// Frames in the legacy format have room for LEGACY_PAYLOAD_BITS_LEN bits of payload, frames
+/// carrying the extension only for PAYLOAD_BITS_LEN bits, see `payload_bits_len`.
+pub const PAYLOAD_BITS_LEN: usize =
+    ATHER_PAYLOAD_BITS_LEN - HEADER_BITS_LEN - EXT_HEADER_BITS_LEN - PARITY_BITS_LEN;
+pub const LEGACY_PAYLOAD_BITS_LEN: usize = PAYLOAD_BITS_LEN + EXT_HEADER_BITS_LEN;
+
+/// Every data frame of the socket starts with a fragment header of packet id, fragment index and
+/// fragment count, FRAGMENT_BITS_LEN bits each.
+pub const FRAGMENT_BITS_LEN: usize = 8;
+pub const FRAGMENT_PAYLOAD_BITS_LEN: usize = PAYLOAD_BITS_LEN - 3 * FRAGMENT_BITS_LEN;
+
+/// Sockets with a pre-shared key seal the payloads of data frames, see `AcsmaCipher`. Sealing
+/// takes the epoch, the padding of the plaintext to whole bytes, and the tag.
+pub const CRYPTO_EPOCH_BITS_LEN: usize = 29;
+pub const CRYPTO_PADDING_BITS_LEN: usize = 3;
+pub const CRYPTO_MAX_PADDING_BITS_LEN: usize = 7;
+pub const CRYPTO_TAG_BITS_LEN: usize = 128;
+pub const CRYPTO_OVERHEAD_BITS_LEN: usize = CRYPTO_EPOCH_BITS_LEN
+    + CRYPTO_PADDING_BITS_LEN
+    + CRYPTO_MAX_PADDING_BITS_LEN
+    + CRYPTO_TAG_BITS_LEN;
+
+/// Captures are written with the first link type reserved for private use, LINKTYPE_USER0, see
+/// `AcsmaCaptureWriter` for the pseudo-header in front of every frame.
+pub const CAPTURE_LINKTYPE: u16 = 147;
+pub const CAPTURE_VERSION: u8 = 1;
+pub const CAPTURE_HEADER_LEN: usize = 24;
 
+/// The timings below are tuned for frames at this bit rate. Sockets scale them to the frame
+/// duration of their own PHY, see `AcsmaTimingConfig::from_ather`.
+pub const SOCKET_REFERENCE_BIT_RATE: u32 = 24000;
 pub const SOCKET_SLOT_TIMEOUT: Duration = Duration::from_millis(85);
 pub const SOCKET_ACK_TIMEOUT: Duration = Duration::from_millis(30);
 pub const SOCKET_RECIEVE_TIMEOUT: Duration = Duration::from_millis(25);
+pub const SOCKET_MIN_ACK_TIMEOUT: Duration = Duration::from_millis(10);
+pub const SOCKET_MAX_ACK_TIMEOUT: Duration = Duration::from_millis(2000);
 
 pub const SOCKET_MAX_RESENDS: usize = 8;
 pub const SOCKET_MAX_RANGE: usize = 6;
-pub const SOCKET_JAR_CAPACITY: usize = 4;
+pub const SOCKET_HISTORY_LEN: usize = 64;
+pub const SOCKET_REASSEMBLY_TIMEOUT: Duration = Duration::from_millis(5000);
+pub const SOCKET_WINDOW_LEN: usize = 8;
+pub const SOCKET_MAX_WINDOW_LEN: usize = 1 << (SEQ_BITS_LEN - 1);
+pub const SOCKET_MAX_BURST_LEN: usize = 8;
+pub const SOCKET_QUEUE_LEN: usize = 64;
+pub const SOCKET_CODEL_TARGET: Duration = Duration::from_millis(500);
+pub const SOCKET_CODEL_INTERVAL: Duration = Duration::from_millis(5000);
 
 pub const SOCKET_FREE_THRESHOLD: f32 = 1e-5;
 pub const SOCKET_COLISION_THRESHOLD: f32 = 1e-4;
+pub const SOCKET_COLISION_INTERVAL: Duration = Duration::from_millis(2);
+pub const SOCKET_JAM_DURATION: Duration = Duration::from_millis(10);
+
+pub const SOCKET_ECHO_SEARCH_LEN: usize = 480;
+pub const SOCKET_ECHO_TRACK_LEN: usize = 16;
+pub const SOCKET_ECHO_LOCK_THRESHOLD: f32 = 0.5;
+pub const SOCKET_ECHO_SMOOTHING: f32 = 0.2;
+
+/// A slot fits a full data frame and its ACK.
+pub const SOCKET_TDMA_SLOT: Duration = Duration::from_millis(120);
+/// Stations fall back to CSMA/CD once they have missed the beacons for this long.
+pub const SOCKET_BEACON_TIMEOUT: Duration = Duration::from_millis(2000);
+
+pub const SOCKET_ARP_TTL: Duration = Duration::from_secs(300);
+pub const SOCKET_ARP_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+pub const SOCKET_HELLO_INTERVAL: Duration = Duration::from_millis(2000);
+/// Delivery ratios are computed over the last SOCKET_HELLO_WINDOW_LEN hellos of a neighbor.
+pub const SOCKET_HELLO_WINDOW_LEN: usize = 16;
+pub const SOCKET_NEIGHBOR_TTL: Duration = Duration::from_secs(60);
+pub const SOCKET_NEIGHBOR_SMOOTHING: f32 = 0.2;
 
 pub const SOCKET_PERF_INTERVAL: Duration = Duration::from_millis(1000);
 pub const SOCKET_PERF_TIMEOUT: Duration = Duration::from_millis(4000);
 pub const SOCKET_PING_INTERVAL: Duration = Duration::from_millis(4000);
 pub const SOCKET_PING_TIMEOUT: Duration = Duration::from_millis(2000);
 
-pub const SOCKET_BROADCAST_ADDRESS: usize = (1 << ADDRESS_BITS_LEN) - 1;
+/// Defaults of the simulated medium: sound travels about a meter in SIM_PROPAGATION, and energy
+/// only shows on carrier sense after the audio buffers of the receiver, SIM_SENSE_DELAY.
+pub const SIM_SAMPLE_RATE: u32 = 48000;
+pub const SIM_PROPAGATION: Duration = Duration::from_millis(3);
+pub const SIM_SENSE_DELAY: Duration = Duration::from_millis(10);
+
+pub const LEGACY_BROADCAST_ADDRESS: usize = (1 << ADDRESS_BITS_LEN) - 1;
+pub const LEGACY_EXT_ADDRESS: usize = LEGACY_BROADCAST_ADDRESS - 1;
+pub const SOCKET_BROADCAST_ADDRESS: usize = (1 << EXT_ADDRESS_BITS_LEN) - 1;
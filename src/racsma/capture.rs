@@ -0,0 +1,272 @@
+use super::{
+    builtin::{CAPTURE_HEADER_LEN, CAPTURE_LINKTYPE, CAPTURE_VERSION, PARITY_BITS_LEN},
+    frame::{AcsmaFrame, FrameDecodeError, FrameHeader, FrameType},
+    packet::AcsmaFragment,
+};
+use crate::rather::encode::DecodeToBytes;
+use anyhow::Result;
+use bitvec::prelude::*;
+use parking_lot::Mutex;
+use std::{
+    io::Write,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcsmaCaptureDirection {
+    Received,
+    Sent,
+}
+
+/// A frame as it went over the medium, before any decoding.
+#[derive(Debug, Clone)]
+pub struct AcsmaCaptureRecord {
+    /// When the frame was done on the medium, received or sent.
+    pub timestamp: SystemTime,
+    pub direction: AcsmaCaptureDirection,
+    pub bits: BitVec,
+    /// SNR reported by the PHY for the frame, in dB. Only known for received frames.
+    pub snr: Option<f32>,
+    /// Whether we cut the frame short on a collision. Only ever set for sent frames.
+    pub aborted: bool,
+}
+
+/// The subscribers to the frames of a socket. Records are only made while someone listens.
+#[derive(Debug, Clone, Default)]
+pub struct AcsmaCaptureTap(Arc<Mutex<Vec<UnboundedSender<AcsmaCaptureRecord>>>>);
+
+impl AcsmaCaptureTap {
+    pub fn subscribe(&self) -> UnboundedReceiver<AcsmaCaptureRecord> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.0.lock().push(tx);
+        rx
+    }
+
+    pub fn record(&self, direction: AcsmaCaptureDirection, bits: &BitSlice, snr: Option<f32>) {
+        self.push(direction, bits, snr, false);
+    }
+
+    pub fn record_aborted(&self, bits: &BitSlice) {
+        self.push(AcsmaCaptureDirection::Sent, bits, None, true);
+    }
+
+    fn push(
+        &self,
+        direction: AcsmaCaptureDirection,
+        bits: &BitSlice,
+        snr: Option<f32>,
+        aborted: bool,
+    ) {
+        let mut txs = self.0.lock();
+        if txs.is_empty() {
+            return;
+        }
+        let record = AcsmaCaptureRecord {
+            timestamp: SystemTime::now(),
+            direction,
+            bits: bits.to_owned(),
+            snr,
+            aborted,
+        };
+        txs.retain(|tx| tx.send(record.clone()).is_ok());
+    }
+}
+
+/// Writes capture records to a pcapng file with a single interface of link type
+/// `CAPTURE_LINKTYPE`. Every packet starts with a pseudo-header of CAPTURE_HEADER_LEN bytes in
+/// network byte order, followed by the payload of the frame packed into bytes, first bit in the
+/// least significant bit:
+/// | Version (1) | Status (1) | Type (1) | Flag (1) | Dest (2) | Src (2) | Seq (1) |
+/// | Fragment id (1) | Fragment index (1) | Fragment count (1) | SNR (4, f32) |
+/// | Frame length in bits (2) | Payload length in bits (2) | Reserved (4) |
+/// The status is 0 for frames that decode, 1 for frames failing the CRC, 2 for anything else,
+/// whose header fields are then zero and whose payload is the whole frame, and 3 for frames we
+/// aborted on a collision, which are decoded from what we meant to send. Data frames have their
+/// fragment header taken off the payload, so that the packets of rateway start right after the
+/// pseudo-header. The SNR is NaN if unknown. Payloads sealed with a pre-shared key are captured
+/// as they are sent, encrypted.
+pub struct AcsmaCaptureWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> AcsmaCaptureWriter<W> {
+    /// Start a capture file with a section header and the description of the link, whose speed
+    /// is the bit rate of the PHY.
+    pub fn new(mut inner: W, bit_rate: u32) -> Result<Self> {
+        let mut shb = vec![];
+        shb.extend(0x1a2b3c4du32.to_le_bytes());
+        shb.extend(1u16.to_le_bytes());
+        shb.extend(0u16.to_le_bytes());
+        shb.extend((-1i64).to_le_bytes());
+        write_block(&mut inner, 0x0a0d0d0a, &shb)?;
+
+        let mut idb = vec![];
+        idb.extend(CAPTURE_LINKTYPE.to_le_bytes());
+        idb.extend(0u16.to_le_bytes());
+        idb.extend(0u32.to_le_bytes());
+        write_option(&mut idb, 2, b"ather");
+        write_option(&mut idb, 8, &(bit_rate as u64).to_le_bytes());
+        write_option(&mut idb, 0, &[]);
+        write_block(&mut inner, 1, &idb)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn write(&mut self, record: &AcsmaCaptureRecord) -> Result<()> {
+        let data = encode_record(record);
+        let micros = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64);
+
+        let mut epb = vec![];
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(((micros >> 32) as u32).to_le_bytes());
+        epb.extend((micros as u32).to_le_bytes());
+        epb.extend((data.len() as u32).to_le_bytes());
+        epb.extend((data.len() as u32).to_le_bytes());
+        epb.extend(&data);
+        pad(&mut epb);
+        let flags: u32 = match record.direction {
+            AcsmaCaptureDirection::Received => 1,
+            AcsmaCaptureDirection::Sent => 2,
+        };
+        write_option(&mut epb, 2, &flags.to_le_bytes());
+        write_option(&mut epb, 0, &[]);
+        write_block(&mut self.inner, 6, &epb)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.inner.flush()?)
+    }
+}
+
+/// The pseudo-header and payload of a record, see `AcsmaCaptureWriter`.
+fn encode_record(record: &AcsmaCaptureRecord) -> Vec<u8> {
+    let bits = record.bits.as_bitslice();
+    let status = match AcsmaFrame::try_from(record.bits.clone()) {
+        _ if record.aborted => 3u8,
+        Ok(_) => 0,
+        Err(err) => match err.downcast_ref() {
+            Some(FrameDecodeError::ParityCheckFailed(..)) => 1,
+            _ => 2,
+        },
+    };
+    let decoded = match status {
+        2 => None,
+        _ => FrameHeader::decode(bits).ok(),
+    };
+
+    let mut data = vec![CAPTURE_VERSION, status];
+    let payload = match decoded {
+        Some((header, len)) => {
+            let mut payload = &bits[len..bits.len() - PARITY_BITS_LEN];
+            let mut fragment = AcsmaFragment::new(0, 0, 0);
+            if header.r#type == usize::from(FrameType::DATA) {
+                if let Some((inner, rest)) = AcsmaFragment::decode(payload) {
+                    fragment = inner;
+                    payload = rest;
+                }
+            }
+            data.extend([header.r#type as u8, header.flag.bits() as u8]);
+            data.extend((header.dest as u16).to_be_bytes());
+            data.extend((header.src as u16).to_be_bytes());
+            data.extend([
+                header.seq as u8,
+                fragment.id as u8,
+                fragment.index as u8,
+                fragment.count as u8,
+            ]);
+            payload
+        }
+        None => {
+            data.extend([0; 10]);
+            bits
+        }
+    };
+    data.extend(record.snr.unwrap_or(f32::NAN).to_be_bytes());
+    data.extend((bits.len() as u16).to_be_bytes());
+    data.extend((payload.len() as u16).to_be_bytes());
+    data.extend(0u32.to_be_bytes());
+    debug_assert_eq!(data.len(), CAPTURE_HEADER_LEN);
+    data.extend(DecodeToBytes::decode(payload));
+    data
+}
+
+fn write_block<W: Write>(inner: &mut W, r#type: u32, body: &[u8]) -> Result<()> {
+    let len = (body.len() + 12) as u32;
+    inner.write_all(&r#type.to_le_bytes())?;
+    inner.write_all(&len.to_le_bytes())?;
+    inner.write_all(body)?;
+    inner.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend(code.to_le_bytes());
+    block.extend((value.len() as u16).to_le_bytes());
+    block.extend(value);
+    pad(block);
+}
+
+fn pad(block: &mut Vec<u8>) {
+    block.resize(block.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        racsma::{
+            builtin::FRAGMENT_PAYLOAD_BITS_LEN,
+            frame::{DataFrame, FrameFlag},
+            packet::encode_packet,
+        },
+        rather::encode::EncodeFromBytes,
+    };
+
+    #[test]
+    fn test_capture() {
+        let packet = [0x45u8, 0x00, 0x00, 0x1c, 0xde, 0xad];
+        let frame = encode_packet(&packet.encode(), FRAGMENT_PAYLOAD_BITS_LEN, 3, 1, 0x1f2a)
+            .unwrap()
+            .remove(0);
+        let mut record = AcsmaCaptureRecord {
+            timestamp: UNIX_EPOCH,
+            direction: AcsmaCaptureDirection::Sent,
+            bits: BitVec::from(frame),
+            snr: Some(12.5),
+            aborted: false,
+        };
+
+        let data = encode_record(&record);
+        assert_eq!(&data[..4], &[CAPTURE_VERSION, 0, 0, 1]);
+        assert_eq!(&data[4..12], &[0x1f, 0x2a, 0, 1, 0, 3, 0, 1]);
+        assert_eq!(&data[12..16], &12.5f32.to_be_bytes());
+        assert_eq!(&data[18..20], &48u16.to_be_bytes());
+        assert_eq!(&data[CAPTURE_HEADER_LEN..], &packet);
+
+        record.aborted = true;
+        assert_eq!(&encode_record(&record)[..4], &[CAPTURE_VERSION, 3, 0, 1]);
+        record.aborted = false;
+
+        let len = record.bits.len();
+        let bit = !record.bits[len - 1];
+        record.bits.set(len - 1, bit);
+        assert_eq!(encode_record(&record)[1], 1);
+        let data = DataFrame::new(1, 2, 0, FrameFlag::empty(), bitvec![]);
+        record.bits = BitVec::from(data)[..8].to_owned();
+        assert_eq!(encode_record(&record)[1], 2);
+
+        let mut file = vec![];
+        let mut writer = AcsmaCaptureWriter::new(&mut file, 24000).unwrap();
+        writer.write(&record).unwrap();
+        assert_eq!(&file[..4], &0x0a0d0d0au32.to_le_bytes());
+        assert_eq!(file.len() % 4, 0);
+        let shb = u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize;
+        let idb = u32::from_le_bytes(file[shb + 4..shb + 8].try_into().unwrap()) as usize;
+        assert_eq!(&file[shb + idb..shb + idb + 4], &6u32.to_le_bytes());
+    }
+}
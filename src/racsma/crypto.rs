@@ -0,0 +1,208 @@
+use super::{
+    builtin::{
+        CRYPTO_EPOCH_BITS_LEN, CRYPTO_PADDING_BITS_LEN, CRYPTO_TAG_BITS_LEN, EXT_ADDRESS_BITS_LEN,
+        FLAG_BITS_LEN, SEQ_BITS_LEN, SOCKET_BROADCAST_ADDRESS, TYPE_BITS_LEN,
+    },
+    frame::{DataFrame, Frame, FrameFlag, FrameHeader},
+    AcsmaIoError,
+};
+use crate::rather::encode::{DecodeToBytes, DecodeToInt, EncodeFromBytes};
+use anyhow::Result;
+use bitvec::prelude::*;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const CRYPTO_HEADER_BITS_LEN: usize = CRYPTO_EPOCH_BITS_LEN + CRYPTO_PADDING_BITS_LEN;
+
+/// Parse a pre-shared key written as 64 hexadecimal digits.
+pub fn parse_psk(src: &str) -> Result<[u8; 32]> {
+    let src = src.trim();
+    let mut key = [0u8; 32];
+    if src.len() != 2 * key.len() || !src.is_ascii() {
+        return Err(AcsmaIoError::InvalidKey(src.len()).into());
+    }
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&src[2 * index..2 * index + 2], 16)
+            .map_err(|_| AcsmaIoError::InvalidKey(src.len()))?;
+    }
+    Ok(key)
+}
+
+/// The epoch a station starts with. Epochs count the wraps of the sequence numbers of a peer,
+/// starting from the current time, so that a restarted station never reuses the nonces of its
+/// previous run as long as it wraps less than once per second.
+pub fn initial_epoch() -> usize {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as usize);
+    secs % (1 << CRYPTO_EPOCH_BITS_LEN)
+}
+
+/// Encrypts and authenticates the payloads of data frames with ChaCha20-Poly1305 under a
+/// pre-shared key. The nonce is made of the addresses, the sequence number and the epoch of the
+/// sender, which together never repeat for a key. Sealed payloads carry the epoch and the padding
+/// of the plaintext to whole bytes in front of the ciphertext. Both are authenticated as
+/// associated data, after the frame header, so that no header field can be altered either:
+/// | Epoch (CRYPTO_EPOCH_BITS_LEN) | Padding (CRYPTO_PADDING_BITS_LEN) | Ciphertext | Tag (CRYPTO_TAG_BITS_LEN) |
+pub struct AcsmaCipher {
+    cipher: ChaCha20Poly1305,
+    replay_len: usize,
+    /// Highest counter, the epoch followed by the sequence number, seen from each source.
+    counters: HashMap<(usize, bool), usize>,
+}
+
+impl AcsmaCipher {
+    /// Frames more than `replay_len` counters behind the newest frame of their source are
+    /// rejected as replays. Frames inside the window, retransmissions among them, are passed on
+    /// for the read window to deduplicate.
+    pub fn new(key: &[u8; 32], replay_len: usize) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            replay_len,
+            counters: HashMap::new(),
+        }
+    }
+
+    pub fn seal(&self, frame: &mut DataFrame, epoch: usize) {
+        let header = frame.header();
+        let nonce = nonce(header.dest, header.src, header.seq, epoch);
+        let plaintext = frame.payload().unwrap();
+        let padding = (8 - plaintext.len() % 8) % 8;
+
+        let mut payload = bitvec![];
+        payload.extend(&epoch.view_bits::<Lsb0>()[..CRYPTO_EPOCH_BITS_LEN]);
+        payload.extend(&padding.view_bits::<Lsb0>()[..CRYPTO_PADDING_BITS_LEN]);
+        let aad = associated_data(header, &payload);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &DecodeToBytes::decode(plaintext),
+                    aad: &aad,
+                },
+            )
+            .expect("encryption cannot fail for payloads of a frame");
+        payload.extend(ciphertext.encode());
+        *frame.payload_mut() = payload;
+    }
+
+    /// Replace the sealed payload of a frame by its plaintext.
+    pub fn open(&mut self, frame: &mut DataFrame) -> Result<()> {
+        let header = frame.header().clone();
+        let payload = frame.payload().unwrap();
+        let len = payload.len();
+        if len < CRYPTO_HEADER_BITS_LEN + CRYPTO_TAG_BITS_LEN
+            || !(len - CRYPTO_HEADER_BITS_LEN).is_multiple_of(8)
+        {
+            return Err(AcsmaIoError::AuthFailed(header.src).into());
+        }
+        let epoch: usize = DecodeToInt::decode(&payload[..CRYPTO_EPOCH_BITS_LEN]);
+        let padding: usize =
+            DecodeToInt::decode(&payload[CRYPTO_EPOCH_BITS_LEN..CRYPTO_HEADER_BITS_LEN]);
+
+        let key = (header.src, header.dest == SOCKET_BROADCAST_ADDRESS);
+        let counter = (epoch << SEQ_BITS_LEN) | header.seq;
+        let highest = self.counters.get(&key).copied();
+        if highest.is_some_and(|highest| counter + self.replay_len < highest) {
+            return Err(AcsmaIoError::Replayed(header.src).into());
+        }
+
+        let nonce = nonce(header.dest, header.src, header.seq, epoch);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &DecodeToBytes::decode(&payload[CRYPTO_HEADER_BITS_LEN..]),
+                    aad: &associated_data(&header, &payload[..CRYPTO_HEADER_BITS_LEN]),
+                },
+            )
+            .map_err(|_| AcsmaIoError::AuthFailed(header.src))?;
+        let mut plaintext = plaintext.encode();
+        if padding > plaintext.len() {
+            return Err(AcsmaIoError::AuthFailed(header.src).into());
+        }
+        plaintext.truncate(plaintext.len() - padding);
+
+        self.counters
+            .insert(key, highest.map_or(counter, |highest| highest.max(counter)));
+        *frame.payload_mut() = plaintext;
+        Ok(())
+    }
+}
+
+/// The header of the frame with its full addresses, followed by the crypto header. `BURST` is
+/// left out, as it is only set when the frame goes to the medium, and so is `EXT`, which only
+/// depends on the addresses.
+fn associated_data(header: &FrameHeader, crypto_header: &BitSlice) -> Vec<u8> {
+    let flag = header.flag & (FrameFlag::EOP | FrameFlag::SYN);
+    let mut aad = bitvec![];
+    aad.extend(&header.dest.view_bits::<Lsb0>()[..EXT_ADDRESS_BITS_LEN]);
+    aad.extend(&header.src.view_bits::<Lsb0>()[..EXT_ADDRESS_BITS_LEN]);
+    aad.extend(&header.seq.view_bits::<Lsb0>()[..SEQ_BITS_LEN]);
+    aad.extend(&header.r#type.view_bits::<Lsb0>()[..TYPE_BITS_LEN]);
+    aad.extend(&flag.bits().view_bits::<Lsb0>()[..FLAG_BITS_LEN]);
+    aad.extend(crypto_header);
+    DecodeToBytes::decode(aad.as_bitslice())
+}
+
+fn nonce(dest: usize, src: usize, seq: usize, epoch: usize) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..2].copy_from_slice(&(dest as u16).to_le_bytes());
+    nonce[2..4].copy_from_slice(&(src as u16).to_le_bytes());
+    nonce[4..8].copy_from_slice(&(epoch as u32).to_le_bytes());
+    nonce[8] = seq as u8;
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::racsma::frame::FrameFlag;
+
+    #[test]
+    fn test_seal_open() {
+        let key = parse_psk(&"0123456789abcdef".repeat(4)).unwrap();
+        assert!(parse_psk("0123").is_err());
+        let mut cipher = AcsmaCipher::new(&key, 16);
+
+        let payload = (0..101).map(|i| i % 3 == 0).collect::<BitVec>();
+        let frame = |seq| DataFrame::new(1, 2, seq, FrameFlag::empty(), payload.clone());
+        let mut sealed = frame(7);
+        cipher.seal(&mut sealed, 5);
+        assert_ne!(sealed.payload().unwrap(), payload.as_bitslice());
+
+        let mut opened = sealed.clone();
+        cipher.open(&mut opened).unwrap();
+        assert_eq!(opened.payload().unwrap(), payload.as_bitslice());
+
+        // Retransmissions are let through, tampered frames and frames from old epochs are not.
+        cipher.open(&mut sealed.clone()).unwrap();
+        let mut tampered = sealed.clone();
+        let bit = !tampered.payload().unwrap()[40];
+        tampered.payload_mut().set(40, bit);
+        assert!(cipher.open(&mut tampered).is_err());
+        let mut forged = sealed.clone();
+        forged.header_mut().seq = 8;
+        assert!(cipher.open(&mut forged).is_err());
+        // A SYN would reset the read window of the receiver.
+        let mut forged = sealed.clone();
+        forged.header_mut().flag |= FrameFlag::SYN;
+        assert!(cipher.open(&mut forged).is_err());
+        let mut burst = sealed.clone();
+        burst.header_mut().flag |= FrameFlag::BURST;
+        cipher.open(&mut burst).unwrap();
+
+        let mut newer = frame(7);
+        cipher.seal(&mut newer, 6);
+        cipher.open(&mut newer).unwrap();
+        assert!(cipher.open(&mut sealed).is_err());
+    }
+}
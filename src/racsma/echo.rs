@@ -0,0 +1,183 @@
+use super::builtin::{
+    SOCKET_ECHO_LOCK_THRESHOLD, SOCKET_ECHO_SEARCH_LEN, SOCKET_ECHO_SMOOTHING,
+    SOCKET_ECHO_TRACK_LEN,
+};
+use crate::{rather::signal::dot_product, raudio::SharedSamples};
+use std::{collections::VecDeque, time::Instant};
+
+/// Echo canceller for the write monitor of a socket. The monitor is opened on the same device as
+/// the output stream, so it hears every frame we transmit. The canceller keeps the transmitted
+/// waveforms, aligns them against the monitor input and subtracts them, so that only the signal
+/// of other nodes is left in the residual.
+pub struct AcsmaEchoCanceller {
+    sample_rate: u32,
+    position: usize,
+    clock: Option<Instant>,
+    references: VecDeque<(isize, SharedSamples<f32>)>,
+    delay: isize,
+    gain: f32,
+    locked: bool,
+}
+
+impl AcsmaEchoCanceller {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            position: 0,
+            clock: None,
+            references: VecDeque::new(),
+            delay: SOCKET_ECHO_SEARCH_LEN as isize,
+            gain: 0.,
+            locked: false,
+        }
+    }
+}
+
+impl AcsmaEchoCanceller {
+    /// Register a waveform that starts playing at `start`. The start time is translated to the
+    /// timeline of the monitor input, which is counted in samples.
+    pub fn transmit(&mut self, start: Instant, samples: SharedSamples<f32>) {
+        let index = match self.clock {
+            Some(clock) => {
+                let elapsed = start.saturating_duration_since(clock).as_secs_f32();
+                self.position as isize + (elapsed * self.sample_rate as f32) as isize
+            }
+            None => self.position as isize,
+        };
+        self.references.push_back((index, samples));
+    }
+
+    /// Cancel our own transmissions from a chunk of the monitor input arriving at `arrival`.
+    /// Returns `None` if the chunk contains an echo that cannot be located yet.
+    pub fn cancel(&mut self, arrival: Instant, chunk: &[f32]) -> Option<Box<[f32]>> {
+        let begin = self.position as isize;
+        self.position += chunk.len();
+        self.clock = Some(arrival);
+
+        let horizon = begin - self.delay - SOCKET_ECHO_SEARCH_LEN as isize;
+        while let Some((index, samples)) = self.references.front() {
+            if index + (samples.len() as isize) < horizon {
+                self.references.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.references.is_empty() || chunk.is_empty() {
+            return Some(chunk.into());
+        }
+
+        let range = if self.locked {
+            SOCKET_ECHO_TRACK_LEN
+        } else {
+            SOCKET_ECHO_SEARCH_LEN
+        } as isize;
+        let extended = self.reference(begin - self.delay - range, chunk.len() + 2 * range as usize);
+        let energy = dot_product(chunk, chunk);
+
+        let mut best: Option<(isize, f32, f32)> = None;
+        for shift in -range..=range {
+            let start = (range - shift) as usize;
+            let reference = &extended[start..start + chunk.len()];
+            let power = dot_product(reference, reference);
+            if power <= f32::EPSILON {
+                continue;
+            }
+            let cross = dot_product(chunk, reference);
+            let score = cross.abs() / (power * energy).sqrt().max(f32::EPSILON);
+            match best {
+                Some((_, value, _)) if value >= score => {}
+                _ => best = Some((shift, score, cross / power)),
+            }
+        }
+
+        match best {
+            Some((shift, score, gain)) if score > SOCKET_ECHO_LOCK_THRESHOLD => {
+                self.delay = (self.delay + shift).max(0);
+                self.gain = if self.locked {
+                    self.gain + SOCKET_ECHO_SMOOTHING * (gain - self.gain)
+                } else {
+                    gain
+                };
+                self.locked = true;
+            }
+            Some(_) if self.locked => {}
+            Some(_) => return None,
+            None => return Some(chunk.into()),
+        }
+
+        let reference = self.reference(begin - self.delay, chunk.len());
+        let residual = chunk
+            .iter()
+            .zip(reference.iter())
+            .map(|(sample, echo)| sample - self.gain * echo)
+            .collect();
+        Some(residual)
+    }
+
+    fn reference(&self, start: isize, len: usize) -> Vec<f32> {
+        let mut result = vec![0.; len];
+        for (index, samples) in self.references.iter() {
+            let offset = start - index;
+            for (i, item) in result.iter_mut().enumerate() {
+                let pos = offset + i as isize;
+                if pos >= 0 && (pos as usize) < samples.len() {
+                    *item += samples[pos as usize];
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+    use std::time::Duration;
+
+    #[test]
+    fn test_cancel() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let transmitted: Vec<f32> = (0..4800).map(|_| rng.gen_range(-1. ..1.)).collect();
+        let foreign: Vec<f32> = (0..4800).map(|_| rng.gen_range(-0.1..0.1)).collect();
+
+        for with_foreign in [false, true] {
+            let mut canceller = AcsmaEchoCanceller::new(48000);
+            let start = Instant::now();
+            canceller.transmit(start, transmitted.clone().into());
+
+            let delay = 37;
+            let mut residual = 0.;
+            let mut reference = 0.;
+            for (index, chunk) in (0..4800).collect::<Vec<usize>>().chunks(256).enumerate() {
+                let input = chunk
+                    .iter()
+                    .map(|&i| {
+                        let echo = if i >= delay {
+                            0.3 * transmitted[i - delay]
+                        } else {
+                            0.
+                        };
+                        if with_foreign {
+                            echo + foreign[i]
+                        } else {
+                            echo
+                        }
+                    })
+                    .collect::<Vec<f32>>();
+                let output = canceller.cancel(start + Duration::from_millis(5), &input);
+                if index > 1 {
+                    let output = output.unwrap();
+                    residual += dot_product(&output, &output);
+                    reference += chunk.iter().map(|&i| foreign[i] * foreign[i]).sum::<f32>();
+                }
+            }
+
+            if with_foreign {
+                assert!((residual - reference).abs() < 0.1 * reference);
+            } else {
+                assert!(residual < 1e-3 * reference);
+            }
+        }
+    }
+}
@@ -1,11 +1,15 @@
 use super::builtin::{
-    ADDRESS_BITS_LEN, FLAG_BITS_LEN, PARITY_ALGORITHM, PARITY_BITS_LEN, SEQ_BITS_LEN,
-    SOCKET_BROADCAST_ADDRESS, TYPE_BITS_LEN,
+    ADDRESS_BITS_LEN, ARP_IP_BITS_LEN, BEACON_COUNT_BITS_LEN, BLOCK_ACK_BITMAP_LEN,
+    EXT_ADDRESS_BITS_LEN, EXT_HEADER_BITS_LEN, EXT_VERSION, EXT_VERSION_BITS_LEN, FLAG_BITS_LEN,
+    HEADER_BITS_LEN, HELLO_RATE_BITS_LEN, HELLO_RATIO_BITS_LEN, LEGACY_BROADCAST_ADDRESS,
+    LEGACY_EXT_ADDRESS, LEGACY_PAYLOAD_BITS_LEN, NAV_BITS_LEN, PARITY_ALGORITHM, PARITY_BITS_LEN,
+    PAYLOAD_BITS_LEN, SEQ_BITS_LEN, SOCKET_BROADCAST_ADDRESS, TYPE_BITS_LEN,
 };
 use crate::rather::encode::{DecodeToBytes, DecodeToInt};
 use anyhow::{Error, Result};
 use bitflags::bitflags;
 use bitvec::prelude::*;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -18,6 +22,11 @@ impl FrameType {
     pub const MAC_PING_RESP: Self = Self(0b0000_0011);
     pub const MAC_ARP_REQ: Self = Self(0b0000_0100);
     pub const MAC_ARP_RESP: Self = Self(0b0000_0101);
+    pub const RTS: Self = Self(0b0000_0110);
+    pub const CTS: Self = Self(0b0000_0111);
+    pub const BEACON: Self = Self(0b0000_1000);
+    pub const BLOCK_ACK: Self = Self(0b0000_1001);
+    pub const HELLO: Self = Self(0b0000_1010);
 }
 
 impl From<FrameType> for usize {
@@ -30,6 +39,9 @@ bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct FrameFlag: usize {
         const EOP = 0b0000_0001;
+        const SYN = 0b0000_0010;
+        const BURST = 0b0000_0100;
+        const EXT = 0b0000_1000;
     }
 }
 
@@ -42,22 +54,27 @@ pub struct FrameHeader {
     pub flag: FrameFlag,
 }
 
-impl From<FrameHeader> for BitVec {
-    fn from(value: FrameHeader) -> Self {
-        let mut header = bitvec![];
-        header.extend(&value.dest.view_bits::<Lsb0>()[..ADDRESS_BITS_LEN]);
-        header.extend(&value.src.view_bits::<Lsb0>()[..ADDRESS_BITS_LEN]);
-        header.extend(&value.seq.view_bits::<Lsb0>()[..SEQ_BITS_LEN]);
-        header.extend(&value.r#type.view_bits::<Lsb0>()[..TYPE_BITS_LEN]);
-        header.extend(&value.flag.bits().view_bits::<Lsb0>()[..FLAG_BITS_LEN]);
+/// Whether the addresses fit into the legacy 4-bit address fields. LEGACY_EXT_ADDRESS is reserved
+/// for frames carrying the extension.
+fn is_legacy(dest: usize, src: usize) -> bool {
+    [dest, src]
+        .into_iter()
+        .all(|address| address < LEGACY_EXT_ADDRESS || address == SOCKET_BROADCAST_ADDRESS)
+}
 
-        header
+/// Bits left for the payload of a frame from `src` to `dest`.
+pub fn payload_bits_len(dest: usize, src: usize) -> usize {
+    if is_legacy(dest, src) {
+        LEGACY_PAYLOAD_BITS_LEN
+    } else {
+        PAYLOAD_BITS_LEN
     }
 }
 
-impl From<&BitSlice> for FrameHeader {
-    fn from(value: &BitSlice) -> Self {
-        Self {
+impl FrameHeader {
+    /// Decode the header at the beginning of a frame, returning the header and its length in bits.
+    pub(super) fn decode(value: &BitSlice) -> Result<(Self, usize)> {
+        let mut header = Self {
             dest: DecodeToInt::decode(&value[0..ADDRESS_BITS_LEN]),
             src: DecodeToInt::decode(&value[ADDRESS_BITS_LEN..ADDRESS_BITS_LEN + ADDRESS_BITS_LEN]),
             seq: DecodeToInt::decode(
@@ -70,13 +87,72 @@ impl From<&BitSlice> for FrameHeader {
             ),
             flag: FrameFlag::from_bits_truncate(DecodeToInt::decode(
                 &value[ADDRESS_BITS_LEN + ADDRESS_BITS_LEN + SEQ_BITS_LEN + TYPE_BITS_LEN
-                    ..ADDRESS_BITS_LEN
-                        + ADDRESS_BITS_LEN
-                        + SEQ_BITS_LEN
-                        + TYPE_BITS_LEN
-                        + FLAG_BITS_LEN],
+                    ..HEADER_BITS_LEN],
             )),
+        };
+
+        if !header.flag.contains(FrameFlag::EXT) {
+            for address in [&mut header.dest, &mut header.src] {
+                if *address == LEGACY_BROADCAST_ADDRESS {
+                    *address = SOCKET_BROADCAST_ADDRESS;
+                }
+            }
+            return Ok((header, HEADER_BITS_LEN));
+        }
+
+        if value.len() < HEADER_BITS_LEN + EXT_HEADER_BITS_LEN + PARITY_BITS_LEN {
+            return Err(FrameDecodeError::FrameIsTooShort(
+                value.len(),
+                HEADER_BITS_LEN + EXT_HEADER_BITS_LEN + PARITY_BITS_LEN,
+            )
+            .into());
+        }
+        let ext = &value[HEADER_BITS_LEN..HEADER_BITS_LEN + EXT_HEADER_BITS_LEN];
+        let version = DecodeToInt::<usize>::decode(&ext[..EXT_VERSION_BITS_LEN]);
+        if version != EXT_VERSION {
+            return Err(FrameDecodeError::UnsupportedVersion(version).into());
+        }
+        header.dest = DecodeToInt::decode(
+            &ext[EXT_VERSION_BITS_LEN..EXT_VERSION_BITS_LEN + EXT_ADDRESS_BITS_LEN],
+        );
+        header.src = DecodeToInt::decode(&ext[EXT_VERSION_BITS_LEN + EXT_ADDRESS_BITS_LEN..]);
+        header.flag.remove(FrameFlag::EXT);
+
+        Ok((header, HEADER_BITS_LEN + EXT_HEADER_BITS_LEN))
+    }
+}
+
+/// Headers are written in the legacy format whenever the addresses allow it, so that 4-bit peers
+/// keep understanding us. Otherwise the `EXT` flag is set and the versioned extension carrying the
+/// full addresses follows the legacy header.
+impl From<FrameHeader> for BitVec {
+    fn from(value: FrameHeader) -> Self {
+        let is_legacy = is_legacy(value.dest, value.src);
+        let legacy = |address: usize| {
+            if !is_legacy {
+                LEGACY_EXT_ADDRESS
+            } else if address == SOCKET_BROADCAST_ADDRESS {
+                LEGACY_BROADCAST_ADDRESS
+            } else {
+                address
+            }
+        };
+        let mut flag = value.flag;
+        flag.set(FrameFlag::EXT, !is_legacy);
+
+        let mut header = bitvec![];
+        header.extend(&legacy(value.dest).view_bits::<Lsb0>()[..ADDRESS_BITS_LEN]);
+        header.extend(&legacy(value.src).view_bits::<Lsb0>()[..ADDRESS_BITS_LEN]);
+        header.extend(&value.seq.view_bits::<Lsb0>()[..SEQ_BITS_LEN]);
+        header.extend(&value.r#type.view_bits::<Lsb0>()[..TYPE_BITS_LEN]);
+        header.extend(&flag.bits().view_bits::<Lsb0>()[..FLAG_BITS_LEN]);
+        if !is_legacy {
+            header.extend(&EXT_VERSION.view_bits::<Lsb0>()[..EXT_VERSION_BITS_LEN]);
+            header.extend(&value.dest.view_bits::<Lsb0>()[..EXT_ADDRESS_BITS_LEN]);
+            header.extend(&value.src.view_bits::<Lsb0>()[..EXT_ADDRESS_BITS_LEN]);
         }
+
+        header
     }
 }
 
@@ -104,6 +180,14 @@ impl DataFrame {
             payload,
         }
     }
+
+    pub fn header_mut(&mut self) -> &mut FrameHeader {
+        &mut self.header
+    }
+
+    pub fn payload_mut(&mut self) -> &mut BitVec {
+        &mut self.payload
+    }
 }
 
 impl Frame for DataFrame {
@@ -165,26 +249,15 @@ impl TryFrom<BitVec> for DataFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
-        if header.r#type != FrameType::DATA.into() {
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::DATA) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::DATA.into(),
             )
             .into());
         }
-        let payload = value[ADDRESS_BITS_LEN
-            + ADDRESS_BITS_LEN
-            + SEQ_BITS_LEN
-            + TYPE_BITS_LEN
-            + FLAG_BITS_LEN..value.len() - PARITY_BITS_LEN]
-            .to_owned();
+        let payload = value[offset..value.len() - PARITY_BITS_LEN].to_owned();
         Ok(Self { header, payload })
     }
 }
@@ -198,10 +271,11 @@ fn checksum(bits: &BitSlice) -> BitVec {
 #[derive(Debug, Clone)]
 pub struct AckFrame {
     header: FrameHeader,
+    window: usize,
 }
 
 impl AckFrame {
-    pub fn new(dest: usize, src: usize, seq: usize) -> Self {
+    pub fn new(dest: usize, src: usize, seq: usize, window: usize) -> Self {
         Self {
             header: FrameHeader {
                 dest,
@@ -210,6 +284,22 @@ impl AckFrame {
                 r#type: FrameType::ACK.into(),
                 flag: FrameFlag::empty(),
             },
+            window,
+        }
+    }
+
+    /// Receive window advertised by the sender of the ACK.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    fn decode_window(value: &BitSlice, offset: usize) -> usize {
+        let payload = &value[offset..value.len() - PARITY_BITS_LEN];
+        if payload.len() < SEQ_BITS_LEN {
+            // ACKs without a window come from stop-and-wait peers
+            1
+        } else {
+            DecodeToInt::decode(&payload[..SEQ_BITS_LEN])
         }
     }
 }
@@ -227,6 +317,7 @@ impl Frame for AckFrame {
 impl From<AckFrame> for BitVec {
     fn from(value: AckFrame) -> Self {
         let mut frame = BitVec::from(value.header);
+        frame.extend(&value.window.view_bits::<Lsb0>()[..SEQ_BITS_LEN]);
         frame.extend(checksum(&frame));
         frame
     }
@@ -237,21 +328,16 @@ impl TryFrom<BitVec> for AckFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
-        if header.r#type != FrameType::ACK.into() {
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::ACK) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::ACK.into(),
             )
             .into());
         }
-        Ok(Self { header })
+        let window = Self::decode_window(&value, offset);
+        Ok(Self { header, window })
     }
 }
 
@@ -297,14 +383,8 @@ impl TryFrom<BitVec> for MacPingReqFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
-        if header.r#type != FrameType::MAC_PING_REQ.into() {
+        let (header, _) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::MAC_PING_REQ) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::MAC_PING_REQ.into(),
@@ -357,14 +437,8 @@ impl TryFrom<BitVec> for MacPingRespFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
-        if header.r#type != FrameType::MAC_PING_RESP.into() {
+        let (header, _) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::MAC_PING_RESP) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::MAC_PING_RESP.into(),
@@ -379,10 +453,11 @@ impl TryFrom<BitVec> for MacPingRespFrame {
 pub struct MacArpReqFrame {
     header: FrameHeader,
     target: usize,
+    sender: Option<usize>,
 }
 
 impl MacArpReqFrame {
-    pub fn new(src: usize, target: usize) -> Self {
+    pub fn new(src: usize, target: usize, sender: Option<usize>) -> Self {
         Self {
             header: FrameHeader {
                 dest: SOCKET_BROADCAST_ADDRESS,
@@ -392,12 +467,32 @@ impl MacArpReqFrame {
                 flag: FrameFlag::empty(),
             },
             target,
+            sender,
         }
     }
 
     pub fn target(&self) -> usize {
         self.target
     }
+
+    /// The IP of the requester, unless it is probing for its own IP.
+    pub fn sender(&self) -> Option<usize> {
+        self.sender
+    }
+
+    fn decode(header: FrameHeader, payload: &BitSlice) -> Result<Self> {
+        if payload.len() < 2 * ARP_IP_BITS_LEN {
+            return Err(
+                FrameDecodeError::FrameIsTooShort(payload.len(), 2 * ARP_IP_BITS_LEN).into(),
+            );
+        }
+        let sender = DecodeToInt::<usize>::decode(&payload[ARP_IP_BITS_LEN..2 * ARP_IP_BITS_LEN]);
+        Ok(Self {
+            header,
+            target: DecodeToInt::decode(&payload[..ARP_IP_BITS_LEN]),
+            sender: (sender != 0).then_some(sender),
+        })
+    }
 }
 
 impl Frame for MacArpReqFrame {
@@ -406,14 +501,15 @@ impl Frame for MacArpReqFrame {
     }
 
     fn payload(&self) -> Option<&BitSlice> {
-        Some(self.target.view_bits::<Lsb0>())
+        Some(&self.target.view_bits::<Lsb0>()[..ARP_IP_BITS_LEN])
     }
 }
 
 impl From<MacArpReqFrame> for BitVec {
     fn from(value: MacArpReqFrame) -> Self {
         let mut frame = BitVec::from(value.header);
-        frame.extend(value.target.view_bits::<Lsb0>());
+        frame.extend(&value.target.view_bits::<Lsb0>()[..ARP_IP_BITS_LEN]);
+        frame.extend(&value.sender.unwrap_or(0).view_bits::<Lsb0>()[..ARP_IP_BITS_LEN]);
         frame.extend(checksum(&frame));
         frame
     }
@@ -424,31 +520,15 @@ impl TryFrom<BitVec> for MacArpReqFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
-        if header.r#type != FrameType::MAC_ARP_REQ.into() {
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::MAC_ARP_REQ) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::MAC_ARP_REQ.into(),
             )
             .into());
         }
-        let sender = DecodeToInt::<usize>::decode(
-            &value[ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN..value.len() - PARITY_BITS_LEN],
-        );
-        Ok(Self {
-            header,
-            target: sender,
-        })
+        Self::decode(header, &value[offset..value.len() - PARITY_BITS_LEN])
     }
 }
 
@@ -501,37 +581,481 @@ impl TryFrom<BitVec> for MacArpRespFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
-        if header.r#type != FrameType::MAC_ARP_RESP.into() {
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::MAC_ARP_RESP) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::MAC_ARP_RESP.into(),
             )
             .into());
         }
-        let sender = DecodeToInt::<usize>::decode(
-            &value[ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN..value.len() - PARITY_BITS_LEN],
-        );
+        let sender = DecodeToInt::<usize>::decode(&value[offset..value.len() - PARITY_BITS_LEN]);
         Ok(Self { header, sender })
     }
 }
 
+fn encode_nav(duration: Duration) -> BitVec {
+    let millis = (duration.as_millis() as usize).min((1 << NAV_BITS_LEN) - 1);
+    millis.view_bits::<Lsb0>()[..NAV_BITS_LEN].to_owned()
+}
+
+fn decode_nav(payload: &BitSlice) -> Duration {
+    let len = payload.len().min(NAV_BITS_LEN);
+    Duration::from_millis(DecodeToInt::<u64>::decode(&payload[..len]))
+}
+
+#[derive(Debug, Clone)]
+pub struct RtsFrame {
+    header: FrameHeader,
+    duration: Duration,
+}
+
+impl RtsFrame {
+    pub fn new(dest: usize, src: usize, seq: usize, duration: Duration) -> Self {
+        Self {
+            header: FrameHeader {
+                dest,
+                src,
+                seq,
+                r#type: FrameType::RTS.into(),
+                flag: FrameFlag::empty(),
+            },
+            duration,
+        }
+    }
+
+    /// How long the medium is reserved for the exchange following the RTS.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+impl Frame for RtsFrame {
+    fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    fn payload(&self) -> Option<&BitSlice> {
+        None
+    }
+}
+
+impl From<RtsFrame> for BitVec {
+    fn from(value: RtsFrame) -> Self {
+        let mut frame = BitVec::from(value.header);
+        frame.extend(encode_nav(value.duration));
+        frame.extend(checksum(&frame));
+        frame
+    }
+}
+
+impl TryFrom<BitVec> for RtsFrame {
+    type Error = Error;
+
+    fn try_from(value: BitVec) -> Result<Self, Self::Error> {
+        verify(&value)?;
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::RTS) {
+            return Err(FrameDecodeError::UnexpectedFrameType(
+                header.r#type,
+                FrameType::RTS.into(),
+            )
+            .into());
+        }
+        let duration = decode_nav(&value[offset..value.len() - PARITY_BITS_LEN]);
+        Ok(Self { header, duration })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CtsFrame {
+    header: FrameHeader,
+    duration: Duration,
+}
+
+impl CtsFrame {
+    pub fn new(dest: usize, src: usize, seq: usize, duration: Duration) -> Self {
+        Self {
+            header: FrameHeader {
+                dest,
+                src,
+                seq,
+                r#type: FrameType::CTS.into(),
+                flag: FrameFlag::empty(),
+            },
+            duration,
+        }
+    }
+
+    /// How long the medium is reserved for the exchange following the CTS.
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+}
+
+impl Frame for CtsFrame {
+    fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    fn payload(&self) -> Option<&BitSlice> {
+        None
+    }
+}
+
+impl From<CtsFrame> for BitVec {
+    fn from(value: CtsFrame) -> Self {
+        let mut frame = BitVec::from(value.header);
+        frame.extend(encode_nav(value.duration));
+        frame.extend(checksum(&frame));
+        frame
+    }
+}
+
+impl TryFrom<BitVec> for CtsFrame {
+    type Error = Error;
+
+    fn try_from(value: BitVec) -> Result<Self, Self::Error> {
+        verify(&value)?;
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::CTS) {
+            return Err(FrameDecodeError::UnexpectedFrameType(
+                header.r#type,
+                FrameType::CTS.into(),
+            )
+            .into());
+        }
+        let duration = decode_nav(&value[offset..value.len() - PARITY_BITS_LEN]);
+        Ok(Self { header, duration })
+    }
+}
+
+/// Acknowledges a burst of data frames at once. Every frame before `seq` of the header has been
+/// received, and bit `i` of the bitmap tells whether frame `seq + 1 + i` has been received, too.
+/// The frame `seq` itself is the first one missing.
+#[derive(Debug, Clone)]
+pub struct BlockAckFrame {
+    header: FrameHeader,
+    window: usize,
+    bitmap: usize,
+}
+
+impl BlockAckFrame {
+    pub fn new(dest: usize, src: usize, base: usize, bitmap: usize, window: usize) -> Self {
+        Self {
+            header: FrameHeader {
+                dest,
+                src,
+                seq: base,
+                r#type: FrameType::BLOCK_ACK.into(),
+                flag: FrameFlag::empty(),
+            },
+            window,
+            bitmap,
+        }
+    }
+
+    /// Receive window advertised by the sender of the ACK.
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Whether the frame with the given sequence number is acknowledged. Sequence numbers up to
+    /// half the sequence space behind the base count as before it.
+    pub fn contains(&self, seq: usize) -> bool {
+        let space = 1 << SEQ_BITS_LEN;
+        let offset = (seq + space - self.header.seq) % space;
+        if offset >= space / 2 {
+            true
+        } else {
+            (1..=BLOCK_ACK_BITMAP_LEN).contains(&offset) && self.bitmap & (1 << (offset - 1)) != 0
+        }
+    }
+
+    fn decode_block(value: &BitSlice) -> Result<(usize, usize)> {
+        if value.len() < SEQ_BITS_LEN + BLOCK_ACK_BITMAP_LEN {
+            return Err(FrameDecodeError::FrameIsTooShort(
+                value.len(),
+                SEQ_BITS_LEN + BLOCK_ACK_BITMAP_LEN,
+            )
+            .into());
+        }
+        let window = DecodeToInt::decode(&value[..SEQ_BITS_LEN]);
+        let bitmap = DecodeToInt::decode(&value[SEQ_BITS_LEN..SEQ_BITS_LEN + BLOCK_ACK_BITMAP_LEN]);
+        Ok((window, bitmap))
+    }
+}
+
+impl Frame for BlockAckFrame {
+    fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    fn payload(&self) -> Option<&BitSlice> {
+        None
+    }
+}
+
+impl From<BlockAckFrame> for BitVec {
+    fn from(value: BlockAckFrame) -> Self {
+        let mut frame = BitVec::from(value.header);
+        frame.extend(&value.window.view_bits::<Lsb0>()[..SEQ_BITS_LEN]);
+        frame.extend(&value.bitmap.view_bits::<Lsb0>()[..BLOCK_ACK_BITMAP_LEN]);
+        frame.extend(checksum(&frame));
+        frame
+    }
+}
+
+impl TryFrom<BitVec> for BlockAckFrame {
+    type Error = Error;
+
+    fn try_from(value: BitVec) -> Result<Self, Self::Error> {
+        verify(&value)?;
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::BLOCK_ACK) {
+            return Err(FrameDecodeError::UnexpectedFrameType(
+                header.r#type,
+                FrameType::BLOCK_ACK.into(),
+            )
+            .into());
+        }
+        let (window, bitmap) = Self::decode_block(&value[offset..value.len() - PARITY_BITS_LEN])?;
+        Ok(Self {
+            header,
+            window,
+            bitmap,
+        })
+    }
+}
+
+/// Beacon of a TDMA coordinator. The slots of the schedule follow the beacon back to back, each of
+/// them `slot` long and owned by the station at the same position in `stations`.
+#[derive(Debug, Clone)]
+pub struct BeaconFrame {
+    header: FrameHeader,
+    slot: Duration,
+    stations: Vec<usize>,
+}
+
+impl BeaconFrame {
+    pub fn new(src: usize, slot: Duration, stations: Vec<usize>) -> Self {
+        Self {
+            header: FrameHeader {
+                dest: SOCKET_BROADCAST_ADDRESS,
+                src,
+                seq: 0,
+                r#type: FrameType::BEACON.into(),
+                flag: FrameFlag::empty(),
+            },
+            slot,
+            stations,
+        }
+    }
+
+    pub fn slot(&self) -> Duration {
+        self.slot
+    }
+
+    pub fn stations(&self) -> &[usize] {
+        &self.stations
+    }
+
+    fn decode_schedule(payload: &BitSlice) -> Result<(Duration, Vec<usize>)> {
+        if payload.len() < NAV_BITS_LEN + BEACON_COUNT_BITS_LEN {
+            return Err(FrameDecodeError::FrameIsTooShort(
+                payload.len(),
+                NAV_BITS_LEN + BEACON_COUNT_BITS_LEN,
+            )
+            .into());
+        }
+        let slot = decode_nav(&payload[..NAV_BITS_LEN]);
+        let count = DecodeToInt::<usize>::decode(
+            &payload[NAV_BITS_LEN..NAV_BITS_LEN + BEACON_COUNT_BITS_LEN],
+        );
+        let stations = &payload[NAV_BITS_LEN + BEACON_COUNT_BITS_LEN..];
+        if stations.len() < count * EXT_ADDRESS_BITS_LEN {
+            return Err(FrameDecodeError::FrameIsTooShort(
+                payload.len(),
+                NAV_BITS_LEN + BEACON_COUNT_BITS_LEN + count * EXT_ADDRESS_BITS_LEN,
+            )
+            .into());
+        }
+        let stations = stations
+            .chunks(EXT_ADDRESS_BITS_LEN)
+            .take(count)
+            .map(DecodeToInt::decode)
+            .collect();
+        Ok((slot, stations))
+    }
+}
+
+impl Frame for BeaconFrame {
+    fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    fn payload(&self) -> Option<&BitSlice> {
+        None
+    }
+}
+
+impl From<BeaconFrame> for BitVec {
+    fn from(value: BeaconFrame) -> Self {
+        let mut frame = BitVec::from(value.header);
+        frame.extend(encode_nav(value.slot));
+        frame.extend(&value.stations.len().view_bits::<Lsb0>()[..BEACON_COUNT_BITS_LEN]);
+        for station in value.stations {
+            frame.extend(&station.view_bits::<Lsb0>()[..EXT_ADDRESS_BITS_LEN]);
+        }
+        frame.extend(checksum(&frame));
+        frame
+    }
+}
+
+impl TryFrom<BitVec> for BeaconFrame {
+    type Error = Error;
+
+    fn try_from(value: BitVec) -> Result<Self, Self::Error> {
+        verify(&value)?;
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::BEACON) {
+            return Err(FrameDecodeError::UnexpectedFrameType(
+                header.r#type,
+                FrameType::BEACON.into(),
+            )
+            .into());
+        }
+        let (slot, stations) =
+            Self::decode_schedule(&value[offset..value.len() - PARITY_BITS_LEN])?;
+        Ok(Self {
+            header,
+            slot,
+            stations,
+        })
+    }
+}
+
+/// Neighbor discovery hello. The sequence number counts the hellos of the sender, so that receivers
+/// can tell how many of them they missed. `ratios` holds the delivery ratio of the hellos of each
+/// neighbor of the sender, which is the forward delivery ratio as seen from that neighbor.
+#[derive(Debug, Clone)]
+pub struct HelloFrame {
+    header: FrameHeader,
+    bit_rate: u32,
+    ratios: Vec<(usize, f32)>,
+}
+
+impl HelloFrame {
+    pub fn new(src: usize, seq: usize, bit_rate: u32, ratios: Vec<(usize, f32)>) -> Self {
+        Self {
+            header: FrameHeader {
+                dest: SOCKET_BROADCAST_ADDRESS,
+                src,
+                seq,
+                r#type: FrameType::HELLO.into(),
+                flag: FrameFlag::empty(),
+            },
+            bit_rate,
+            ratios,
+        }
+    }
+
+    pub fn bit_rate(&self) -> u32 {
+        self.bit_rate
+    }
+
+    pub fn ratios(&self) -> &[(usize, f32)] {
+        &self.ratios
+    }
+
+    fn decode_hello(payload: &BitSlice) -> Result<(u32, Vec<(usize, f32)>)> {
+        let len = HELLO_RATE_BITS_LEN + BEACON_COUNT_BITS_LEN;
+        if payload.len() < len {
+            return Err(FrameDecodeError::FrameIsTooShort(payload.len(), len).into());
+        }
+        let bit_rate = DecodeToInt::decode(&payload[..HELLO_RATE_BITS_LEN]);
+        let count = DecodeToInt::<usize>::decode(&payload[HELLO_RATE_BITS_LEN..len]);
+        let entry_len = EXT_ADDRESS_BITS_LEN + HELLO_RATIO_BITS_LEN;
+        let entries = &payload[len..];
+        if entries.len() < count * entry_len {
+            return Err(
+                FrameDecodeError::FrameIsTooShort(payload.len(), len + count * entry_len).into(),
+            );
+        }
+        let ratios = entries
+            .chunks(entry_len)
+            .take(count)
+            .map(|entry| {
+                let address = DecodeToInt::decode(&entry[..EXT_ADDRESS_BITS_LEN]);
+                let ratio = DecodeToInt::<usize>::decode(&entry[EXT_ADDRESS_BITS_LEN..]);
+                (
+                    address,
+                    ratio as f32 / ((1 << HELLO_RATIO_BITS_LEN) - 1) as f32,
+                )
+            })
+            .collect();
+        Ok((bit_rate, ratios))
+    }
+}
+
+impl Frame for HelloFrame {
+    fn header(&self) -> &FrameHeader {
+        &self.header
+    }
+
+    fn payload(&self) -> Option<&BitSlice> {
+        None
+    }
+}
+
+impl From<HelloFrame> for BitVec {
+    fn from(value: HelloFrame) -> Self {
+        let mut frame = BitVec::from(value.header);
+        frame.extend(&(value.bit_rate as usize).view_bits::<Lsb0>()[..HELLO_RATE_BITS_LEN]);
+        frame.extend(&value.ratios.len().view_bits::<Lsb0>()[..BEACON_COUNT_BITS_LEN]);
+        for (address, ratio) in value.ratios {
+            let ratio = (ratio.clamp(0., 1.) * ((1 << HELLO_RATIO_BITS_LEN) - 1) as f32).round();
+            frame.extend(&address.view_bits::<Lsb0>()[..EXT_ADDRESS_BITS_LEN]);
+            frame.extend(&(ratio as usize).view_bits::<Lsb0>()[..HELLO_RATIO_BITS_LEN]);
+        }
+        frame.extend(checksum(&frame));
+        frame
+    }
+}
+
+impl TryFrom<BitVec> for HelloFrame {
+    type Error = Error;
+
+    fn try_from(value: BitVec) -> Result<Self, Self::Error> {
+        verify(&value)?;
+        let (header, offset) = FrameHeader::decode(&value)?;
+        if header.r#type != usize::from(FrameType::HELLO) {
+            return Err(FrameDecodeError::UnexpectedFrameType(
+                header.r#type,
+                FrameType::HELLO.into(),
+            )
+            .into());
+        }
+        let (bit_rate, ratios) = Self::decode_hello(&value[offset..value.len() - PARITY_BITS_LEN])?;
+        Ok(Self {
+            header,
+            bit_rate,
+            ratios,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AcsmaFrame {
     NonAck(NonAckFrame),
     Ack(AckFrame),
     MacPingResp(MacPingRespFrame),
     MacArpResp(MacArpRespFrame),
+    Rts(RtsFrame),
+    Cts(CtsFrame),
+    Beacon(BeaconFrame),
+    BlockAck(BlockAckFrame),
+    Hello(HelloFrame),
 }
 
 impl From<AcsmaFrame> for BitVec {
@@ -541,6 +1065,11 @@ impl From<AcsmaFrame> for BitVec {
             AcsmaFrame::Ack(ack) => ack.into(),
             AcsmaFrame::MacPingResp(ping) => ping.into(),
             AcsmaFrame::MacArpResp(arp) => arp.into(),
+            AcsmaFrame::Rts(rts) => rts.into(),
+            AcsmaFrame::Cts(cts) => cts.into(),
+            AcsmaFrame::Beacon(beacon) => beacon.into(),
+            AcsmaFrame::BlockAck(block) => block.into(),
+            AcsmaFrame::Hello(hello) => hello.into(),
         }
     }
 }
@@ -550,27 +1079,47 @@ impl TryFrom<BitVec> for AcsmaFrame {
 
     fn try_from(value: BitVec) -> Result<Self, Self::Error> {
         verify(&value)?;
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
+        let (header, offset) = FrameHeader::decode(&value)?;
 
-        if header.r#type == FrameType::ACK.into() {
-            Ok(AcsmaFrame::Ack(AckFrame { header }))
-        } else if header.r#type == FrameType::MAC_PING_RESP.into() {
+        if header.r#type == usize::from(FrameType::ACK) {
+            let window = AckFrame::decode_window(&value, offset);
+            Ok(AcsmaFrame::Ack(AckFrame { header, window }))
+        } else if header.r#type == usize::from(FrameType::MAC_PING_RESP) {
             Ok(AcsmaFrame::MacPingResp(MacPingRespFrame { header }))
-        } else if header.r#type == FrameType::MAC_ARP_RESP.into() {
-            let sender = DecodeToInt::<usize>::decode(
-                &value[ADDRESS_BITS_LEN
-                    + ADDRESS_BITS_LEN
-                    + SEQ_BITS_LEN
-                    + TYPE_BITS_LEN
-                    + FLAG_BITS_LEN..value.len() - PARITY_BITS_LEN],
-            );
+        } else if header.r#type == usize::from(FrameType::MAC_ARP_RESP) {
+            let sender =
+                DecodeToInt::<usize>::decode(&value[offset..value.len() - PARITY_BITS_LEN]);
             Ok(AcsmaFrame::MacArpResp(MacArpRespFrame { header, sender }))
+        } else if header.r#type == usize::from(FrameType::RTS) {
+            let duration = decode_nav(&value[offset..value.len() - PARITY_BITS_LEN]);
+            Ok(AcsmaFrame::Rts(RtsFrame { header, duration }))
+        } else if header.r#type == usize::from(FrameType::CTS) {
+            let duration = decode_nav(&value[offset..value.len() - PARITY_BITS_LEN]);
+            Ok(AcsmaFrame::Cts(CtsFrame { header, duration }))
+        } else if header.r#type == usize::from(FrameType::BEACON) {
+            let (slot, stations) =
+                BeaconFrame::decode_schedule(&value[offset..value.len() - PARITY_BITS_LEN])?;
+            Ok(AcsmaFrame::Beacon(BeaconFrame {
+                header,
+                slot,
+                stations,
+            }))
+        } else if header.r#type == usize::from(FrameType::BLOCK_ACK) {
+            let (window, bitmap) =
+                BlockAckFrame::decode_block(&value[offset..value.len() - PARITY_BITS_LEN])?;
+            Ok(AcsmaFrame::BlockAck(BlockAckFrame {
+                header,
+                window,
+                bitmap,
+            }))
+        } else if header.r#type == usize::from(FrameType::HELLO) {
+            let (bit_rate, ratios) =
+                HelloFrame::decode_hello(&value[offset..value.len() - PARITY_BITS_LEN])?;
+            Ok(AcsmaFrame::Hello(HelloFrame {
+                header,
+                bit_rate,
+                ratios,
+            }))
         } else {
             Ok(AcsmaFrame::NonAck(NonAckFrame::try_from_bitvec_unchecked(
                 value,
@@ -586,6 +1135,11 @@ impl Frame for AcsmaFrame {
             AcsmaFrame::Ack(ack) => ack.header(),
             AcsmaFrame::MacPingResp(ping) => ping.header(),
             AcsmaFrame::MacArpResp(arp) => arp.header(),
+            AcsmaFrame::Rts(rts) => rts.header(),
+            AcsmaFrame::Cts(cts) => cts.header(),
+            AcsmaFrame::Beacon(beacon) => beacon.header(),
+            AcsmaFrame::BlockAck(block) => block.header(),
+            AcsmaFrame::Hello(hello) => hello.header(),
         }
     }
 
@@ -595,6 +1149,11 @@ impl Frame for AcsmaFrame {
             AcsmaFrame::Ack(ack) => ack.payload(),
             AcsmaFrame::MacPingResp(ping) => ping.payload(),
             AcsmaFrame::MacArpResp(arp) => arp.payload(),
+            AcsmaFrame::Rts(rts) => rts.payload(),
+            AcsmaFrame::Cts(cts) => cts.payload(),
+            AcsmaFrame::Beacon(beacon) => beacon.payload(),
+            AcsmaFrame::BlockAck(block) => block.payload(),
+            AcsmaFrame::Hello(hello) => hello.payload(),
         }
     }
 }
@@ -608,34 +1167,19 @@ pub enum NonAckFrame {
 
 impl NonAckFrame {
     fn try_from_bitvec_unchecked(value: BitVec) -> Result<Self> {
-        let header = FrameHeader::from(
-            &value[..ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN],
-        );
+        let (header, offset) = FrameHeader::decode(&value)?;
 
-        if header.r#type == FrameType::DATA.into() {
-            let payload = value[ADDRESS_BITS_LEN
-                + ADDRESS_BITS_LEN
-                + SEQ_BITS_LEN
-                + TYPE_BITS_LEN
-                + FLAG_BITS_LEN..value.len() - PARITY_BITS_LEN]
-                .to_owned();
+        if header.r#type == usize::from(FrameType::DATA) {
+            let payload = value[offset..value.len() - PARITY_BITS_LEN].to_owned();
             Ok(NonAckFrame::Data(DataFrame { header, payload }))
-        } else if header.r#type == FrameType::MAC_PING_REQ.into() {
+        } else if header.r#type == usize::from(FrameType::MAC_PING_REQ) {
             Ok(NonAckFrame::MacPingReq(MacPingReqFrame { header }))
-        } else if header.r#type == FrameType::MAC_ARP_REQ.into() {
-            let target = DecodeToInt::<usize>::decode(
-                &value[ADDRESS_BITS_LEN
-                    + ADDRESS_BITS_LEN
-                    + SEQ_BITS_LEN
-                    + TYPE_BITS_LEN
-                    + FLAG_BITS_LEN..value.len() - PARITY_BITS_LEN],
-            );
-            Ok(NonAckFrame::MacArpReq(MacArpReqFrame { header, target }))
-        } else if header.r#type == FrameType::ACK.into() {
+        } else if header.r#type == usize::from(FrameType::MAC_ARP_REQ) {
+            let payload = &value[offset..value.len() - PARITY_BITS_LEN];
+            Ok(NonAckFrame::MacArpReq(MacArpReqFrame::decode(
+                header, payload,
+            )?))
+        } else if header.r#type == usize::from(FrameType::ACK) {
             return Err(FrameDecodeError::UnexpectedFrameType(
                 header.r#type,
                 FrameType::DATA.into(),
@@ -648,9 +1192,9 @@ impl NonAckFrame {
 
     pub fn corresponds(&self, other: &FrameHeader) -> bool {
         match self {
-            NonAckFrame::Data(_) => other.r#type == FrameType::ACK.into(),
-            NonAckFrame::MacPingReq(_) => other.r#type == FrameType::MAC_PING_RESP.into(),
-            NonAckFrame::MacArpReq(_) => other.r#type == FrameType::MAC_ARP_RESP.into(),
+            NonAckFrame::Data(_) => other.r#type == usize::from(FrameType::ACK),
+            NonAckFrame::MacPingReq(_) => other.r#type == usize::from(FrameType::MAC_PING_RESP),
+            NonAckFrame::MacArpReq(_) => other.r#type == usize::from(FrameType::MAC_ARP_RESP),
         }
     }
 }
@@ -702,4 +1246,119 @@ pub enum FrameDecodeError {
     UnexpectedFrameType(usize, usize),
     #[error("Unknown frame type (got {0})")]
     UnknownFrameType(usize),
+    #[error("Unsupported header version (got {0})")]
+    UnsupportedVersion(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(frame: impl Into<BitVec>) -> AcsmaFrame {
+        AcsmaFrame::try_from(frame.into()).unwrap()
+    }
+
+    #[test]
+    fn test_ext_header() {
+        let payload = bitvec![1, 0, 1, 1, 0, 0, 1, 0];
+        let frame = DataFrame::new(300, 2, 5, FrameFlag::EOP, payload.clone());
+        let bits = BitVec::from(frame);
+        assert_eq!(
+            DecodeToInt::<usize>::decode(&bits[..ADDRESS_BITS_LEN]),
+            LEGACY_EXT_ADDRESS
+        );
+        assert_eq!(
+            DecodeToInt::<usize>::decode(&bits[ADDRESS_BITS_LEN..ADDRESS_BITS_LEN * 2]),
+            LEGACY_EXT_ADDRESS
+        );
+        let Ok(AcsmaFrame::NonAck(NonAckFrame::Data(data))) = AcsmaFrame::try_from(bits) else {
+            panic!("expected a data frame");
+        };
+        let header = data.header();
+        assert_eq!((header.dest, header.src, header.seq), (300, 2, 5));
+        assert_eq!(header.flag, FrameFlag::EOP);
+        assert_eq!(data.payload(), Some(payload.as_bitslice()));
+
+        let bits = BitVec::from(DataFrame::new(
+            SOCKET_BROADCAST_ADDRESS,
+            3,
+            0,
+            FrameFlag::empty(),
+            bitvec![],
+        ));
+        assert_eq!(bits.len(), HEADER_BITS_LEN + PARITY_BITS_LEN);
+        let Ok(AcsmaFrame::NonAck(NonAckFrame::Data(data))) = AcsmaFrame::try_from(bits) else {
+            panic!("expected a data frame");
+        };
+        assert_eq!(data.header().dest, SOCKET_BROADCAST_ADDRESS);
+    }
+
+    #[test]
+    fn test_rts_cts() {
+        let duration = Duration::from_millis(1234);
+        let AcsmaFrame::Rts(rts) = round_trip(RtsFrame::new(1, 400, 7, duration)) else {
+            panic!("expected an RTS frame");
+        };
+        assert_eq!(
+            (rts.header().dest, rts.header().src, rts.header().seq),
+            (1, 400, 7)
+        );
+        assert_eq!(rts.duration(), duration);
+
+        let AcsmaFrame::Cts(cts) = round_trip(CtsFrame::new(400, 1, 7, duration)) else {
+            panic!("expected a CTS frame");
+        };
+        assert_eq!(
+            (cts.header().dest, cts.header().src, cts.header().seq),
+            (400, 1, 7)
+        );
+        assert_eq!(cts.duration(), duration);
+    }
+
+    #[test]
+    fn test_block_ack() {
+        let frame = BlockAckFrame::new(2, 1, 10, 0b1000_0000_0000_0101, 9);
+        let AcsmaFrame::BlockAck(block) = round_trip(frame) else {
+            panic!("expected a block ACK frame");
+        };
+        assert_eq!(block.window(), 9);
+        assert!(block.contains(9));
+        assert!(!block.contains(10));
+        assert!(block.contains(11));
+        assert!(!block.contains(12));
+        assert!(block.contains(13));
+        assert!(block.contains(10 + BLOCK_ACK_BITMAP_LEN));
+        assert!(!block.contains(11 + BLOCK_ACK_BITMAP_LEN));
+    }
+
+    #[test]
+    fn test_beacon() {
+        let slot = Duration::from_millis(150);
+        let frame = BeaconFrame::new(1, slot, vec![1, 2, 1000]);
+        let AcsmaFrame::Beacon(beacon) = round_trip(frame) else {
+            panic!("expected a beacon frame");
+        };
+        assert_eq!(beacon.header().src, 1);
+        assert_eq!(beacon.header().dest, SOCKET_BROADCAST_ADDRESS);
+        assert_eq!(beacon.slot(), slot);
+        assert_eq!(beacon.stations(), [1, 2, 1000]);
+    }
+
+    #[test]
+    fn test_hello() {
+        let frame = HelloFrame::new(3, 42, 24000, vec![(1, 0.5), (500, 1.), (2, 0.)]);
+        let AcsmaFrame::Hello(hello) = round_trip(frame) else {
+            panic!("expected a hello frame");
+        };
+        assert_eq!((hello.header().src, hello.header().seq), (3, 42));
+        assert_eq!(hello.bit_rate(), 24000);
+        let expected = [(1, 0.5), (500, 1.), (2, 0.)];
+        assert_eq!(hello.ratios().len(), expected.len());
+        for (&(address, ratio), (expected_address, expected_ratio)) in
+            hello.ratios().iter().zip(expected)
+        {
+            assert_eq!(address, expected_address);
+            assert!((ratio - expected_ratio).abs() < 1. / ((1 << HELLO_RATIO_BITS_LEN) - 1) as f32);
+        }
+    }
 }
@@ -0,0 +1,168 @@
+use super::{builtin::SOCKET_BROADCAST_ADDRESS, qos::AcsmaTrafficClass, AcsmaIoError};
+use anyhow::Result;
+use bitvec::prelude::*;
+use std::future::Future;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+/// The sending half of a link. Packets are bits addressed to link addresses, the traffic class
+/// is a hint that links without scheduling ignore.
+pub trait LinkWriter: Send + Sync + 'static {
+    /// The address of this end of the link.
+    fn address(&self) -> usize;
+
+    /// The largest packet in bits.
+    fn mtu(&self) -> usize;
+
+    fn write_packet(
+        &self,
+        dest: usize,
+        packet: &BitSlice,
+        class: AcsmaTrafficClass,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Like `write_packet`, but may drop the packet instead of waiting when the link is
+    /// congested.
+    fn try_write_packet(
+        &self,
+        dest: usize,
+        packet: &BitSlice,
+        class: AcsmaTrafficClass,
+    ) -> impl Future<Output = Result<()>> + Send {
+        self.write_packet(dest, packet, class)
+    }
+
+    /// Resolve an IP address to the link address of its owner. Links without address resolution
+    /// resolve nothing.
+    fn resolve(&self, ip: usize) -> impl Future<Output = Result<usize>> + Send {
+        async move { Err(AcsmaIoError::ArpUnresolved(ip).into()) }
+    }
+}
+
+/// The receiving half of a link.
+pub trait LinkReader: Send + 'static {
+    /// The next packet addressed to this end or broadcast, together with its source.
+    fn read_packet(&mut self) -> impl Future<Output = Result<(usize, BitVec)>> + Send;
+}
+
+/// A link that packets can be written to and read from. It is split into its halves, so that
+/// both can be used at once.
+pub trait Link {
+    type Writer: LinkWriter;
+    type Reader: LinkReader;
+
+    fn split(self) -> (Self::Writer, Self::Reader);
+}
+
+/// One end of a lossless link held in memory, for running the gateways without a medium.
+pub struct AcsmaMemoryLink {
+    writer: AcsmaMemoryLinkWriter,
+    reader: AcsmaMemoryLinkReader,
+}
+
+impl AcsmaMemoryLink {
+    /// Two connected ends, given as their link address and IP address. Each end resolves the IP
+    /// address of the other one.
+    pub fn pair(a: (usize, usize), b: (usize, usize), mtu: usize) -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        let end = |(address, _), peer, tx, rx| Self {
+            writer: AcsmaMemoryLinkWriter {
+                address,
+                peer,
+                mtu,
+                tx,
+            },
+            reader: AcsmaMemoryLinkReader { rx },
+        };
+        (end(a, b, b_tx, a_rx), end(b, a, a_tx, b_rx))
+    }
+}
+
+impl Link for AcsmaMemoryLink {
+    type Writer = AcsmaMemoryLinkWriter;
+    type Reader = AcsmaMemoryLinkReader;
+
+    fn split(self) -> (Self::Writer, Self::Reader) {
+        (self.writer, self.reader)
+    }
+}
+
+pub struct AcsmaMemoryLinkWriter {
+    address: usize,
+    /// Link address and IP address of the other end.
+    peer: (usize, usize),
+    mtu: usize,
+    tx: UnboundedSender<(usize, BitVec)>,
+}
+
+impl LinkWriter for AcsmaMemoryLinkWriter {
+    fn address(&self) -> usize {
+        self.address
+    }
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    async fn write_packet(
+        &self,
+        dest: usize,
+        packet: &BitSlice,
+        _class: AcsmaTrafficClass,
+    ) -> Result<()> {
+        if packet.len() > self.mtu {
+            return Err(AcsmaIoError::PacketTooLarge(packet.len()).into());
+        }
+        // Packets to anyone else are lost, like on the medium.
+        if dest == self.peer.0 || dest == SOCKET_BROADCAST_ADDRESS {
+            self.tx
+                .send((self.address, packet.to_owned()))
+                .map_err(|_| AcsmaIoError::SocketClosed)?;
+        }
+        Ok(())
+    }
+
+    async fn resolve(&self, ip: usize) -> Result<usize> {
+        if ip == self.peer.1 {
+            Ok(self.peer.0)
+        } else {
+            Err(AcsmaIoError::ArpUnresolved(ip).into())
+        }
+    }
+}
+
+pub struct AcsmaMemoryLinkReader {
+    rx: UnboundedReceiver<(usize, BitVec)>,
+}
+
+impl LinkReader for AcsmaMemoryLinkReader {
+    async fn read_packet(&mut self) -> Result<(usize, BitVec)> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| AcsmaIoError::SocketClosed.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_link() {
+        let (a, b) = AcsmaMemoryLink::pair((1, 0x0a000001), (2, 0x0a000002), 64);
+        let (tx_a, _rx_a) = a.split();
+        let (_tx_b, mut rx_b) = b.split();
+        assert_eq!(tx_a.resolve(0x0a000002).await.unwrap(), 2);
+        assert!(tx_a.resolve(0x0a000003).await.is_err());
+
+        let class = AcsmaTrafficClass::Bulk;
+        tx_a.write_packet(3, &bitvec![0; 8], class).await.unwrap();
+        tx_a.write_packet(2, &bitvec![1; 8], class).await.unwrap();
+        assert_eq!(rx_b.read_packet().await.unwrap(), (1, bitvec![1; 8]));
+        assert!(tx_a.write_packet(2, &bitvec![1; 65], class).await.is_err());
+
+        drop(tx_a);
+        assert!(rx_b.read_packet().await.is_err());
+    }
+}
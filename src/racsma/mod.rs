@@ -1,11 +1,49 @@
+mod address;
+mod arp;
+mod capture;
+mod crypto;
+mod echo;
 mod frame;
+mod link;
+mod neighbor;
+mod packet;
+mod perf;
+mod phy;
+mod qos;
+#[cfg(feature = "sim")]
+mod sim;
 mod socket;
+mod stats;
 mod stream;
+mod tdma;
+mod timing;
 
 pub mod builtin;
 
-pub use socket::{AcsmaIoSocket, AcsmaSocketConfig, AcsmaSocketReader, AcsmaSocketWriter};
-pub use stream::{AcsmaIoStream, AcsmaStreamConfig};
+pub use address::parse_address;
+pub use arp::AcsmaArpEntry;
+pub use capture::{AcsmaCaptureDirection, AcsmaCaptureRecord, AcsmaCaptureWriter};
+pub use crypto::parse_psk;
+pub use link::{
+    AcsmaMemoryLink, AcsmaMemoryLinkReader, AcsmaMemoryLinkWriter, Link, LinkReader, LinkWriter,
+};
+pub use neighbor::AcsmaNeighbor;
+pub use perf::{AcsmaPerfConfig, AcsmaPerfMode, AcsmaPerfPattern, AcsmaPerfReport};
+pub use phy::{AcsmaAudioPhy, AcsmaPhy};
+pub use qos::{AcsmaBackoffConfig, AcsmaQueueDiscipline, AcsmaTrafficClass};
+#[cfg(feature = "sim")]
+pub use sim::{
+    AcsmaSimExpect, AcsmaSimMediumConfig, AcsmaSimNode, AcsmaSimNodeReport, AcsmaSimReport,
+    AcsmaSimScenario, AcsmaSimScenarioFile,
+};
+pub use socket::{
+    AcsmaIoSocket, AcsmaSocketConfig, AcsmaSocketHandle, AcsmaSocketMode, AcsmaSocketPermit,
+    AcsmaSocketReader, AcsmaSocketWriter,
+};
+pub use stats::{AcsmaHistogram, AcsmaPeerStats, AcsmaSocketStats, AcsmaSocketStatsHandle};
+pub use stream::{AcsmaIoStream, AcsmaIoStreamReader, AcsmaIoStreamWriter, AcsmaStreamConfig};
+pub use tdma::AcsmaTdmaConfig;
+pub use timing::{frame_duration, AcsmaTimingConfig};
 
 use thiserror::Error;
 
@@ -13,6 +51,32 @@ use thiserror::Error;
 pub enum AcsmaIoError {
     #[error("Link error after {0} retries")]
     LinkError(usize),
-    #[error("Perf timeout after {0} ms")]
-    PerfTimeout(usize),
+    #[error("Packet too large ({0} bits)")]
+    PacketTooLarge(usize),
+    #[error("Address {0} is unresolved")]
+    ArpUnresolved(usize),
+    #[error("Address conflict on {0}")]
+    AddressConflict(String),
+    #[error("Write queue full")]
+    QueueFull,
+    #[error("Frame dropped by queue management")]
+    Congested,
+    #[error("Socket closed")]
+    SocketClosed,
+    #[error("Socket shut down")]
+    Shutdown,
+    #[error("Invalid address `{0}`")]
+    InvalidAddress(String),
+    #[error("Invalid TDMA schedule: {0}")]
+    InvalidSchedule(String),
+    #[error("Invalid scenario: {0}")]
+    InvalidScenario(String),
+    #[error("Scenario missed its expectations: {0}")]
+    UnmetExpectations(String),
+    #[error("Invalid pre-shared key of length {0}, expected 64 hexadecimal digits")]
+    InvalidKey(usize),
+    #[error("Frame from {0} failed authentication")]
+    AuthFailed(usize),
+    #[error("Frame from {0} replayed")]
+    Replayed(usize),
 }
@@ -0,0 +1,195 @@
+use super::{
+    builtin::{
+        BEACON_COUNT_BITS_LEN, EXT_ADDRESS_BITS_LEN, HELLO_RATE_BITS_LEN, HELLO_RATIO_BITS_LEN,
+        PAYLOAD_BITS_LEN, SEQ_BITS_LEN, SOCKET_NEIGHBOR_SMOOTHING,
+    },
+    frame::{Frame, HelloFrame},
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+const HELLO_MAX_RATIOS_LEN: usize =
+    (PAYLOAD_BITS_LEN - HELLO_RATE_BITS_LEN - BEACON_COUNT_BITS_LEN)
+        / (EXT_ADDRESS_BITS_LEN + HELLO_RATIO_BITS_LEN);
+
+/// A neighbor as seen from outside. The delivery ratios are only known for neighbors sending
+/// hellos, the forward one only if we send hellos as well.
+#[derive(Debug, Clone)]
+pub struct AcsmaNeighbor {
+    pub mac: usize,
+    /// Time since any frame of the neighbor was heard.
+    pub last_heard: Duration,
+    /// Share of the recent hellos of the neighbor that reached us.
+    pub reverse_ratio: Option<f32>,
+    /// Share of our recent hellos that reached the neighbor, as reported in its hellos.
+    pub forward_ratio: Option<f32>,
+    /// Smoothed SNR in dB of the frames heard from the neighbor.
+    pub snr: Option<f32>,
+    /// Bit rate of the PHY of the neighbor, as announced in its hellos.
+    pub bit_rate: Option<u32>,
+}
+
+impl AcsmaNeighbor {
+    /// Expected number of transmissions of a frame and its ACK, as in ETX.
+    pub fn etx(&self) -> Option<f32> {
+        let ratio = self.forward_ratio? * self.reverse_ratio?;
+        (ratio > 0.).then(|| 1. / ratio)
+    }
+}
+
+struct AcsmaNeighborEntry {
+    last_heard: Instant,
+    last_seq: Option<usize>,
+    hellos: VecDeque<bool>,
+    forward_ratio: Option<f32>,
+    snr: Option<f32>,
+    bit_rate: Option<u32>,
+}
+
+impl AcsmaNeighborEntry {
+    fn new() -> Self {
+        Self {
+            last_heard: Instant::now(),
+            last_seq: None,
+            hellos: VecDeque::new(),
+            forward_ratio: None,
+            snr: None,
+            bit_rate: None,
+        }
+    }
+
+    fn reverse_ratio(&self) -> Option<f32> {
+        let received = self.hellos.iter().filter(|hello| **hello).count();
+        (!self.hellos.is_empty()).then(|| received as f32 / self.hellos.len() as f32)
+    }
+}
+
+/// Neighbors of a socket, learned from every frame heard and from their hellos. Hellos are counted
+/// by sequence number over a window of the last `window` ones, so that a gap in the sequence
+/// numbers counts as lost hellos. Neighbors not heard for `ttl` are forgotten.
+pub(super) struct AcsmaNeighborTable {
+    mac: usize,
+    ttl: Duration,
+    window: usize,
+    entries: BTreeMap<usize, AcsmaNeighborEntry>,
+}
+
+impl AcsmaNeighborTable {
+    pub fn new(mac: usize, ttl: Duration, window: usize) -> Self {
+        Self {
+            mac,
+            ttl,
+            window: window.max(1),
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl AcsmaNeighborTable {
+    pub fn hear(&mut self, src: usize, snr: Option<f32>) {
+        let entry = self
+            .entries
+            .entry(src)
+            .or_insert_with(AcsmaNeighborEntry::new);
+        entry.last_heard = Instant::now();
+        if let Some(snr) = snr {
+            entry.snr = Some(match entry.snr {
+                Some(old) => old + SOCKET_NEIGHBOR_SMOOTHING * (snr - old),
+                None => snr,
+            });
+        }
+    }
+
+    pub fn receive(&mut self, hello: &HelloFrame) {
+        let space = 1 << SEQ_BITS_LEN;
+        let header = hello.header();
+        let entry = self
+            .entries
+            .entry(header.src)
+            .or_insert_with(AcsmaNeighborEntry::new);
+        let gap = entry
+            .last_seq
+            .map_or(1, |last| (header.seq + space - last) % space);
+        if gap == 0 {
+            return;
+        }
+        if gap > self.window {
+            // The neighbor restarted or we lost track of it for a while.
+            entry.hellos.clear();
+        } else {
+            entry.hellos.extend((1..gap).map(|_| false));
+        }
+        entry.hellos.push_back(true);
+        while entry.hellos.len() > self.window {
+            entry.hellos.pop_front();
+        }
+        entry.last_seq = Some(header.seq);
+        entry.bit_rate = Some(hello.bit_rate());
+        entry.forward_ratio = hello
+            .ratios()
+            .iter()
+            .find(|(address, _)| *address == self.mac)
+            .map(|(_, ratio)| *ratio);
+    }
+
+    /// The delivery ratios to announce in our own hellos.
+    pub fn ratios(&mut self) -> Vec<(usize, f32)> {
+        self.expire();
+        self.entries
+            .iter()
+            .filter_map(|(mac, entry)| Some((*mac, entry.reverse_ratio()?)))
+            .take(HELLO_MAX_RATIOS_LEN.min((1 << BEACON_COUNT_BITS_LEN) - 1))
+            .collect()
+    }
+
+    pub fn entries(&mut self) -> Vec<AcsmaNeighbor> {
+        self.expire();
+        self.entries
+            .iter()
+            .map(|(mac, entry)| AcsmaNeighbor {
+                mac: *mac,
+                last_heard: entry.last_heard.elapsed(),
+                reverse_ratio: entry.reverse_ratio(),
+                forward_ratio: entry.forward_ratio,
+                snr: entry.snr,
+                bit_rate: entry.bit_rate,
+            })
+            .collect()
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, entry| entry.last_heard.elapsed() < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delivery_ratio() {
+        let mut table = AcsmaNeighborTable::new(1, Duration::from_secs(60), 4);
+        for seq in [10, 11, 13] {
+            table.receive(&HelloFrame::new(2, seq, 24000, vec![(1, 0.5)]));
+        }
+        let neighbor = table.entries().pop().unwrap();
+        assert_eq!(neighbor.mac, 2);
+        assert_eq!(neighbor.reverse_ratio, Some(0.75));
+        assert_eq!(neighbor.etx(), Some(1. / 0.375));
+        assert_eq!(neighbor.bit_rate, Some(24000));
+
+        // A jump beyond the window starts over, then the sequence numbers wrap around.
+        table.receive(&HelloFrame::new(2, 255, 24000, vec![]));
+        table.receive(&HelloFrame::new(2, 0, 24000, vec![]));
+        table.receive(&HelloFrame::new(2, 1, 24000, vec![]));
+        let neighbor = table.entries().pop().unwrap();
+        assert_eq!(neighbor.reverse_ratio, Some(1.));
+        assert_eq!(neighbor.forward_ratio, None);
+        assert_eq!(table.ratios(), vec![(2, 1.)]);
+    }
+}
@@ -0,0 +1,224 @@
+use super::{
+    builtin::{FRAGMENT_BITS_LEN, PAYLOAD_BITS_LEN, SOCKET_BROADCAST_ADDRESS},
+    frame::{payload_bits_len, DataFrame, Frame, FrameFlag},
+    AcsmaIoError,
+};
+use crate::rather::encode::DecodeToInt;
+use anyhow::Result;
+use bitvec::prelude::*;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+/// Fragment header at the beginning of the payload of every data frame sent by the socket. The
+/// packet id tells the packets of a peer apart, the index and count place the fragment inside its
+/// packet, independently of the sequence numbers used by the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcsmaFragment {
+    pub id: usize,
+    pub index: usize,
+    pub count: usize,
+}
+
+impl AcsmaFragment {
+    pub fn new(id: usize, index: usize, count: usize) -> Self {
+        Self { id, index, count }
+    }
+}
+
+impl From<AcsmaFragment> for BitVec {
+    fn from(value: AcsmaFragment) -> Self {
+        let mut bits = bitvec![];
+        bits.extend(&value.id.view_bits::<Lsb0>()[..FRAGMENT_BITS_LEN]);
+        bits.extend(&value.index.view_bits::<Lsb0>()[..FRAGMENT_BITS_LEN]);
+        bits.extend(&value.count.view_bits::<Lsb0>()[..FRAGMENT_BITS_LEN]);
+        bits
+    }
+}
+
+impl AcsmaFragment {
+    /// Split a payload into the fragment header and the fragment data.
+    pub(super) fn decode(payload: &BitSlice) -> Option<(Self, &BitSlice)> {
+        if payload.len() < 3 * FRAGMENT_BITS_LEN {
+            return None;
+        }
+        let fragment = Self {
+            id: DecodeToInt::decode(&payload[..FRAGMENT_BITS_LEN]),
+            index: DecodeToInt::decode(&payload[FRAGMENT_BITS_LEN..2 * FRAGMENT_BITS_LEN]),
+            count: DecodeToInt::decode(&payload[2 * FRAGMENT_BITS_LEN..3 * FRAGMENT_BITS_LEN]),
+        };
+        if fragment.index >= fragment.count {
+            return None;
+        }
+        Some((fragment, &payload[3 * FRAGMENT_BITS_LEN..]))
+    }
+}
+
+/// Split a packet into data frames, each of them carrying a fragment header and `len` bits of
+/// the packet. Sequence numbers are assigned by the daemon once a frame enters the send window.
+pub fn encode_packet(
+    bits: &BitSlice,
+    len: usize,
+    id: usize,
+    src: usize,
+    dest: usize,
+) -> Result<Vec<DataFrame>> {
+    let chunks = bits.chunks(len);
+    let count = chunks.len();
+    if count >= 1 << FRAGMENT_BITS_LEN {
+        return Err(AcsmaIoError::PacketTooLarge(bits.len()).into());
+    }
+
+    Ok(chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            let flag = if index == count - 1 {
+                FrameFlag::EOP
+            } else {
+                FrameFlag::empty()
+            };
+            let mut payload = BitVec::from(AcsmaFragment::new(id, index, count));
+            payload.extend_from_bitslice(chunk);
+            DataFrame::new(dest, src, 0, flag, payload)
+        })
+        .collect())
+}
+
+/// Reassembles packets from data frames. Fragments are collected per peer and packet id, so
+/// that packets sent by several peers at once do not get mixed up, and placed by their fragment
+/// index. Packets that stay incomplete for longer than the timeout are dropped.
+pub struct AcsmaPacketAssembler {
+    timeout: Duration,
+    len: usize,
+    packets: HashMap<(usize, bool, usize), AcsmaPartialPacket>,
+}
+
+struct AcsmaPartialPacket {
+    update: Instant,
+    fragments: Vec<Option<BitVec>>,
+}
+
+impl AcsmaPacketAssembler {
+    /// All fragments but the last one of a packet carry `len` bits of it, or as many more as the
+    /// legacy format saves, see `payload_bits_len`.
+    pub fn new(timeout: Duration, len: usize) -> Self {
+        Self {
+            timeout,
+            len,
+            packets: HashMap::new(),
+        }
+    }
+}
+
+impl AcsmaPacketAssembler {
+    /// Add a fragment, returning the source and the payload of the packet it completes.
+    pub fn push(&mut self, frame: DataFrame) -> Option<(usize, BitVec)> {
+        self.expire();
+
+        let header = frame.header();
+        let Some((fragment, data)) = AcsmaFragment::decode(frame.payload().unwrap()) else {
+            log::warn!(
+                "Drop frame {} from {} without fragment header",
+                header.seq,
+                header.src
+            );
+            return None;
+        };
+        let is_last = fragment.index == fragment.count - 1;
+        let len = self.len + payload_bits_len(header.dest, header.src) - PAYLOAD_BITS_LEN;
+        if (is_last && data.len() > len) || (!is_last && data.len() != len) {
+            log::warn!(
+                "Drop fragment {}/{} of packet {} from {} with length {}",
+                fragment.index,
+                fragment.count,
+                fragment.id,
+                header.src,
+                data.len()
+            );
+            return None;
+        }
+
+        let key = (
+            header.src,
+            header.dest == SOCKET_BROADCAST_ADDRESS,
+            fragment.id,
+        );
+        let packet = self
+            .packets
+            .entry(key)
+            .or_insert_with(|| AcsmaPartialPacket {
+                update: Instant::now(),
+                fragments: vec![None; fragment.count],
+            });
+        if packet.fragments.len() != fragment.count {
+            // The id has been reused for a new packet, the old one is lost.
+            packet.fragments = vec![None; fragment.count];
+        }
+        packet.update = Instant::now();
+        packet.fragments[fragment.index] = Some(data.to_owned());
+
+        if packet.fragments.iter().all(Option::is_some) {
+            let packet = self.packets.remove(&key).unwrap();
+            let payload =
+                packet
+                    .fragments
+                    .into_iter()
+                    .flatten()
+                    .fold(bitvec![], |mut acc, fragment| {
+                        acc.extend_from_bitslice(&fragment);
+                        acc
+                    });
+            log::info!(
+                "Reassembled packet {} from {}, {} frames, total {}",
+                fragment.id,
+                header.src,
+                fragment.count,
+                payload.len()
+            );
+            Some((header.src, payload))
+        } else {
+            None
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.timeout;
+        self.packets.retain(|(src, _, id), packet| {
+            let alive = packet.update.elapsed() < timeout;
+            if !alive {
+                log::warn!("Reassembly of packet {} from {} timed out", id, src);
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::racsma::builtin::{EXT_HEADER_BITS_LEN, FRAGMENT_PAYLOAD_BITS_LEN};
+
+    #[test]
+    fn test_reassemble() {
+        // Peers with legacy addresses send fragments without the extension.
+        let len = FRAGMENT_PAYLOAD_BITS_LEN + EXT_HEADER_BITS_LEN;
+        let bits = (0..3 * len + 7).map(|i| i % 3 == 0).collect::<BitVec>();
+        let mut assembler =
+            AcsmaPacketAssembler::new(Duration::from_secs(1), FRAGMENT_PAYLOAD_BITS_LEN);
+
+        let mut first = encode_packet(&bits, len, 1, 2, 0).unwrap();
+        let mut second = encode_packet(&bits[..10], len, 2, 3, 0).unwrap();
+        assert_eq!(first.len(), 4);
+        assert_eq!(second.len(), 1);
+
+        first.swap(0, 3);
+        assert!(assembler.push(first.remove(0)).is_none());
+        assert_eq!(
+            assembler.push(second.remove(0)),
+            Some((3, bits[..10].to_owned()))
+        );
+        for frame in first.drain(..2) {
+            assert!(assembler.push(frame).is_none());
+        }
+        assert_eq!(assembler.push(first.remove(0)), Some((2, bits)));
+    }
+}
@@ -0,0 +1,257 @@
+use super::{builtin::SOCKET_PERF_INTERVAL, stats::AcsmaSocketStats};
+use bitvec::prelude::*;
+use rand::{rngs::SmallRng, Rng};
+use serde::Serialize;
+use std::{fmt, time::Duration};
+
+/// The payload carried by the frames of a perf run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcsmaPerfPattern {
+    Zeros,
+    Ones,
+    Alternating,
+    Random,
+}
+
+impl AcsmaPerfPattern {
+    pub fn generate(&self, rng: &mut SmallRng, len: usize) -> BitVec {
+        match self {
+            Self::Zeros => bitvec![usize, Lsb0; 0; len],
+            Self::Ones => bitvec![usize, Lsb0; 1; len],
+            Self::Alternating => (0..len).map(|index| index % 2 == 1).collect(),
+            Self::Random => (0..len).map(|_| rng.gen::<bool>()).collect(),
+        }
+    }
+}
+
+/// Whether a perf run sends frames to the peer, only reports what it receives from the peer, or
+/// both at once. The receiving side is reported in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcsmaPerfMode {
+    Send,
+    Receive,
+    Both,
+}
+
+impl AcsmaPerfMode {
+    pub fn is_sending(&self) -> bool {
+        matches!(self, Self::Send | Self::Both)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcsmaPerfConfig {
+    /// How long to run, until interrupted if `None`.
+    pub duration: Option<Duration>,
+    pub pattern: AcsmaPerfPattern,
+    pub mode: AcsmaPerfMode,
+    /// How often to report on the last interval, only the summary is returned if `None`.
+    pub interval: Option<Duration>,
+}
+
+impl AcsmaPerfConfig {
+    pub fn new(duration: Option<Duration>, pattern: AcsmaPerfPattern, mode: AcsmaPerfMode) -> Self {
+        Self {
+            duration,
+            pattern,
+            mode,
+            interval: Some(SOCKET_PERF_INTERVAL),
+        }
+    }
+}
+
+/// The outcome of a perf run or one of its intervals. RTTs are taken from handing a frame to the
+/// socket to its ACK, so they include the time spent in the queue and in backoff.
+#[derive(Debug, Clone, Default)]
+pub struct AcsmaPerfReport {
+    pub elapsed: Duration,
+    /// Payload bytes of the frames acknowledged by the peer.
+    pub bytes_sent: usize,
+    /// Payload bytes delivered from the peer.
+    pub bytes_received: usize,
+    pub frames_acked: usize,
+    /// Frames given up on, by the link or after waiting too long for their ACK.
+    pub frames_lost: usize,
+    /// Retransmissions of the socket, taken from its statistics.
+    pub retransmissions: usize,
+    rtts: Vec<Duration>,
+}
+
+impl AcsmaPerfReport {
+    pub(super) fn ack(&mut self, rtt: Duration, len: usize) {
+        self.frames_acked += 1;
+        self.bytes_sent += len;
+        self.rtts.push(rtt);
+    }
+
+    pub(super) fn lose(&mut self) {
+        self.frames_lost += 1;
+    }
+
+    /// Take the counters kept by the socket since `base`.
+    pub(super) fn update(
+        &mut self,
+        base: &AcsmaSocketStats,
+        stats: &AcsmaSocketStats,
+        peer: usize,
+    ) {
+        let received = |stats: &AcsmaSocketStats| {
+            stats
+                .peers
+                .get(&peer)
+                .map_or(0, |stats| stats.bytes_received)
+        };
+        self.bytes_received = received(stats) - received(base);
+        self.retransmissions = stats.retransmissions - base.retransmissions;
+    }
+
+    /// Acknowledged payload rate in kbps.
+    pub fn throughput(&self) -> f64 {
+        self.rate(self.bytes_sent)
+    }
+
+    /// Delivered payload rate from the peer in kbps.
+    pub fn goodput(&self) -> f64 {
+        self.rate(self.bytes_received)
+    }
+
+    pub fn loss_rate(&self) -> Option<f64> {
+        let frames = self.frames_acked + self.frames_lost;
+        (frames > 0).then(|| self.frames_lost as f64 / frames as f64)
+    }
+
+    /// Retransmissions per frame sent.
+    pub fn retransmission_rate(&self) -> Option<f64> {
+        let frames = self.frames_acked + self.frames_lost;
+        (frames > 0).then(|| self.retransmissions as f64 / frames as f64)
+    }
+
+    /// The RTT below which the given share of the frames were acknowledged.
+    pub fn rtt_quantile(&self, quantile: f64) -> Option<Duration> {
+        let mut rtts = self.rtts.clone();
+        rtts.sort_unstable();
+        let rank = (rtts.len() as f64 * quantile).ceil() as usize;
+        rtts.get(rank.clamp(1, rtts.len().max(1)) - 1).copied()
+    }
+
+    /// Mean difference between the RTTs of consecutive frames.
+    pub fn jitter(&self) -> Option<Duration> {
+        let diffs = self.rtts.windows(2).map(|pair| pair[0].abs_diff(pair[1]));
+        let len = self.rtts.len().checked_sub(1).filter(|len| *len > 0)?;
+        Some(diffs.sum::<Duration>() / len as u32)
+    }
+
+    /// A single line JSON object, tagged with `kind`.
+    pub fn to_json(&self, kind: &str) -> String {
+        let millis = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.);
+        let json = AcsmaPerfJson {
+            kind,
+            elapsed_s: self.elapsed.as_secs_f64(),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            throughput_kbps: self.throughput(),
+            goodput_kbps: self.goodput(),
+            frames_acked: self.frames_acked,
+            frames_lost: self.frames_lost,
+            loss_rate: self.loss_rate(),
+            retransmissions: self.retransmissions,
+            retransmission_rate: self.retransmission_rate(),
+            rtt_ms: AcsmaPerfRttJson {
+                p50: millis(self.rtt_quantile(0.5)),
+                p90: millis(self.rtt_quantile(0.9)),
+                p99: millis(self.rtt_quantile(0.99)),
+                max: millis(self.rtt_quantile(1.)),
+            },
+            jitter_ms: millis(self.jitter()),
+        };
+        serde_json::to_string(&json).unwrap()
+    }
+
+    fn rate(&self, bytes: usize) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0. {
+            bytes as f64 * 8. / (1000. * secs)
+        } else {
+            0.
+        }
+    }
+}
+
+/// The JSON lines of `racsma perf --json`, as read by `assets/acsma/grade.py`.
+#[derive(Serialize)]
+struct AcsmaPerfJson<'a> {
+    kind: &'a str,
+    elapsed_s: f64,
+    bytes_sent: usize,
+    bytes_received: usize,
+    throughput_kbps: f64,
+    goodput_kbps: f64,
+    frames_acked: usize,
+    frames_lost: usize,
+    loss_rate: Option<f64>,
+    retransmissions: usize,
+    retransmission_rate: Option<f64>,
+    rtt_ms: AcsmaPerfRttJson,
+    jitter_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct AcsmaPerfRttJson {
+    p50: Option<f64>,
+    p90: Option<f64>,
+    p99: Option<f64>,
+    max: Option<f64>,
+}
+
+impl fmt::Display for AcsmaPerfReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = |rtt: Option<Duration>| rtt.map_or(0, |rtt| rtt.as_millis());
+        write!(
+            f,
+            "{:>6.1} s  sent {:>7.2} kbps  received {:>7.2} kbps  lost {}/{}  retransmitted {}  \
+             rtt p50={} p90={} p99={} ms  jitter {} ms",
+            self.elapsed.as_secs_f64(),
+            self.throughput(),
+            self.goodput(),
+            self.frames_lost,
+            self.frames_acked + self.frames_lost,
+            self.retransmissions,
+            millis(self.rtt_quantile(0.5)),
+            millis(self.rtt_quantile(0.9)),
+            millis(self.rtt_quantile(0.99)),
+            millis(self.jitter()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = AcsmaPerfReport {
+            elapsed: Duration::from_secs(2),
+            ..Default::default()
+        };
+        assert_eq!(report.rtt_quantile(0.5), None);
+        assert_eq!(report.jitter(), None);
+        assert!(report.to_json("summary").contains("\"loss_rate\":null"));
+
+        for rtt in [40, 10, 30, 20] {
+            report.ack(Duration::from_millis(rtt), 250);
+        }
+        report.lose();
+        assert_eq!(report.throughput(), 4.);
+        assert_eq!(report.loss_rate(), Some(0.2));
+        assert_eq!(report.rtt_quantile(0.5), Some(Duration::from_millis(20)));
+        assert_eq!(report.rtt_quantile(1.), Some(Duration::from_millis(40)));
+        assert_eq!(report.jitter(), Some(Duration::from_millis(20)));
+        assert!(report
+            .to_json("summary")
+            .starts_with("{\"kind\":\"summary\",\"elapsed_s\":2.0,\"bytes_sent\":1000,"));
+        assert!(report
+            .to_json("summary")
+            .contains("\"rtt_ms\":{\"p50\":20.0,\"p90\":40.0,\"p99\":40.0,\"max\":40.0}"));
+    }
+}
@@ -0,0 +1,209 @@
+use super::{
+    builtin::{SOCKET_COLISION_INTERVAL, SOCKET_JAM_DURATION},
+    echo::AcsmaEchoCanceller,
+    AcsmaSocketConfig,
+};
+use crate::{
+    rather::{signal::Energy, AtherInputStream, AtherOutputStream},
+    raudio::{AsioDevice, AudioInputStream, AudioOutputStream, SharedSamples},
+};
+use anyhow::Result;
+use bitvec::prelude::*;
+use log;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::{future::Future, sync::Arc, time::Instant};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time,
+};
+use tokio_stream::StreamExt;
+
+/// The physical layer below a socket. It carries frames and senses the medium for the MAC, which
+/// backs off while the medium is busy and resends frames whose transmission collided.
+pub trait AcsmaPhy: Send + 'static {
+    /// The next frame received, `None` once the PHY is closed. Dropping the future loses no frame.
+    fn read(&mut self) -> impl Future<Output = Option<BitVec>> + Send;
+
+    /// SNR of the last frame received in dB, if the PHY measures it.
+    fn snr(&self) -> Option<f32>;
+
+    /// Whether the medium is free of foreign energy.
+    fn is_free(&mut self) -> impl Future<Output = bool> + Send;
+
+    /// Send a frame while watching the medium for foreign energy. Once a collision is detected,
+    /// the frame is aborted and a jam signal is sent instead, so that every node involved backs
+    /// off. Returns whether the frame went through without a collision.
+    fn write(&mut self, bits: &BitSlice) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Send a frame without watching for collisions, for responses that are sent right away.
+    fn write_unchecked(&mut self, bits: &BitSlice) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// The acoustic PHY of an audio device. Collisions are detected on a second input stream, from
+/// which our own transmissions are cancelled.
+pub struct AcsmaAudioPhy {
+    read_ather: AtherInputStream,
+    write_ather: AtherOutputStream,
+    write_monitor: AcsmaSocketWriteMonitor,
+    sample_rate: u32,
+    free_threshold: f32,
+    collision_threshold: f32,
+}
+
+impl AcsmaAudioPhy {
+    pub fn try_from_device(config: &AcsmaSocketConfig, device: &AsioDevice) -> Result<Self> {
+        let ather_config = &config.ather_config;
+        let sample_rate = ather_config.stream_config.sample_rate().0;
+        Ok(Self {
+            read_ather: AtherInputStream::new(
+                ather_config.clone(),
+                AudioInputStream::try_from_device_config(
+                    device,
+                    ather_config.stream_config.clone(),
+                )?,
+            ),
+            write_ather: AtherOutputStream::new(
+                ather_config.clone(),
+                AudioOutputStream::try_from_device_config(
+                    device,
+                    ather_config.stream_config.clone(),
+                )?,
+            ),
+            write_monitor: AcsmaSocketWriteMonitor::new(
+                AudioInputStream::try_from_device_config(
+                    device,
+                    ather_config.stream_config.clone(),
+                )?,
+                sample_rate,
+            ),
+            sample_rate,
+            free_threshold: config.timing.free_threshold,
+            collision_threshold: config.timing.collision_threshold,
+        })
+    }
+}
+
+impl AcsmaPhy for AcsmaAudioPhy {
+    async fn read(&mut self) -> Option<BitVec> {
+        self.read_ather.next().await
+    }
+
+    fn snr(&self) -> Option<f32> {
+        self.read_ather.snr()
+    }
+
+    async fn is_free(&mut self) -> bool {
+        if let Some(sample) = self.write_monitor.sample().await {
+            sample.energy(self.sample_rate) < self.free_threshold
+        } else {
+            true
+        }
+    }
+
+    async fn write(&mut self, bits: &BitSlice) -> Result<bool> {
+        let samples = self.write_ather.encode(bits);
+        let on_start = self.write_monitor.transmit(samples.clone());
+
+        let colision_monitor = &mut self.write_monitor;
+        let (sample_rate, collision_threshold) = (self.sample_rate, self.collision_threshold);
+        let colision = async {
+            loop {
+                time::sleep(SOCKET_COLISION_INTERVAL).await;
+                if let Some(sample) = colision_monitor.sample().await {
+                    if sample.energy(sample_rate) > collision_threshold {
+                        break;
+                    }
+                }
+            }
+        };
+
+        tokio::select! {
+            result = self.write_ather.write_samples_with(samples, on_start) => {
+                result?;
+                Ok(true)
+            }
+            _ = colision => {
+                log::info!("Colision detected, sending jam signal");
+                let jam = create_jam(sample_rate);
+                let on_start = colision_monitor.transmit(jam.clone());
+                self.write_ather.write_samples_with(jam, on_start).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    async fn write_unchecked(&mut self, bits: &BitSlice) -> Result<()> {
+        let samples = self.write_ather.encode(bits);
+        let on_start = self.write_monitor.transmit(samples.clone());
+        self.write_ather.write_samples_with(samples, on_start).await
+    }
+}
+
+fn create_jam(sample_rate: u32) -> SharedSamples<f32> {
+    let mut rng = rand::thread_rng();
+    let len = (SOCKET_JAM_DURATION.as_secs_f32() * sample_rate as f32) as usize;
+    (0..len).map(|_| rng.gen_range(-1. ..=1.)).collect()
+}
+
+struct AcsmaSocketWriteMonitor {
+    req_tx: UnboundedSender<()>,
+    resp_rx: UnboundedReceiver<Option<Box<[f32]>>>,
+    canceller: Arc<Mutex<AcsmaEchoCanceller>>,
+}
+
+impl AcsmaSocketWriteMonitor {
+    fn new(mut write_monitor: AudioInputStream<f32>, sample_rate: u32) -> Self {
+        let (req_tx, mut req_rx) = mpsc::unbounded_channel();
+        let (resp_tx, resp_rx) = mpsc::unbounded_channel();
+        let canceller = Arc::new(Mutex::new(AcsmaEchoCanceller::new(sample_rate)));
+
+        tokio::spawn({
+            let canceller = canceller.clone();
+            async move {
+                let mut sample = None;
+                loop {
+                    tokio::select! {
+                        cmd = req_rx.recv() => {
+                            if cmd.is_some() && resp_tx.send(sample.clone()).is_ok() {
+                                continue;
+                            }
+                            break;
+                        },
+                        data = write_monitor.next() => {
+                            let arrival = Instant::now();
+                            sample = data.and_then(|data| canceller.lock().cancel(arrival, &data));
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            req_tx,
+            resp_rx,
+            canceller,
+        }
+    }
+
+    /// Record a waveform once it starts playing, so that it can be cancelled from the monitor
+    /// input. The returned callback is handed to `write_samples_with`.
+    fn transmit(&self, samples: SharedSamples<f32>) -> impl FnOnce(Instant) + Send + 'static {
+        let canceller = self.canceller.clone();
+        move |start| canceller.lock().transmit(start, samples)
+    }
+
+    /// The last block of the monitor input with our own transmissions cancelled, `None` before the
+    /// first block or once the monitor has stopped.
+    async fn sample(&mut self) -> Option<Box<[f32]>> {
+        self.clear();
+        if self.req_tx.send(()).is_err() {
+            return None;
+        }
+        self.resp_rx.recv().await.flatten()
+    }
+
+    fn clear(&mut self) {
+        while self.resp_rx.try_recv().is_ok() {}
+    }
+}
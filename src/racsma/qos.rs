@@ -0,0 +1,151 @@
+use super::builtin::{SOCKET_CODEL_INTERVAL, SOCKET_CODEL_TARGET};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Traffic classes of the socket, from the highest priority to the lowest. Frames of a higher
+/// class are always admitted and sent first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AcsmaTrafficClass {
+    /// ARP, ping and probes of the socket itself.
+    Control,
+    /// Small packets that somebody is waiting for, like TCP ACKs or ICMP.
+    Interactive,
+    /// Everything else.
+    Bulk,
+}
+
+impl AcsmaTrafficClass {
+    pub const ALL: [Self; 3] = [Self::Control, Self::Interactive, Self::Bulk];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Backoff parameters of a traffic class, in the spirit of 802.11e EDCA. Every backoff waits
+/// `aifs` slots plus a random number of slots out of a range that doubles with every retry, from
+/// `min_range` up to `max_range`. The range is further capped by the `max_range` of the socket
+/// timings.
+#[derive(Debug, Clone)]
+pub struct AcsmaBackoffConfig {
+    pub aifs: usize,
+    pub min_range: usize,
+    pub max_range: usize,
+}
+
+impl AcsmaBackoffConfig {
+    pub fn new(aifs: usize, min_range: usize, max_range: usize) -> Self {
+        Self {
+            aifs,
+            min_range,
+            max_range,
+        }
+    }
+
+    /// The default parameters of all classes, indexed by `AcsmaTrafficClass::index`.
+    pub fn defaults() -> [Self; 3] {
+        [
+            Self::new(0, 0, 2),
+            Self::new(0, 1, 4),
+            Self::new(1, 1, usize::MAX),
+        ]
+    }
+
+    pub fn range(&self, retry: usize) -> usize {
+        let range = 1usize.checked_shl(retry as u32).unwrap_or(usize::MAX);
+        range.clamp(self.min_range, self.max_range.max(self.min_range))
+    }
+}
+
+/// What to do with frames once the link cannot keep up with the writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcsmaQueueDiscipline {
+    /// Only refuse new frames once the queue of a class is full.
+    TailDrop,
+    /// Additionally drop frames that have been queued for too long, as in CoDel (RFC 8289).
+    Codel,
+}
+
+/// A simplified CoDel for a single queue. Once frames have been sitting in the queue for longer
+/// than `target` for a whole `interval`, frames are dropped on dequeue at a rate that grows with
+/// the square root of the number of drops, until the sojourn time falls below `target` again.
+pub(super) struct AcsmaCodel {
+    target: Duration,
+    interval: Duration,
+    first_above: Option<Instant>,
+    drop_next: Option<Instant>,
+    count: u32,
+}
+
+impl AcsmaCodel {
+    pub(super) fn new() -> Self {
+        Self {
+            target: SOCKET_CODEL_TARGET,
+            interval: SOCKET_CODEL_INTERVAL,
+            first_above: None,
+            drop_next: None,
+            count: 0,
+        }
+    }
+
+    /// Whether a frame that was queued at `queued` should be dropped instead of sent at `now`.
+    pub(super) fn dequeue(&mut self, queued: Instant, now: Instant) -> bool {
+        if now.saturating_duration_since(queued) < self.target {
+            self.first_above = None;
+            self.drop_next = None;
+            return false;
+        }
+
+        let first_above = *self.first_above.get_or_insert(now + self.interval);
+        match self.drop_next {
+            Some(drop_next) if now >= drop_next => {
+                self.count += 1;
+                self.drop_next = Some(drop_next + self.control_law());
+                true
+            }
+            Some(_) => false,
+            None if now >= first_above => {
+                self.count = 1;
+                self.drop_next = Some(now + self.control_law());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn control_law(&self) -> Duration {
+        self.interval.div_f64((self.count as f64).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codel() {
+        let mut codel = AcsmaCodel::new();
+        let start = Instant::now();
+        let queued = |at: Instant| at - SOCKET_CODEL_TARGET * 2;
+
+        // A standing queue is tolerated for one interval
+        assert!(!codel.dequeue(queued(start), start));
+        let at = start + SOCKET_CODEL_INTERVAL / 2;
+        assert!(!codel.dequeue(queued(at), at));
+
+        // Then frames are dropped at a growing rate
+        let at = start + SOCKET_CODEL_INTERVAL;
+        assert!(codel.dequeue(queued(at), at));
+        let at = at + SOCKET_CODEL_INTERVAL / 2;
+        assert!(!codel.dequeue(queued(at), at));
+        let at = at + SOCKET_CODEL_INTERVAL / 2;
+        assert!(codel.dequeue(queued(at), at));
+        let at = at + SOCKET_CODEL_INTERVAL.div_f64(2f64.sqrt());
+        assert!(codel.dequeue(queued(at), at));
+
+        // Until the queue drains
+        assert!(!codel.dequeue(at, at));
+        let at = at + SOCKET_CODEL_INTERVAL;
+        assert!(!codel.dequeue(queued(at), at));
+    }
+}
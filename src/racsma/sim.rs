@@ -0,0 +1,701 @@
+use super::{
+    address::parse_address,
+    builtin::{
+        SIM_PROPAGATION, SIM_SAMPLE_RATE, SIM_SENSE_DELAY, SOCKET_COLISION_INTERVAL,
+        SOCKET_JAM_DURATION,
+    },
+    perf::{AcsmaPerfConfig, AcsmaPerfMode, AcsmaPerfPattern, AcsmaPerfReport},
+    phy::AcsmaPhy,
+    socket::{AcsmaIoSocket, AcsmaSocketConfig},
+    stats::AcsmaSocketStats,
+    timing::frame_duration,
+    AcsmaIoError,
+};
+use crate::rather::{
+    builtin::{LENGTH_BITS_LEN, PREAMBLE_SYMBOL_LEN, WARMUP_SYMBOL_LEN},
+    AtherStreamConfig,
+};
+use anyhow::Result;
+use bitvec::prelude::*;
+use cpal::{SampleFormat, SampleRate, SupportedBufferSize, SupportedStreamConfig};
+use futures::future;
+use parking_lot::Mutex;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use serde::{de::Error, Deserialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    runtime,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::{self, Instant},
+};
+
+/// The shared medium of a simulation.
+#[derive(Debug, Clone)]
+pub struct AcsmaSimMediumConfig {
+    /// Delay from a node starting to send until the other nodes hear it.
+    pub propagation: Duration,
+    /// Delay from energy reaching a node until its carrier sense and collision detection see it.
+    pub sense_delay: Duration,
+    /// Probability that a frame which did not collide is lost anyway, for each of its receivers.
+    pub loss: f64,
+    /// Pairs of nodes, by MAC address, that are out of range of each other.
+    pub hidden: Vec<(usize, usize)>,
+}
+
+impl AcsmaSimMediumConfig {
+    pub fn new(propagation: Duration, sense_delay: Duration, loss: f64) -> Self {
+        Self {
+            propagation,
+            sense_delay,
+            loss,
+            hidden: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcsmaSimNode {
+    pub mac: usize,
+    /// The peer this node saturates with data frames, silent if `None`.
+    pub dest: Option<usize>,
+}
+
+impl AcsmaSimNode {
+    pub fn new(mac: usize, dest: Option<usize>) -> Self {
+        Self { mac, dest }
+    }
+}
+
+/// Nodes running the MAC of `AcsmaIoSocket` against a simulated medium, under a virtual clock.
+/// Every node with a peer measures the link to it as `perf` does, for the whole run.
+#[derive(Clone)]
+pub struct AcsmaSimScenario {
+    pub duration: Duration,
+    pub seed: u64,
+    pub medium: AcsmaSimMediumConfig,
+    /// The socket of every node, which only gets its own MAC address and seed.
+    pub socket: AcsmaSocketConfig,
+    pub nodes: Vec<AcsmaSimNode>,
+}
+
+impl AcsmaSimScenario {
+    pub fn new(bit_rate: u32, duration: Duration, seed: u64) -> Self {
+        let stream_config = SupportedStreamConfig::new(
+            1,
+            SampleRate(SIM_SAMPLE_RATE),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        Self {
+            duration,
+            seed,
+            medium: AcsmaSimMediumConfig::new(SIM_PROPAGATION, SIM_SENSE_DELAY, 0.),
+            socket: AcsmaSocketConfig::new(
+                0,
+                None,
+                AtherStreamConfig::new(bit_rate, stream_config),
+            ),
+            nodes: vec![],
+        }
+    }
+
+    /// Run the scenario to the end on a runtime of its own, whose clock only advances while all
+    /// nodes wait. Runs with the same seed give the same report.
+    pub fn run(&self) -> Result<AcsmaSimReport> {
+        self.validate()?;
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?;
+        runtime.block_on(self.simulate())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(0. ..=1.).contains(&self.medium.loss) {
+            return Err(AcsmaIoError::InvalidScenario(format!(
+                "loss {} outside of [0, 1]",
+                self.medium.loss
+            ))
+            .into());
+        }
+        let mut macs = HashSet::new();
+        for node in self.nodes.iter() {
+            if !macs.insert(node.mac) {
+                return Err(
+                    AcsmaIoError::InvalidScenario(format!("duplicate node {}", node.mac)).into(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn simulate(&self) -> Result<AcsmaSimReport> {
+        let ranges =
+            self.nodes
+                .iter()
+                .map(|from| {
+                    self.nodes
+                        .iter()
+                        .map(|to| {
+                            !self.medium.hidden.iter().any(|&pair| {
+                                pair == (from.mac, to.mac) || pair == (to.mac, from.mac)
+                            })
+                        })
+                        .collect()
+                })
+                .collect();
+        let medium = Arc::new(Mutex::new(AcsmaSimMedium {
+            config: self.medium.clone(),
+            bit_rate: self.socket.ather_config.bit_rate,
+            ranges,
+            rx_txs: vec![],
+            transmissions: BTreeMap::new(),
+            next_id: 0,
+            rng: SmallRng::seed_from_u64(self.seed),
+            frames: 0,
+            collided: 0,
+        }));
+
+        // All nodes probe their addresses at once, like nodes powered up together.
+        let mut opens = vec![];
+        for (index, node) in self.nodes.iter().enumerate() {
+            let (rx_tx, rx) = mpsc::unbounded_channel();
+            medium.lock().rx_txs.push(rx_tx);
+            let phy = AcsmaSimPhy {
+                index,
+                medium: medium.clone(),
+                rx,
+            };
+            let mut config = self.socket.clone();
+            config.mac = node.mac;
+            config.seed = Some(self.seed.wrapping_add(index as u64 + 1));
+            opens.push(AcsmaIoSocket::from_phy(config, phy));
+        }
+        let mut sockets = vec![];
+        for (node, socket) in self.nodes.iter().zip(future::join_all(opens).await) {
+            let (writer, mut reader, handle) = socket?;
+            tokio::spawn(async move { reader.serve().await });
+            sockets.push((node, writer, handle));
+        }
+
+        let mut perf = AcsmaPerfConfig::new(
+            Some(self.duration),
+            AcsmaPerfPattern::Zeros,
+            AcsmaPerfMode::Send,
+        );
+        perf.interval = None;
+        let runs = sockets.iter().map(|(node, writer, _)| {
+            let perf = perf.clone();
+            async move {
+                match node.dest {
+                    Some(dest) => writer.perf(dest, perf, |_| {}).await.map(Some),
+                    None => {
+                        time::sleep(self.duration).await;
+                        Ok(None)
+                    }
+                }
+            }
+        });
+        let perfs = future::join_all(runs).await;
+
+        let (frames, collided) = {
+            let medium = medium.lock();
+            (medium.frames, medium.collided)
+        };
+        let mut nodes = vec![];
+        for ((node, writer, handle), perf) in sockets.into_iter().zip(perfs) {
+            nodes.push(AcsmaSimNodeReport {
+                mac: node.mac,
+                perf: perf?,
+                stats: writer.stats(),
+            });
+            handle.shutdown().await?;
+        }
+
+        Ok(AcsmaSimReport {
+            duration: self.duration,
+            frames,
+            collided,
+            nodes,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcsmaSimNodeReport {
+    pub mac: usize,
+    /// The perf run of the node, `None` for silent nodes.
+    pub perf: Option<AcsmaPerfReport>,
+    pub stats: AcsmaSocketStats,
+}
+
+#[derive(Debug, Clone)]
+pub struct AcsmaSimReport {
+    pub duration: Duration,
+    /// Frames sent on the medium, jam signals aside.
+    pub frames: usize,
+    /// Frames aborted on a collision, or overlapping another transmission at one of their
+    /// receivers.
+    pub collided: usize,
+    pub nodes: Vec<AcsmaSimNodeReport>,
+}
+
+impl AcsmaSimReport {
+    /// Acknowledged payload rate of all nodes in kbps.
+    pub fn throughput(&self) -> f64 {
+        self.throughputs().sum()
+    }
+
+    /// Jain's fairness index over the throughput of the sending nodes, 1 when they all get the
+    /// same share and 1/n when a single one gets everything.
+    pub fn fairness(&self) -> Option<f64> {
+        let len = self.throughputs().count();
+        let sum = self.throughput();
+        let squares = self.throughputs().map(|rate| rate * rate).sum::<f64>();
+        (squares > 0.).then(|| sum * sum / (len as f64 * squares))
+    }
+
+    pub fn collision_rate(&self) -> Option<f64> {
+        (self.frames > 0).then(|| self.collided as f64 / self.frames as f64)
+    }
+
+    fn throughputs(&self) -> impl Iterator<Item = f64> + '_ {
+        self.nodes
+            .iter()
+            .filter_map(|node| node.perf.as_ref().map(AcsmaPerfReport::throughput))
+    }
+}
+
+impl fmt::Display for AcsmaSimReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>6.1} s  throughput {:>7.2} kbps  fairness {:.3}  collided {}/{} frames ({:.1}%)",
+            self.duration.as_secs_f64(),
+            self.throughput(),
+            self.fairness().unwrap_or_default(),
+            self.collided,
+            self.frames,
+            self.collision_rate().unwrap_or_default() * 100.,
+        )?;
+        for node in self.nodes.iter() {
+            write!(f, "\nnode {}: ", node.mac)?;
+            match &node.perf {
+                Some(perf) => write!(f, "{}", perf)?,
+                None => write!(f, "silent")?,
+            }
+            write!(f, "  collisions {}", node.stats.collisions)?;
+        }
+        Ok(())
+    }
+}
+
+/// A scenario as written in a TOML file, with its duration in seconds, the other durations in
+/// milliseconds and addresses as strings.
+#[derive(Deserialize, Debug)]
+pub struct AcsmaSimScenarioFile {
+    #[serde(rename = "duration")]
+    duration_s: u64,
+    seed: Option<u64>,
+    bit_rate: Option<u32>,
+    medium: Option<AcsmaSimMediumFile>,
+    socket: Option<AcsmaSimSocketFile>,
+    nodes: Vec<AcsmaSimNodeFile>,
+    pub expect: Option<AcsmaSimExpect>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AcsmaSimMediumFile {
+    #[serde(rename = "propagation")]
+    propagation_ms: Option<u64>,
+    #[serde(rename = "sense_delay")]
+    sense_delay_ms: Option<u64>,
+    loss: Option<f64>,
+    #[serde(default, deserialize_with = "deserialize_mac_pairs")]
+    hidden: Vec<(usize, usize)>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AcsmaSimSocketFile {
+    window: Option<usize>,
+    rts_threshold: Option<usize>,
+    block_ack: Option<bool>,
+    #[serde(rename = "slot")]
+    slot_ms: Option<u64>,
+    #[serde(rename = "ack_timeout")]
+    ack_timeout_ms: Option<u64>,
+    #[serde(rename = "receive_timeout")]
+    receive_timeout_ms: Option<u64>,
+    max_resends: Option<usize>,
+    max_range: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AcsmaSimNodeFile {
+    #[serde(deserialize_with = "deserialize_mac")]
+    mac: usize,
+    #[serde(default, deserialize_with = "deserialize_dest")]
+    dest: Option<usize>,
+}
+
+/// Bounds a report has to meet, so that scenarios can be run as regression tests.
+#[derive(Deserialize, Debug)]
+pub struct AcsmaSimExpect {
+    /// In kbps.
+    pub min_throughput: Option<f64>,
+    pub min_fairness: Option<f64>,
+    pub max_collision_rate: Option<f64>,
+}
+
+impl AcsmaSimScenarioFile {
+    pub fn parse(src: &str) -> Result<Self> {
+        Ok(toml::from_str(src)?)
+    }
+
+    pub fn translate(&self) -> AcsmaSimScenario {
+        let mut scenario = AcsmaSimScenario::new(
+            self.bit_rate.unwrap_or(24000),
+            Duration::from_secs(self.duration_s),
+            self.seed.unwrap_or_default(),
+        );
+        if let Some(medium) = &self.medium {
+            if let Some(propagation_ms) = medium.propagation_ms {
+                scenario.medium.propagation = Duration::from_millis(propagation_ms);
+            }
+            if let Some(sense_delay_ms) = medium.sense_delay_ms {
+                scenario.medium.sense_delay = Duration::from_millis(sense_delay_ms);
+            }
+            if let Some(loss) = medium.loss {
+                scenario.medium.loss = loss;
+            }
+            scenario.medium.hidden = medium.hidden.clone();
+        }
+        if let Some(socket) = &self.socket {
+            let socket_config = &mut scenario.socket;
+            if let Some(window) = socket.window {
+                socket_config.window = window;
+            }
+            socket_config.rts_threshold = socket.rts_threshold;
+            if let Some(block_ack) = socket.block_ack {
+                socket_config.block_ack = block_ack;
+            }
+            let timing = &mut socket_config.timing;
+            if let Some(slot_ms) = socket.slot_ms {
+                timing.slot = Duration::from_millis(slot_ms);
+            }
+            if let Some(ack_timeout_ms) = socket.ack_timeout_ms {
+                timing.ack_timeout = Duration::from_millis(ack_timeout_ms);
+            }
+            if let Some(receive_timeout_ms) = socket.receive_timeout_ms {
+                timing.receive_timeout = Duration::from_millis(receive_timeout_ms);
+            }
+            if let Some(max_resends) = socket.max_resends {
+                timing.max_resends = max_resends;
+            }
+            if let Some(max_range) = socket.max_range {
+                timing.max_range = max_range;
+            }
+        }
+        scenario.nodes = self
+            .nodes
+            .iter()
+            .map(|node| AcsmaSimNode::new(node.mac, node.dest))
+            .collect();
+        scenario
+    }
+}
+
+impl AcsmaSimExpect {
+    pub fn check(&self, report: &AcsmaSimReport) -> Result<()> {
+        let mut misses = vec![];
+        let throughput = report.throughput();
+        if let Some(min) = self.min_throughput.filter(|min| throughput < *min) {
+            misses.push(format!(
+                "throughput {:.2} kbps below {:.2}",
+                throughput, min
+            ));
+        }
+        let fairness = report.fairness().unwrap_or_default();
+        if let Some(min) = self.min_fairness.filter(|min| fairness < *min) {
+            misses.push(format!("fairness {:.3} below {:.3}", fairness, min));
+        }
+        let collision_rate = report.collision_rate().unwrap_or_default();
+        if let Some(max) = self.max_collision_rate.filter(|max| collision_rate > *max) {
+            misses.push(format!(
+                "collision rate {:.3} above {:.3}",
+                collision_rate, max
+            ));
+        }
+        if !misses.is_empty() {
+            return Err(AcsmaIoError::UnmetExpectations(misses.join(", ")).into());
+        }
+        Ok(())
+    }
+}
+
+fn deserialize_mac<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let mac = String::deserialize(deserializer)?;
+    parse_address(&mac).map_err(Error::custom)
+}
+
+fn deserialize_dest<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let dest = Option::<String>::deserialize(deserializer)?;
+    dest.map(|dest| parse_address(&dest).map_err(Error::custom))
+        .transpose()
+}
+
+fn deserialize_mac_pairs<'de, D>(deserializer: D) -> Result<Vec<(usize, usize)>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let pairs = Vec::<(String, String)>::deserialize(deserializer)?;
+    pairs
+        .iter()
+        .map(|(from, to)| {
+            let from = parse_address(from).map_err(Error::custom)?;
+            let to = parse_address(to).map_err(Error::custom)?;
+            Ok((from, to))
+        })
+        .collect()
+}
+
+struct AcsmaSimTransmission {
+    src: usize,
+    start: Instant,
+    end: Instant,
+    /// The frame sent, `None` for jam signals.
+    bits: Option<BitVec>,
+    aborted: bool,
+    collided: bool,
+}
+
+/// Every transmission on the medium, by id, until no node can hear it anymore. Nodes are
+/// referred to by their index in the scenario.
+struct AcsmaSimMedium {
+    config: AcsmaSimMediumConfig,
+    bit_rate: u32,
+    ranges: Vec<Vec<bool>>,
+    rx_txs: Vec<UnboundedSender<BitVec>>,
+    transmissions: BTreeMap<usize, AcsmaSimTransmission>,
+    next_id: usize,
+    rng: SmallRng,
+    frames: usize,
+    collided: usize,
+}
+
+type AcsmaSimMediumHandle = Arc<Mutex<AcsmaSimMedium>>;
+
+impl AcsmaSimMedium {
+    /// The delay from one node to another, `None` if out of range. Nodes hear themselves at once.
+    fn delay(&self, from: usize, to: usize) -> Option<Duration> {
+        if from == to {
+            Some(Duration::ZERO)
+        } else {
+            self.ranges[from][to].then_some(self.config.propagation)
+        }
+    }
+
+    /// Whether a node senses energy from the other nodes.
+    fn is_busy(&self, index: usize, now: Instant) -> bool {
+        self.transmissions.values().any(|transmission| {
+            transmission.src != index
+                && self.delay(transmission.src, index).is_some_and(|delay| {
+                    let delay = delay + self.config.sense_delay;
+                    transmission.start + delay <= now && now < transmission.end + delay
+                })
+        })
+    }
+
+    fn begin(&mut self, src: usize, bits: Option<BitVec>) -> (usize, Instant) {
+        let now = Instant::now();
+        let horizon = 2 * self.config.propagation
+            + self.config.sense_delay
+            + frame_duration(self.bit_rate)
+            + SOCKET_JAM_DURATION;
+        self.transmissions
+            .retain(|_, transmission| transmission.end + horizon > now);
+
+        let airtime = match &bits {
+            Some(bits) => {
+                self.frames += 1;
+                let symbols =
+                    WARMUP_SYMBOL_LEN + PREAMBLE_SYMBOL_LEN + LENGTH_BITS_LEN + bits.len();
+                Duration::from_secs_f64(symbols as f64 / self.bit_rate as f64)
+            }
+            None => SOCKET_JAM_DURATION,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.transmissions.insert(
+            id,
+            AcsmaSimTransmission {
+                src,
+                start: now,
+                end: now + airtime,
+                bits,
+                aborted: false,
+                collided: false,
+            },
+        );
+        (id, now + airtime)
+    }
+
+    fn abort(&mut self, id: usize) {
+        if let Some(transmission) = self.transmissions.get_mut(&id) {
+            transmission.end = Instant::now();
+            transmission.aborted = true;
+        }
+        self.collide(id);
+    }
+
+    fn collide(&mut self, id: usize) {
+        if let Some(transmission) = self.transmissions.get_mut(&id) {
+            if !transmission.collided {
+                transmission.collided = true;
+                self.collided += 1;
+            }
+        }
+    }
+
+    /// Hand a frame to a node once it has fully arrived, unless anything else reached the node
+    /// in the meantime, its own transmissions included.
+    fn deliver(&mut self, id: usize, to: usize) {
+        let Some(transmission) = self.transmissions.get(&id) else {
+            return;
+        };
+        let (Some(bits), Some(delay)) = (&transmission.bits, self.delay(transmission.src, to))
+        else {
+            return;
+        };
+        if transmission.aborted {
+            return;
+        }
+        let (start, end) = (transmission.start + delay, transmission.end + delay);
+        let overlapped = self.transmissions.iter().any(|(other_id, other)| {
+            *other_id != id
+                && self
+                    .delay(other.src, to)
+                    .is_some_and(|delay| other.start + delay < end && start < other.end + delay)
+        });
+        let bits = bits.clone();
+        if overlapped {
+            self.collide(id);
+        } else if !self.rng.gen_bool(self.config.loss) {
+            let _ = self.rx_txs[to].send(bits);
+        }
+    }
+}
+
+/// Start a transmission, scheduling its arrival at every node in range.
+fn transmit(medium: &AcsmaSimMediumHandle, src: usize, bits: Option<BitVec>) -> (usize, Instant) {
+    let is_frame = bits.is_some();
+    let mut guard = medium.lock();
+    let (id, end) = guard.begin(src, bits);
+    if is_frame {
+        for to in (0..guard.rx_txs.len()).filter(|to| *to != src) {
+            if let Some(delay) = guard.delay(src, to) {
+                let medium = medium.clone();
+                tokio::spawn(async move {
+                    time::sleep_until(end + delay).await;
+                    medium.lock().deliver(id, to);
+                });
+            }
+        }
+    }
+    (id, end)
+}
+
+struct AcsmaSimPhy {
+    index: usize,
+    medium: AcsmaSimMediumHandle,
+    rx: UnboundedReceiver<BitVec>,
+}
+
+impl AcsmaPhy for AcsmaSimPhy {
+    async fn read(&mut self) -> Option<BitVec> {
+        self.rx.recv().await
+    }
+
+    fn snr(&self) -> Option<f32> {
+        None
+    }
+
+    async fn is_free(&mut self) -> bool {
+        !self.medium.lock().is_busy(self.index, Instant::now())
+    }
+
+    async fn write(&mut self, bits: &BitSlice) -> Result<bool> {
+        let (id, end) = transmit(&self.medium, self.index, Some(bits.to_owned()));
+        loop {
+            time::sleep_until(end.min(Instant::now() + SOCKET_COLISION_INTERVAL)).await;
+            let now = Instant::now();
+            if now >= end {
+                return Ok(true);
+            }
+            let busy = self.medium.lock().is_busy(self.index, now);
+            if busy {
+                log::info!("Colision detected, sending jam signal");
+                self.medium.lock().abort(id);
+                let (_, end) = transmit(&self.medium, self.index, None);
+                time::sleep_until(end).await;
+                return Ok(false);
+            }
+        }
+    }
+
+    async fn write_unchecked(&mut self, bits: &BitSlice) -> Result<()> {
+        let (_, end) = transmit(&self.medium, self.index, Some(bits.to_owned()));
+        time::sleep_until(end).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scenario() -> AcsmaSimScenario {
+        let mut scenario = AcsmaSimScenario::new(24000, Duration::from_secs(30), 7);
+        scenario.socket.window = 1;
+        scenario.nodes = vec![
+            AcsmaSimNode::new(0, None),
+            AcsmaSimNode::new(1, Some(0)),
+            AcsmaSimNode::new(2, Some(0)),
+        ];
+        scenario
+    }
+
+    #[test]
+    fn test_simulation() {
+        let report = scenario().run().unwrap();
+        assert!(report.throughput() > 4.);
+        assert!(report.fairness().unwrap() > 0.9);
+        assert!(report.collision_rate().unwrap() < 0.3);
+        let again = scenario().run().unwrap();
+        assert_eq!(
+            (report.frames, report.collided),
+            (again.frames, again.collided)
+        );
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut lossy = scenario();
+        lossy.medium.loss = 1.5;
+        assert!(lossy.run().is_err());
+        let mut duplicate = scenario();
+        duplicate.nodes.push(AcsmaSimNode::new(1, Some(0)));
+        assert!(duplicate.run().is_err());
+    }
+}
@@ -1,43 +1,91 @@
 use super::{
+    arp::{AcsmaArpCache, AcsmaArpEntry},
     builtin::{
-        PAYLOAD_BITS_LEN, SEQ_BITS_LEN, SOCKET_ACK_TIMEOUT, SOCKET_BROADCAST_ADDRESS,
-        SOCKET_FREE_THRESHOLD, SOCKET_JAR_CAPACITY, SOCKET_MAX_RANGE, SOCKET_MAX_RESENDS,
-        SOCKET_PERF_INTERVAL, SOCKET_PERF_TIMEOUT, SOCKET_PING_INTERVAL, SOCKET_PING_TIMEOUT,
-        SOCKET_RECIEVE_TIMEOUT, SOCKET_SLOT_TIMEOUT,
+        BLOCK_ACK_BITMAP_LEN, CRYPTO_EPOCH_BITS_LEN, CRYPTO_OVERHEAD_BITS_LEN, FRAGMENT_BITS_LEN,
+        PAYLOAD_BITS_LEN, SEQ_BITS_LEN, SOCKET_ARP_NEGATIVE_TTL, SOCKET_ARP_TTL,
+        SOCKET_BROADCAST_ADDRESS, SOCKET_HELLO_WINDOW_LEN, SOCKET_HISTORY_LEN,
+        SOCKET_MAX_BURST_LEN, SOCKET_MAX_WINDOW_LEN, SOCKET_NEIGHBOR_TTL, SOCKET_PERF_TIMEOUT,
+        SOCKET_PING_INTERVAL, SOCKET_PING_TIMEOUT, SOCKET_QUEUE_LEN, SOCKET_REASSEMBLY_TIMEOUT,
+        SOCKET_WINDOW_LEN,
     },
+    capture::{AcsmaCaptureDirection, AcsmaCaptureRecord, AcsmaCaptureTap},
+    crypto::{initial_epoch, AcsmaCipher},
     frame::{
-        AckFrame, AcsmaFrame, DataFrame, Frame, FrameFlag, FrameHeader, MacArpReqFrame,
-        MacArpRespFrame, MacPingReqFrame, MacPingRespFrame, NonAckFrame,
+        payload_bits_len, AckFrame, AcsmaFrame, BlockAckFrame, CtsFrame, DataFrame, Frame,
+        FrameDecodeError, FrameFlag, FrameHeader, HelloFrame, MacArpReqFrame, MacArpRespFrame,
+        MacPingReqFrame, MacPingRespFrame, NonAckFrame, RtsFrame,
     },
+    link::{Link, LinkReader, LinkWriter},
+    neighbor::{AcsmaNeighbor, AcsmaNeighborTable},
+    packet::{encode_packet, AcsmaPacketAssembler},
+    perf::{AcsmaPerfConfig, AcsmaPerfReport},
+    phy::{AcsmaAudioPhy, AcsmaPhy},
+    qos::{AcsmaBackoffConfig, AcsmaCodel, AcsmaQueueDiscipline, AcsmaTrafficClass},
+    stats::{AcsmaSocketStats, AcsmaSocketStatsHandle},
+    tdma::{AcsmaTdmaConfig, AcsmaTdmaSchedule},
+    timing::{frame_duration, AcsmaRttEstimator, AcsmaTimingConfig},
     AcsmaIoError,
 };
-use crate::{
-    rather::{signal::Energy, AtherInputStream, AtherOutputStream, AtherStreamConfig},
-    raudio::{AsioDevice, AudioInputStream, AudioOutputStream},
-};
+use crate::{rather::AtherStreamConfig, raudio::AsioDevice};
 use anyhow::Result;
 use bitvec::prelude::*;
+use futures::stream::FuturesUnordered;
 use log;
+use parking_lot::Mutex;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
-use ringbuffer::{AllocRingBuffer, RingBuffer};
 use std::{
-    collections::BTreeMap,
-    mem,
-    time::{Duration, Instant},
+    collections::{BTreeMap, HashMap},
+    future, iter, mem,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
 use tokio::{
     sync::{
-        mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender},
+        mpsc::{
+            self,
+            error::{TryRecvError, TrySendError},
+            UnboundedReceiver, UnboundedSender,
+        },
         oneshot::{self, Sender},
     },
-    time,
+    task::JoinHandle,
+    time::{self, Instant},
 };
 use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Clone)]
 pub struct AcsmaSocketConfig {
     pub mac: usize,
     pub ip: Option<usize>,
+    pub window: usize,
+    pub history: usize,
+    /// Refuse to start if the addresses are already in use, instead of only warning.
+    pub exclusive: bool,
+    /// Payload length in bits above which data frames are preceded by RTS/CTS.
+    pub rts_threshold: Option<usize>,
+    /// Send the data frames queued for a peer in bursts, acknowledged by a single block ACK.
+    pub block_ack: bool,
+    /// Interval of the neighbor discovery hellos. Without hellos, neighbors are only learned from
+    /// the frames they send anyway.
+    pub hello: Option<Duration>,
+    /// Pre-shared key that the payloads of data frames are encrypted and authenticated with.
+    /// Frames that fail authentication are dropped, so all stations need the same key.
+    pub psk: Option<[u8; 32]>,
+    /// Seed of the backoff, sequence numbers and packet ids, for reproducible simulations.
+    pub seed: Option<u64>,
+    pub mode: AcsmaSocketMode,
+    pub timing: AcsmaTimingConfig,
+    /// Backoff parameters of each traffic class, indexed by `AcsmaTrafficClass::index`.
+    pub backoff: [AcsmaBackoffConfig; 3],
+    /// Depth of the write queue of each traffic class, in frames.
+    pub queue_len: usize,
+    pub discipline: AcsmaQueueDiscipline,
     pub ather_config: AtherStreamConfig,
 }
 
@@ -46,65 +94,126 @@ impl AcsmaSocketConfig {
         Self {
             mac,
             ip,
+            window: SOCKET_WINDOW_LEN,
+            history: SOCKET_HISTORY_LEN,
+            exclusive: false,
+            rts_threshold: None,
+            block_ack: false,
+            hello: None,
+            psk: None,
+            seed: None,
+            mode: AcsmaSocketMode::Csma,
+            timing: AcsmaTimingConfig::from_ather(&ather_config),
+            backoff: AcsmaBackoffConfig::defaults(),
+            queue_len: SOCKET_QUEUE_LEN,
+            discipline: AcsmaQueueDiscipline::Codel,
             ather_config,
         }
     }
+
+    /// Bits left for the payload of a data frame to `dest` once it is sealed. Without a peer,
+    /// the bits left in frames to any peer, that is, frames carrying the extension.
+    fn payload_len(&self, dest: Option<usize>) -> usize {
+        let len = dest.map_or(PAYLOAD_BITS_LEN, |dest| payload_bits_len(dest, self.mac));
+        match self.psk {
+            Some(_) => len - CRYPTO_OVERHEAD_BITS_LEN,
+            None => len,
+        }
+    }
+
+    /// Bits of a packet carried by each of its fragments.
+    fn fragment_len(&self, dest: Option<usize>) -> usize {
+        self.payload_len(dest) - 3 * FRAGMENT_BITS_LEN
+    }
+}
+
+/// How stations share the medium. With CSMA/CD, stations contend for the medium whenever it is
+/// free. With TDMA, a coordinator hands out slots in its beacons and stations only transmit in
+/// their own slot.
+#[derive(Debug, Clone)]
+pub enum AcsmaSocketMode {
+    Csma,
+    Tdma(AcsmaTdmaConfig),
+}
+
+type AcsmaArpCacheHandle = Arc<Mutex<AcsmaArpCache>>;
+type AcsmaNeighborTableHandle = Arc<Mutex<AcsmaNeighborTable>>;
+
+/// State shared between the daemon and the reader, writer and handle of a socket.
+struct AcsmaSocketHandles {
+    arp: AcsmaArpCacheHandle,
+    neighbors: AcsmaNeighborTableHandle,
+    stats: AcsmaSocketStatsHandle,
+    capture: AcsmaCaptureTap,
+    token: CancellationToken,
+    ready: oneshot::Sender<()>,
+}
+
+/// Handle to the daemon of a socket. Dropping it leaves the daemon running until the reader and
+/// writer are gone.
+pub struct AcsmaSocketHandle {
+    token: CancellationToken,
+    capture: AcsmaCaptureTap,
+    daemon: JoinHandle<Result<()>>,
+}
+
+impl AcsmaSocketHandle {
+    pub fn is_finished(&self) -> bool {
+        self.daemon.is_finished()
+    }
+
+    /// Receive every frame the socket sends or receives from now on, until the receiver is
+    /// dropped.
+    pub fn capture(&self) -> UnboundedReceiver<AcsmaCaptureRecord> {
+        self.capture.subscribe()
+    }
+
+    /// Stop the daemon and its audio streams. Frames still queued or in flight fail with
+    /// `AcsmaIoError::Shutdown`. Returns the error the daemon failed with, if any.
+    pub async fn shutdown(self) -> Result<()> {
+        self.token.cancel();
+        self.daemon.await?
+    }
 }
 
 pub struct AcsmaSocketReader {
     read_rx: UnboundedReceiver<NonAckFrame>,
+    assembler: AcsmaPacketAssembler,
+    stats: AcsmaSocketStatsHandle,
 }
 
 impl AcsmaSocketReader {
+    pub fn stats(&self) -> AcsmaSocketStats {
+        self.stats.snapshot()
+    }
+
+    pub fn stats_handle(&self) -> AcsmaSocketStatsHandle {
+        self.stats.clone()
+    }
+
     pub async fn read(&mut self, src: usize) -> Result<BitVec> {
-        let mut bucket = BTreeMap::new();
-        while let Some(frame) = self.read_rx.recv().await {
-            let header = frame.header().clone();
-            log::info!("Receive frame {}", header.seq);
-            if src == header.src {
-                if let NonAckFrame::Data(data) = frame {
-                    let payload = data.payload().unwrap();
-                    bucket.entry(header.seq).or_insert(payload.to_owned());
-
-                    if header.flag.contains(FrameFlag::EOP) {
-                        break;
-                    }
-                }
+        loop {
+            let (from, payload) = self.read_unchecked().await?;
+            if from == src {
+                return Ok(payload);
             }
         }
-
-        let result = bucket.iter().fold(bitvec![], |mut acc, (_, payload)| {
-            acc.extend_from_bitslice(payload);
-            acc
-        });
-
-        log::info!("Read {} frames, total {}", bucket.len(), result.len());
-
-        Ok(result)
     }
 
-    pub async fn read_unchecked(&mut self) -> Result<BitVec> {
-        let mut bucket = BTreeMap::new();
+    /// Read the next complete packet from any peer, returning its source address together with
+    /// the payload.
+    pub async fn read_unchecked(&mut self) -> Result<(usize, BitVec)> {
         while let Some(frame) = self.read_rx.recv().await {
             let header = frame.header().clone();
-            log::info!("Receive frame {}", header.seq);
+            log::info!("Receive frame {} from {}", header.seq, header.src);
             if let NonAckFrame::Data(data) = frame {
-                let payload = data.payload().unwrap();
-                bucket.entry(header.seq).or_insert(payload.to_owned());
-                if header.flag.contains(FrameFlag::EOP) {
-                    break;
+                if let Some(packet) = self.assembler.push(data) {
+                    return Ok(packet);
                 }
             }
         }
 
-        let result = bucket.iter().fold(bitvec![], |mut acc, (_, payload)| {
-            acc.extend_from_bitslice(payload);
-            acc
-        });
-
-        log::info!("Read {} frames, total {}", bucket.len(), result.len());
-
-        Ok(result)
+        Err(AcsmaIoError::SocketClosed.into())
     }
 
     pub async fn serve(&mut self) -> Result<()> {
@@ -118,70 +227,239 @@ impl AcsmaSocketReader {
 
 pub struct AcsmaSocketWriter {
     config: AcsmaSocketConfig,
-    write_tx: UnboundedSender<AcsmaSocketWriteTask>,
+    write_txs: Vec<mpsc::Sender<AcsmaSocketWriteTask>>,
+    packet_id: AtomicUsize,
+    arp: AcsmaArpCacheHandle,
+    neighbors: AcsmaNeighborTableHandle,
+    stats: AcsmaSocketStatsHandle,
 }
 
-fn encode_packet(bits: &BitSlice, src: usize, dest: usize) -> impl Iterator<Item = DataFrame> + '_ {
-    let frames = bits.chunks(PAYLOAD_BITS_LEN);
-    let len = frames.len();
+impl AcsmaSocketWriter {
+    pub fn stats(&self) -> AcsmaSocketStats {
+        self.stats.snapshot()
+    }
 
-    let mut rng = rand::thread_rng();
-    let base = rng.gen_range(0..(1 << SEQ_BITS_LEN));
+    pub fn stats_handle(&self) -> AcsmaSocketStatsHandle {
+        self.stats.clone()
+    }
 
-    frames.enumerate().map(move |(index, chunk)| {
-        let flag = if index == len - 1 {
-            FrameFlag::EOP
-        } else {
-            FrameFlag::empty()
-        };
-        let seq = (base + index) % (1 << SEQ_BITS_LEN);
-        DataFrame::new(dest, src, seq, flag, chunk.to_owned())
-    })
-}
+    fn next_packet_id(&self) -> usize {
+        self.packet_id.fetch_add(1, Ordering::Relaxed) % (1 << FRAGMENT_BITS_LEN)
+    }
+
+    /// Number of frames that can be queued in the given class without waiting.
+    pub fn capacity(&self, class: AcsmaTrafficClass) -> usize {
+        self.write_txs[class.index()].capacity()
+    }
+
+    /// Wait until the queue of the given class has room for another frame, and reserve it.
+    pub async fn ready(&self, class: AcsmaTrafficClass) -> Result<AcsmaSocketPermit<'_>> {
+        let permit = self.write_txs[class.index()]
+            .reserve()
+            .await
+            .map_err(|_| AcsmaIoError::SocketClosed)?;
+        Ok(AcsmaSocketPermit {
+            writer: self,
+            permit,
+            class,
+        })
+    }
+
+    async fn send(
+        &self,
+        frame: NonAckFrame,
+        class: AcsmaTrafficClass,
+    ) -> Result<oneshot::Receiver<Result<FrameHeader>>> {
+        let (tx, rx) = oneshot::channel();
+        self.write_txs[class.index()]
+            .send(AcsmaSocketWriteTask::new(frame, tx, class))
+            .await
+            .map_err(|_| AcsmaIoError::SocketClosed)?;
+        Ok(rx)
+    }
+
+    /// Reserve room for `len` frames in the queue of a class at once, or for none of them, so that
+    /// concurrent writers cannot leave a packet queued in part.
+    fn try_reserve(
+        &self,
+        class: AcsmaTrafficClass,
+        len: usize,
+    ) -> Result<Vec<mpsc::Permit<'_, AcsmaSocketWriteTask>>> {
+        let mut permits = Vec::with_capacity(len);
+        for _ in 0..len {
+            match self.write_txs[class.index()].try_reserve() {
+                Ok(permit) => permits.push(permit),
+                Err(TrySendError::Full(())) => {
+                    self.stats.lock().tail_drops += len;
+                    return Err(AcsmaIoError::QueueFull.into());
+                }
+                Err(TrySendError::Closed(())) => return Err(AcsmaIoError::SocketClosed.into()),
+            }
+        }
+        Ok(permits)
+    }
 
-impl AcsmaSocketWriter {
     pub async fn write(&self, dest: usize, bits: &BitSlice) -> Result<()> {
-        let frames = encode_packet(bits, self.config.mac, dest);
+        self.write_with_class(dest, bits, AcsmaTrafficClass::Bulk)
+            .await
+    }
+
+    pub async fn write_with_class(
+        &self,
+        dest: usize,
+        bits: &BitSlice,
+        class: AcsmaTrafficClass,
+    ) -> Result<()> {
+        let frames = encode_packet(
+            bits,
+            self.config.fragment_len(Some(dest)),
+            self.next_packet_id(),
+            self.config.mac,
+            dest,
+        )?;
+
+        let mut receivers = vec![];
+        for (index, frame) in frames.into_iter().enumerate() {
+            log::info!("Writing frame {}", index);
+            receivers.push(self.send(NonAckFrame::Data(frame), class).await?);
+        }
+
+        wait_receivers(receivers).await
+    }
+
+    pub async fn try_write(&self, dest: usize, bits: &BitSlice) -> Result<()> {
+        self.try_write_with_class(dest, bits, AcsmaTrafficClass::Bulk)
+            .await
+    }
+
+    /// Like `write_with_class`, but fails with `QueueFull` instead of waiting when the queue of
+    /// the class has no room for the whole packet. Packets of more frames than `queue_len` never
+    /// fit and fail with `PacketTooLarge`.
+    pub async fn try_write_with_class(
+        &self,
+        dest: usize,
+        bits: &BitSlice,
+        class: AcsmaTrafficClass,
+    ) -> Result<()> {
+        let frames = encode_packet(
+            bits,
+            self.config.fragment_len(Some(dest)),
+            self.next_packet_id(),
+            self.config.mac,
+            dest,
+        )?;
+        if frames.len() > self.config.queue_len.max(1) {
+            return Err(AcsmaIoError::PacketTooLarge(bits.len()).into());
+        }
+        let permits = self.try_reserve(class, frames.len())?;
 
-        for (index, frame) in frames.enumerate() {
+        let mut receivers = vec![];
+        for (index, (frame, permit)) in frames.into_iter().zip(permits).enumerate() {
             log::info!("Writing frame {}", index);
             let (tx, rx) = oneshot::channel();
-            self.write_tx.send((NonAckFrame::Data(frame), tx))?;
-            rx.await??;
-            log::info!("Wrote frame (ACK checked) {}", index);
+            permit.send(AcsmaSocketWriteTask::new(
+                NonAckFrame::Data(frame),
+                tx,
+                class,
+            ));
+            receivers.push(rx);
         }
 
-        Ok(())
+        wait_receivers(receivers).await
     }
 
     pub async fn write_unchecked(&self, bits: &BitSlice) -> Result<()> {
-        let frames = encode_packet(bits, self.config.mac, SOCKET_BROADCAST_ADDRESS);
+        let frames = encode_packet(
+            bits,
+            self.config.fragment_len(Some(SOCKET_BROADCAST_ADDRESS)),
+            self.next_packet_id(),
+            self.config.mac,
+            SOCKET_BROADCAST_ADDRESS,
+        )?;
 
-        for (_, frame) in frames.enumerate() {
-            let (tx, rx) = oneshot::channel();
-            self.write_tx.send((NonAckFrame::Data(frame), tx))?;
+        for frame in frames {
+            let rx = self
+                .send(NonAckFrame::Data(frame), AcsmaTrafficClass::Bulk)
+                .await?;
             rx.await??;
         }
 
         Ok(())
     }
 
-    pub async fn perf(&self, dest: usize) -> Result<()> {
-        let (send_tx, send_rx) = mpsc::unbounded_channel();
-        tokio::try_join!(
-            perf_main(send_rx),
-            perf_daemon(&self.config, &self.write_tx, dest, send_tx)
-        )?;
+    /// Measure the link to `dest`, handing the report of every interval to `on_interval`. Returns
+    /// the summary of the run.
+    pub async fn perf(
+        &self,
+        dest: usize,
+        perf: AcsmaPerfConfig,
+        mut on_interval: impl FnMut(&AcsmaPerfReport),
+    ) -> Result<AcsmaPerfReport> {
+        let mut rng = SmallRng::from_entropy();
+        let start = Instant::now();
+        let deadline = perf.duration.map(|duration| start + duration);
+        let mut ticks = perf
+            .interval
+            .map(|interval| time::interval_at(start + interval, interval));
+        let base = self.stats();
+        let mut summary = AcsmaPerfReport::default();
+        let mut interval_base = base.clone();
+        let mut interval = AcsmaPerfReport::default();
+        let mut interval_start = start;
+
+        let len = self.config.payload_len(Some(dest));
+        let mut receivers = FuturesUnordered::new();
+        loop {
+            while perf.mode.is_sending() && receivers.len() < self.config.window.max(1) {
+                let bits = perf.pattern.generate(&mut rng, len);
+                let frame = DataFrame::new(dest, self.config.mac, 0, FrameFlag::empty(), bits);
+                let rx = self
+                    .send(NonAckFrame::Data(frame), AcsmaTrafficClass::Bulk)
+                    .await?;
+                let sent = Instant::now();
+                receivers.push(async move {
+                    let result = time::timeout(SOCKET_PERF_TIMEOUT, rx).await;
+                    (sent.elapsed(), result)
+                });
+            }
+            tokio::select! {
+                Some((rtt, result)) = receivers.next() => match result {
+                    Ok(Ok(Ok(_))) => {
+                        summary.ack(rtt, len / 8);
+                        interval.ack(rtt, len / 8);
+                    }
+                    Ok(Ok(Err(_))) | Err(_) => {
+                        summary.lose();
+                        interval.lose();
+                    }
+                    Ok(Err(_)) => return Err(AcsmaIoError::SocketClosed.into()),
+                },
+                _ = async { ticks.as_mut().unwrap().tick().await }, if ticks.is_some() => {
+                    let stats = self.stats();
+                    interval.elapsed = interval_start.elapsed();
+                    interval.update(&interval_base, &stats, dest);
+                    on_interval(&interval);
+                    interval = AcsmaPerfReport::default();
+                    interval_base = stats;
+                    interval_start = Instant::now();
+                }
+                _ = time::sleep_until(deadline.unwrap_or(start)), if deadline.is_some() => {
+                    break;
+                }
+                else => break,
+            }
+        }
 
-        Ok(())
+        summary.elapsed = start.elapsed();
+        summary.update(&base, &self.stats(), dest);
+        Ok(summary)
     }
 
     pub async fn ping(&self, dest: usize) -> Result<()> {
         let frame = NonAckFrame::MacPingReq(MacPingReqFrame::new(dest, self.config.mac));
         loop {
             time::sleep(SOCKET_PING_INTERVAL).await;
-            let (tx, rx) = oneshot::channel();
-            self.write_tx.send((frame.clone(), tx))?;
+            let rx = self.send(frame.clone(), AcsmaTrafficClass::Control).await?;
             let start = Instant::now();
             if let Ok(inner) = time::timeout(SOCKET_PING_TIMEOUT, rx).await {
                 inner??;
@@ -193,237 +471,702 @@ impl AcsmaSocketWriter {
     }
 
     pub async fn arp(&self, target: usize) -> Result<usize> {
-        let frame = NonAckFrame::MacArpReq(MacArpReqFrame::new(self.config.mac, target));
-        let (tx, rx) = oneshot::channel();
-        self.write_tx.send((frame, tx))?;
-        let header = rx.await??;
-        Ok(header.src)
+        match self.arp.lock().get(target) {
+            Some(Some(mac)) => return Ok(mac),
+            Some(None) => return Err(AcsmaIoError::ArpUnresolved(target).into()),
+            None => {}
+        }
+
+        let frame =
+            NonAckFrame::MacArpReq(MacArpReqFrame::new(self.config.mac, target, self.config.ip));
+        let rx = self.send(frame, AcsmaTrafficClass::Control).await?;
+        match rx.await? {
+            Ok(header) => {
+                self.arp.lock().insert(target, header.src);
+                Ok(header.src)
+            }
+            Err(err) => {
+                self.arp.lock().insert_negative(target);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn arp_entries(&self) -> Vec<AcsmaArpEntry> {
+        self.arp.lock().entries()
+    }
+
+    pub fn flush_arp(&self) {
+        self.arp.lock().flush();
+    }
+
+    pub fn neighbors(&self) -> Vec<AcsmaNeighbor> {
+        self.neighbors.lock().entries()
     }
 }
 
-async fn perf_daemon(
-    config: &AcsmaSocketConfig,
-    write_tx: &UnboundedSender<AcsmaSocketWriteTask>,
-    dest: usize,
-    send_tx: UnboundedSender<usize>,
-) -> Result<()> {
-    let bits = bitvec![usize, Lsb0; 0; PAYLOAD_BITS_LEN];
-    let frame = DataFrame::new(dest, config.mac, 0, FrameFlag::empty(), bits);
+impl LinkWriter for AcsmaSocketWriter {
+    fn address(&self) -> usize {
+        self.config.mac
+    }
 
-    loop {
+    /// Packets of more frames than fit into the queue of a class are rejected by
+    /// `try_write_packet`, so they count towards the MTU, too.
+    fn mtu(&self) -> usize {
+        let frames = ((1 << FRAGMENT_BITS_LEN) - 1).min(self.config.queue_len.max(1));
+        frames * self.config.fragment_len(None)
+    }
+
+    async fn write_packet(
+        &self,
+        dest: usize,
+        packet: &BitSlice,
+        class: AcsmaTrafficClass,
+    ) -> Result<()> {
+        self.write_with_class(dest, packet, class).await
+    }
+
+    async fn try_write_packet(
+        &self,
+        dest: usize,
+        packet: &BitSlice,
+        class: AcsmaTrafficClass,
+    ) -> Result<()> {
+        self.try_write_with_class(dest, packet, class).await
+    }
+
+    async fn resolve(&self, ip: usize) -> Result<usize> {
+        self.arp(ip).await
+    }
+}
+
+impl LinkReader for AcsmaSocketReader {
+    async fn read_packet(&mut self) -> Result<(usize, BitVec)> {
+        self.read_unchecked().await
+    }
+}
+
+/// The halves returned by `AcsmaIoSocket::try_from_device`.
+impl Link for (AcsmaSocketWriter, AcsmaSocketReader) {
+    type Writer = AcsmaSocketWriter;
+    type Reader = AcsmaSocketReader;
+
+    fn split(self) -> (Self::Writer, Self::Reader) {
+        self
+    }
+}
+
+/// Room for a frame in the queue of a class, reserved by `AcsmaSocketWriter::ready`.
+pub struct AcsmaSocketPermit<'a> {
+    writer: &'a AcsmaSocketWriter,
+    permit: mpsc::Permit<'a, AcsmaSocketWriteTask>,
+    class: AcsmaTrafficClass,
+}
+
+impl AcsmaSocketPermit<'_> {
+    /// Write a packet to `dest`. Its first frame takes the reserved room, further frames wait for
+    /// room like `write_with_class`.
+    pub async fn write(self, dest: usize, bits: &BitSlice) -> Result<()> {
+        let writer = self.writer;
+        let frames = encode_packet(
+            bits,
+            writer.config.fragment_len(Some(dest)),
+            writer.next_packet_id(),
+            writer.config.mac,
+            dest,
+        )?;
+
+        let mut frames = frames.into_iter();
+        let Some(first) = frames.next() else {
+            return Ok(());
+        };
         let (tx, rx) = oneshot::channel();
-        write_tx.send((NonAckFrame::Data(frame.clone()), tx))?;
-        if let Ok(inner) = time::timeout(SOCKET_PERF_TIMEOUT, rx).await {
-            inner??;
-            let _ = send_tx.send(PAYLOAD_BITS_LEN);
-        } else {
-            break;
+        self.permit.send(AcsmaSocketWriteTask::new(
+            NonAckFrame::Data(first),
+            tx,
+            self.class,
+        ));
+        let mut receivers = vec![rx];
+        for frame in frames {
+            receivers.push(writer.send(NonAckFrame::Data(frame), self.class).await?);
         }
+
+        wait_receivers(receivers).await
+    }
+}
+
+async fn wait_receivers(receivers: Vec<oneshot::Receiver<Result<FrameHeader>>>) -> Result<()> {
+    for (index, rx) in receivers.into_iter().enumerate() {
+        rx.await??;
+        log::info!("Wrote frame (ACK checked) {}", index);
     }
 
     Ok(())
 }
 
-async fn perf_main(mut send_rx: UnboundedReceiver<usize>) -> Result<()> {
-    let mut sent = 0;
-    let mut epochs = 0usize;
-    'a: loop {
-        time::sleep(SOCKET_PERF_INTERVAL).await;
-        loop {
-            match send_rx.try_recv() {
-                Ok(len) => sent += len,
-                Err(TryRecvError::Disconnected) => {
-                    break 'a;
-                }
-                _ => {
-                    break;
-                }
-            }
+struct AcsmaSocketWriteTask {
+    frame: NonAckFrame,
+    tx: Sender<Result<FrameHeader>>,
+    class: AcsmaTrafficClass,
+    queued: Instant,
+}
+
+impl AcsmaSocketWriteTask {
+    fn new(frame: NonAckFrame, tx: Sender<Result<FrameHeader>>, class: AcsmaTrafficClass) -> Self {
+        Self {
+            frame,
+            tx,
+            class,
+            queued: Instant::now(),
         }
+    }
 
-        epochs += 1;
-        println!(
-            "Throughput: {} kbps",
-            sent as f32 / (1000. * SOCKET_PERF_INTERVAL.as_secs() as f32 * epochs as f32)
-        );
+    fn congested(self) {
+        let _ = self.tx.send(Err(AcsmaIoError::Congested.into()));
     }
 
-    Err(AcsmaIoError::PerfTimeout(SOCKET_PERF_TIMEOUT.as_millis() as usize).into())
+    fn shutdown(self) {
+        let _ = self.tx.send(Err(AcsmaIoError::Shutdown.into()));
+    }
 }
 
-type AcsmaSocketWriteTask = (NonAckFrame, Sender<Result<FrameHeader>>);
-
 pub struct AcsmaIoSocket;
 
 impl AcsmaIoSocket {
-    pub fn try_from_device(
+    pub async fn try_from_device(
         config: AcsmaSocketConfig,
         device: &AsioDevice,
-    ) -> Result<(AcsmaSocketWriter, AcsmaSocketReader)> {
-        let (read_tx, read_rx) = mpsc::unbounded_channel();
-        let (write_tx, write_rx) = mpsc::unbounded_channel();
+    ) -> Result<(AcsmaSocketWriter, AcsmaSocketReader, AcsmaSocketHandle)> {
+        let phy = AcsmaAudioPhy::try_from_device(&config, device)?;
+        Self::from_phy(config, phy).await
+    }
 
-        tokio::spawn(socket_daemon(
+    /// A socket on top of any PHY, such as the simulated medium of `AcsmaSimScenario`. Returns
+    /// once our addresses have been probed, failing on a conflict if the socket is exclusive.
+    pub async fn from_phy<P: AcsmaPhy>(
+        config: AcsmaSocketConfig,
+        phy: P,
+    ) -> Result<(AcsmaSocketWriter, AcsmaSocketReader, AcsmaSocketHandle)> {
+        if let AcsmaSocketMode::Tdma(tdma) = &config.mode {
+            tdma.validate(config.mac)?;
+        }
+        let (read_tx, read_rx) = mpsc::unbounded_channel();
+        let queue_len = config.queue_len.max(1);
+        let (write_txs, write_rxs) = AcsmaTrafficClass::ALL
+            .iter()
+            .map(|_| mpsc::channel(queue_len))
+            .unzip();
+        let stats = AcsmaSocketStatsHandle::default();
+        let arp = Arc::new(Mutex::new(AcsmaArpCache::new(
+            SOCKET_ARP_TTL,
+            SOCKET_ARP_NEGATIVE_TTL,
+        )));
+        let neighbors = Arc::new(Mutex::new(AcsmaNeighborTable::new(
+            config.mac,
+            SOCKET_NEIGHBOR_TTL,
+            SOCKET_HELLO_WINDOW_LEN,
+        )));
+
+        let capture = AcsmaCaptureTap::default();
+        let token = CancellationToken::new();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        let fragment_len = config.fragment_len(None);
+        let mut rng = create_rng(&config);
+        // The daemon draws from a stream of its own rather than repeating the one of the writer.
+        let daemon_rng = SmallRng::seed_from_u64(rng.gen());
+        let daemon = tokio::spawn(socket_daemon(
             config.clone(),
-            AtherInputStream::new(
-                config.ather_config.clone(),
-                AudioInputStream::try_from_device_config(
-                    device,
-                    config.ather_config.stream_config.clone(),
-                )?,
-            ),
-            AtherOutputStream::new(
-                config.ather_config.clone(),
-                AudioOutputStream::try_from_device_config(
-                    device,
-                    config.ather_config.stream_config.clone(),
-                )?,
-            ),
-            AudioInputStream::try_from_device_config(
-                device,
-                config.ather_config.stream_config.clone(),
-            )?,
+            phy,
+            daemon_rng,
             read_tx,
-            write_rx,
+            write_rxs,
+            AcsmaSocketHandles {
+                arp: arp.clone(),
+                neighbors: neighbors.clone(),
+                stats: stats.clone(),
+                capture: capture.clone(),
+                token: token.clone(),
+                ready: ready_tx,
+            },
         ));
+        if ready_rx.await.is_err() {
+            daemon.await??;
+            return Err(AcsmaIoError::Shutdown.into());
+        }
 
         Ok((
-            AcsmaSocketWriter { config, write_tx },
-            AcsmaSocketReader { read_rx },
+            AcsmaSocketWriter {
+                config,
+                write_txs,
+                packet_id: AtomicUsize::new(rng.gen_range(0..(1 << FRAGMENT_BITS_LEN))),
+                arp,
+                neighbors,
+                stats: stats.clone(),
+            },
+            AcsmaSocketReader {
+                read_rx,
+                assembler: AcsmaPacketAssembler::new(SOCKET_REASSEMBLY_TIMEOUT, fragment_len),
+                stats,
+            },
+            AcsmaSocketHandle {
+                token,
+                capture,
+                daemon,
+            },
         ))
     }
 
-    pub fn try_default(
+    pub async fn try_default(
         config: AcsmaSocketConfig,
-    ) -> Result<(AcsmaSocketWriter, AcsmaSocketReader)> {
+    ) -> Result<(AcsmaSocketWriter, AcsmaSocketReader, AcsmaSocketHandle)> {
         let device = AsioDevice::try_default()?;
-        Self::try_from_device(config, &device)
+        Self::try_from_device(config, &device).await
     }
 }
 
-async fn socket_daemon(
+async fn socket_daemon<P: AcsmaPhy>(
     config: AcsmaSocketConfig,
-    mut read_ather: AtherInputStream,
-    write_ather: AtherOutputStream,
-    write_monitor: AudioInputStream<f32>,
+    mut phy: P,
+    mut rng: SmallRng,
     read_tx: UnboundedSender<NonAckFrame>,
-    mut write_rx: UnboundedReceiver<AcsmaSocketWriteTask>,
+    mut write_rxs: Vec<mpsc::Receiver<AcsmaSocketWriteTask>>,
+    handles: AcsmaSocketHandles,
 ) -> Result<()> {
-    let mut rng = SmallRng::from_entropy();
-    let mut write_state: Option<AcsmaSocketWriteTimer> = None;
-    let mut write_monitor = AcsmaSocketWriteMonitor::new(write_monitor);
-    let mut read_jar = AllocRingBuffer::new(SOCKET_JAR_CAPACITY);
+    let AcsmaSocketHandles {
+        arp,
+        neighbors,
+        stats,
+        capture,
+        token,
+        ready,
+    } = handles;
+    let mut ready = Some(ready);
+    let mut window = config.window.clamp(1, SOCKET_MAX_WINDOW_LEN);
+    if config.block_ack {
+        // Every frame in flight has to fit into the bitmap of a block ACK after its base.
+        window = window.min(BLOCK_ACK_BITMAP_LEN + 1);
+    }
+    let mut write_states: Vec<AcsmaSocketWriteTimer> = vec![];
+    let mut write_pending: Vec<Option<AcsmaSocketWriteTask>> =
+        write_rxs.iter().map(|_| None).collect();
+    let mut codels: Vec<AcsmaCodel> = write_rxs.iter().map(|_| AcsmaCodel::new()).collect();
+    let mut write_peers: HashMap<usize, AcsmaSocketWritePeer> = HashMap::new();
+    let history = config.history.clamp(1, (1 << SEQ_BITS_LEN) - window);
+    let mut read_windows: HashMap<(usize, bool), AcsmaSocketReadWindow> = HashMap::new();
+    let mut cipher = config
+        .psk
+        .map(|psk| AcsmaCipher::new(&psk, window + history));
+    let mut nav = Instant::now();
+    // Our own frames wait until then, to leave the medium to the peer sending to us.
+    let mut listen = Instant::now();
+    let mut schedule = match &config.mode {
+        AcsmaSocketMode::Csma => None,
+        AcsmaSocketMode::Tdma(tdma) => Some(AcsmaTdmaSchedule::new(config.mac, tdma.clone())),
+    };
+    let mut write_closed: Vec<bool> = write_rxs.iter().map(|_| false).collect();
+    let mut hello_seq = rng.gen_range(0..(1 << SEQ_BITS_LEN));
+    let mut hello_due = Instant::now();
+    let (mut probe, tasks) = AcsmaSocketProbe::new(&config);
+    for task in tasks {
+        write_states.push(admit_task(
+            &config,
+            &mut rng,
+            &mut write_peers,
+            cipher.as_ref(),
+            task,
+        ));
+    }
     loop {
-        if let Ok(Some(bits)) = time::timeout(SOCKET_RECIEVE_TIMEOUT, read_ather.next()).await {
-            // log::debug!("Got frame len: {}", bits.len());
-            if let Ok(frame) = AcsmaFrame::try_from(bits) {
-                let header = frame.header().clone();
-                // log::debug!("Recieve raw frame with index {}", header.seq);
-                if is_for_self(&config, &header) {
-                    match frame {
-                        AcsmaFrame::NonAck(non_ack) => {
-                            let bits = create_resp(&config, &non_ack);
-                            // log::debug!("Sending ACK | MacPingResp for index {}", header.seq);
-                            if let Some(bits) = bits {
-                                write_ather.write(&bits).await?;
+        let deadline = [
+            next_deadline(&write_states, listen),
+            schedule
+                .as_ref()
+                .and_then(|schedule| schedule.next_beacon()),
+            config.hello.map(|_| hello_due),
+            read_windows
+                .values()
+                .filter_map(|read_window| read_window.burst)
+                .min(),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        // Incoming frames go first, as they may be ACKs that clear the medium for the others.
+        let event = tokio::select! {
+            biased;
+            _ = token.cancelled() => AcsmaSocketEvent::Shutdown,
+            Some(bits) = phy.read() => AcsmaSocketEvent::Frame(bits),
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() => AcsmaSocketEvent::Deadline,
+            event = future::poll_fn(|cx| {
+                poll_write_rxs(cx, &mut write_rxs, &write_pending, &mut write_closed)
+            }), if !probe.is_probing() => event,
+            _ = read_tx.closed(), if !read_tx.is_closed() => AcsmaSocketEvent::ReaderClosed,
+        };
+
+        match event {
+            AcsmaSocketEvent::Shutdown => {
+                log::debug!("Shutting down");
+                for write_rx in write_rxs.iter_mut() {
+                    write_rx.close();
+                    while let Ok(task) = write_rx.try_recv() {
+                        task.shutdown();
+                    }
+                }
+                for task in write_pending.into_iter().flatten() {
+                    task.shutdown();
+                }
+                for timer in write_states {
+                    timer.into_inner().task.shutdown();
+                }
+                return Ok(());
+            }
+            AcsmaSocketEvent::Frame(bits) => {
+                log::debug!("Got frame len: {}", bits.len());
+                capture.record(AcsmaCaptureDirection::Received, &bits, phy.snr());
+                let frame = AcsmaFrame::try_from(bits);
+                if let Err(err) = &frame {
+                    if let Some(FrameDecodeError::ParityCheckFailed(..)) = err.downcast_ref() {
+                        stats.lock().crc_failures += 1;
+                    }
+                }
+                if let Ok(frame) = frame {
+                    let header = frame.header().clone();
+                    log::debug!("Recieve raw frame with index {}", header.seq);
+                    if header.src != config.mac {
+                        neighbors.lock().hear(header.src, phy.snr());
+                    }
+                    if let Some(duration) = overheard_reservation(&config, &frame) {
+                        log::debug!("Defer to reservation of {}", header.src);
+                        nav = nav.max(Instant::now() + duration);
+                    }
+                    if is_for_self(&config, &header) {
+                        stats.lock().frames_received += 1;
+                        match frame {
+                            AcsmaFrame::NonAck(_)
+                                if probe.is_probing() && header.src == config.mac =>
+                            {
+                                log::debug!("Recieve our own probe {}", header.seq);
                             }
-                            // log::debug!("Sent ACK | MacPingResp for index {}", header.seq);
-                            if read_jar.contains(&header.seq) {
-                                // log::debug!("Recieve frame {} but already in jar", header.seq);
-                            } else {
-                                // log::debug!("Recieve frame {} and not in jar", header.seq);
-                                read_jar.push(header.seq);
+                            AcsmaFrame::NonAck(NonAckFrame::Data(data)) => {
+                                listen = Instant::now() + config.timing.receive_timeout;
+                                let data = open_frame(cipher.as_mut(), &stats, data);
+                                if let Some(data) = data {
+                                    let key = (header.src, header.dest == SOCKET_BROADCAST_ADDRESS);
+                                    let read_window =
+                                        read_windows.entry(key).or_insert_with(|| {
+                                            AcsmaSocketReadWindow::new(window, history)
+                                        });
+                                    let frames = read_window.receive(
+                                        &config,
+                                        NonAckFrame::Data(data),
+                                        &stats,
+                                    );
+                                    let bits = read_window.create_ack(&config, window, &header);
+                                    log::debug!("Sending ACK for index {}", header.seq);
+                                    if let Some(bits) = bits {
+                                        write_frame(&mut phy, &capture, &bits).await?;
+                                    }
+                                    log::debug!("Sent ACK for index {}", header.seq);
+                                    for frame in frames {
+                                        let len =
+                                            frame.payload().map_or(0, |payload| payload.len());
+                                        stats.lock().peer(header.src).bytes_received += len / 8;
+                                        let _ = read_tx.send(frame);
+                                    }
+                                }
+                            }
+                            AcsmaFrame::NonAck(non_ack) => {
+                                if let NonAckFrame::MacArpReq(req) = &non_ack {
+                                    if let Some(sender) = req.sender() {
+                                        arp.lock().insert(sender, header.src);
+                                    }
+                                }
+                                let bits = create_resp(&config, &non_ack);
+                                log::debug!("Sending MacPingResp for index {}", header.seq);
+                                if let Some(bits) = bits {
+                                    write_frame(&mut phy, &capture, &bits).await?;
+                                }
+                                log::debug!("Sent MacPingResp for index {}", header.seq);
+                                // Requests carry no sequence number and are idempotent.
                                 let _ = read_tx.send(non_ack);
                             }
-                        }
-                        frame => {
-                            // log::debug!("Recieve ACK | MacPingResp for index {}", header.seq);
-                            if let Some(timer) = write_state {
-                                write_state = Some(clear_timer(&mut rng, &frame, timer));
+                            AcsmaFrame::Rts(rts) => {
+                                // The medium is reserved by someone else, let the RTS time out.
+                                if Instant::now() >= nav {
+                                    let bits = BitVec::from(CtsFrame::new(
+                                        header.src,
+                                        config.mac,
+                                        header.seq,
+                                        rts.duration(),
+                                    ));
+                                    write_frame(&mut phy, &capture, &bits).await?;
+                                }
+                            }
+                            AcsmaFrame::Beacon(beacon) => {
+                                if let Some(schedule) = schedule.as_mut() {
+                                    schedule.receive(&beacon, false);
+                                }
+                            }
+                            AcsmaFrame::BlockAck(block) => {
+                                log::debug!("Recieve block ACK from index {}", header.seq);
+                                clear_block(
+                                    &mut write_states,
+                                    &mut write_peers,
+                                    &stats,
+                                    window,
+                                    &block,
+                                );
+                            }
+                            AcsmaFrame::Hello(hello) => {
+                                neighbors.lock().receive(&hello);
+                            }
+                            AcsmaFrame::Cts(_) => {
+                                log::debug!("Recieve CTS for index {}", header.seq);
+                                grant_timer(&mut write_states, &header);
+                            }
+                            frame => {
+                                log::debug!("Recieve ACK | MacPingResp for index {}", header.seq);
+                                if let AcsmaFrame::MacArpResp(resp) = &frame {
+                                    arp.lock().insert(resp.sender(), header.src);
+                                }
+                                clear_timer(
+                                    &mut write_states,
+                                    &mut write_peers,
+                                    &stats,
+                                    window,
+                                    &frame,
+                                );
                             }
                         }
                     }
                 }
             }
+            AcsmaSocketEvent::Task(index, task) => write_pending[index] = Some(task),
+            AcsmaSocketEvent::Deadline
+            | AcsmaSocketEvent::ReaderClosed
+            | AcsmaSocketEvent::WriterClosed => {}
+        }
+
+        for (&(src, _), read_window) in read_windows.iter_mut() {
+            if let Some(bits) = read_window.expire_burst(&config, window, src) {
+                write_frame(&mut phy, &capture, &bits).await?;
+            }
+        }
+
+        if let Some(schedule) = schedule.as_mut() {
+            if let Some(beacon) = schedule.beacon() {
+                log::debug!("Sending beacon");
+                write_frame(&mut phy, &capture, &BitVec::from(beacon.clone())).await?;
+                schedule.receive(&beacon, true);
+            }
+        }
+
+        if let Some(interval) = config.hello.filter(|_| Instant::now() >= hello_due) {
+            let ratios = neighbors.lock().ratios();
+            let hello =
+                HelloFrame::new(config.mac, hello_seq, config.ather_config.bit_rate, ratios);
+            let bits = BitVec::from(hello);
+            let wait = schedule
+                .as_ref()
+                .and_then(|schedule| schedule.wait(airtime(&config, &bits)));
+            if let Some(wait) = wait {
+                hello_due = Instant::now() + wait;
+            } else if Instant::now() < nav || !phy.is_free().await {
+                hello_due = Instant::now() + config.timing.slot;
+            } else {
+                log::debug!("Sending hello {}", hello_seq);
+                // Hellos are not retransmitted, a collision counts as a lost hello.
+                write_bits(&mut phy, &stats, &capture, &bits).await?;
+                hello_seq = (hello_seq + 1) % (1 << SEQ_BITS_LEN);
+                // Jitter keeps the hellos of neighbors from colliding over and over.
+                hello_due = Instant::now() + interval.mul_f32(rng.gen_range(0.75..1.25));
+            }
         }
 
-        if let Some(timer) = write_state {
-            if timer.is_expired() {
-                write_state = match timer {
-                    AcsmaSocketWriteTimer::Timeout { start: _, inner } => {
-                        // log::debug!("ACK timer expired for frame {}", inner.task.0.header().seq);
-                        Some(create_backoff(&mut rng, inner, 0))
+        write_states = write_states
+            .into_iter()
+            .map(|timer| {
+                if timer.is_backoff() || !timer.is_expired() {
+                    return timer;
+                }
+                let sent = timer.start();
+                let mut inner = timer.into_inner();
+                log::debug!(
+                    "ACK timer expired for frame {}",
+                    inner.task.frame.header().seq
+                );
+                inner.timed_out = true;
+                inner.retransmit = true;
+                if let Some(peer) = write_peers.get_mut(&inner.task.frame.header().dest) {
+                    peer.rtt.back_off(sent, Instant::now());
+                }
+                create_backoff(&mut rng, &config, &stats, inner, 0)
+            })
+            .collect();
+
+        if let Some(index) = write_states
+            .iter()
+            .enumerate()
+            .filter(|(_, timer)| timer.is_backoff() && timer.is_expired())
+            .min_by_key(|(_, timer)| timer.inner().task.class)
+            .map(|(index, _)| index)
+            .filter(|_| Instant::now() >= listen && !is_medium_reserved(&write_states))
+        {
+            if let AcsmaSocketWriteTimer::Backoff { inner, retry, .. } = write_states.remove(index)
+            {
+                let seq = inner.task.frame.header().seq;
+                log::debug!("Backoff timer expired. {}", seq);
+                let wait = schedule.as_ref().and_then(|schedule| {
+                    let bits = Into::<BitVec>::into(inner.task.frame.clone());
+                    schedule.wait(airtime(&config, &bits) + config.timing.ack_timeout)
+                });
+                if let Some(wait) = wait {
+                    log::debug!("Waiting for our slot. {}", seq);
+                    write_states.push(AcsmaSocketWriteTimer::backoff(inner, retry, wait));
+                } else if Instant::now() < nav || !phy.is_free().await {
+                    log::debug!("Medium state: busy. {}", seq);
+                    write_states.push(create_backoff(&mut rng, &config, &stats, inner, retry + 1));
+                } else if inner.resends > config.timing.max_resends {
+                    log::debug!("Medium state: free. resends exceeded {}", seq);
+                    release_peer(&mut write_peers, &inner.task.frame, false);
+                    stats.lock().link_errors += 1;
+                    inner.link_error();
+                } else {
+                    log::debug!("Medium state: free. Sending {}", seq);
+                    let is_rts = !inner.reserved && is_rts_required(&config, &inner.task.frame);
+                    // Bursts would overrun TDMA slots, which only fit a single exchange.
+                    let mut burst = vec![];
+                    if config.block_ack && !is_rts && !inner.reserved && schedule.is_none() {
+                        burst = take_burst(&config, &mut write_states, &inner.task.frame);
                     }
-                    AcsmaSocketWriteTimer::Backoff {
-                        inner: Some(inner),
-                        retry,
-                        ..
-                    } => {
-                        // let header = inner.task.0.header();
-                        // log::debug!("Backoff timer expired. {}", header.seq);
-                        if !is_channel_free(&config, &mut write_monitor).await {
-                            // log::debug!("Medium state: busy. {}", header.seq);
-                            Some(create_backoff(&mut rng, inner, retry + 1))
-                        } else if inner.resends > SOCKET_MAX_RESENDS {
-                            // log::debug!("Medium state: free. resends exceeded {}", header.seq);
-                            inner.link_error();
-                            None
+                    let mut sent = vec![];
+                    let mut burst = iter::once((inner, retry)).chain(burst).peekable();
+                    while let Some((mut inner, retry)) = burst.next() {
+                        let bits = if is_rts {
+                            create_rts(&config, &inner.task.frame)
                         } else {
-                            // log::debug!("Medium state: free. Resending {}", header.seq);
-                            let bits = Into::<BitVec>::into(inner.task.0.clone());
-                            if !write_bits(&config, &write_ather, &mut write_monitor, &bits).await?
-                            {
-                                // log::debug!("Medium state: free. Colision detected {}", header.seq);
-                                Some(create_backoff(&mut rng, inner, retry + 1))
-                            } else {
-                                // log::debug!("Medium state: free. Resent {}", header.seq);
-                                Some(AcsmaSocketWriteTimer::timeout(
-                                    inner.task,
-                                    inner.resends + 1,
-                                ))
+                            if let NonAckFrame::Data(data) = &mut inner.task.frame {
+                                let flag = &mut data.header_mut().flag;
+                                flag.set(FrameFlag::BURST, burst.peek().is_some());
                             }
+                            Into::<BitVec>::into(inner.task.frame.clone())
+                        };
+                        if !write_bits(&mut phy, &stats, &capture, &bits).await? {
+                            log::debug!(
+                                "Medium state: free. Colision detected {}",
+                                inner.task.frame.header().seq
+                            );
+                            for (inner, retry) in iter::once((inner, retry)).chain(burst) {
+                                write_states.push(create_backoff(
+                                    &mut rng,
+                                    &config,
+                                    &stats,
+                                    inner,
+                                    retry + 1,
+                                ));
+                            }
+                            break;
+                        }
+                        log::debug!("Medium state: free. Sent {}", inner.task.frame.header().seq);
+                        stats.lock().frames_sent += 1;
+                        // Only the data frame resent after a timeout counts, not its RTS.
+                        if !is_rts && mem::take(&mut inner.retransmit) {
+                            stats.lock().retransmissions += 1;
                         }
+                        inner.rts = is_rts;
+                        inner.reserved = false;
+                        inner.resends += 1;
+                        sent.push(inner);
+                    }
+                    // The ACK timers start once the whole burst is on the medium.
+                    for inner in sent {
+                        let timeout = ack_timeout(&config, &write_peers, &inner.task.frame);
+                        write_states.push(AcsmaSocketWriteTimer::timeout(inner, timeout));
                     }
-                    _ => None,
                 }
-            } else {
-                write_state = Some(timer);
             }
-        } else {
-            let result = write_rx.try_recv();
-            if let Ok(task) = result {
-                // let header = task.0.header();
-                // log::debug!("Accepted frame from source with index {}", header.seq);
-                write_state = if !is_channel_free(&config, &mut write_monitor).await {
-                    // log::debug!("Medium state: busy. set backoff timer");
-                    Some(create_backoff(
-                        &mut rng,
-                        AcsmaSocketWriteTimerInner { task, resends: 0 },
-                        0,
-                    ))
-                } else {
-                    // log::debug!("Medium state: free. Sending {}", header.seq);
-                    let bits = Into::<BitVec>::into(task.0.clone());
-                    if !write_bits(&config, &write_ather, &mut write_monitor, &bits).await? {
-                        // log::debug!("Medium state: free. Colision detected");
-                        Some(create_backoff(
-                            &mut rng,
-                            AcsmaSocketWriteTimerInner { task, resends: 0 },
-                            1,
-                        ))
-                    } else {
-                        // log::debug!("Medium state: free. Sent {}", header.seq);
-                        Some(AcsmaSocketWriteTimer::timeout(task, 0))
-                    }
+        }
+
+        if let Some(conflicts) = probe.poll() {
+            if !conflicts.is_empty() {
+                stats.lock().conflicts += conflicts.len();
+                let conflicts = conflicts.join(", ");
+                log::error!("Address conflict on {}", conflicts);
+                if config.exclusive {
+                    return Err(AcsmaIoError::AddressConflict(conflicts).into());
                 }
-            } else if let Err(TryRecvError::Disconnected) = result {
-                if read_tx.is_closed() {
+            } else if let Some(ip) = config.ip {
+                // Gratuitous ARP, so that peers update their caches
+                let bits = BitVec::from(MacArpRespFrame::new(
+                    SOCKET_BROADCAST_ADDRESS,
+                    config.mac,
+                    ip,
+                ));
+                write_frame(&mut phy, &capture, &bits).await?;
+            }
+            if let Some(ready) = ready.take() {
+                let _ = ready.send(());
+            }
+        }
+
+        // Strict priority: a class is only admitted once every higher class is drained or held
+        // back by its send window. The queues are held until our addresses turn out to be ours.
+        let mut is_closed = !probe.is_probing();
+        let admissions = if probe.is_probing() {
+            0
+        } else {
+            write_rxs.len()
+        };
+        for (index, write_rx) in write_rxs.iter_mut().enumerate().take(admissions) {
+            loop {
+                let task = match write_pending[index].take() {
+                    Some(task) => task,
+                    None => match write_rx.try_recv() {
+                        Ok(task) => task,
+                        Err(TryRecvError::Empty) => {
+                            is_closed = false;
+                            break;
+                        }
+                        Err(TryRecvError::Disconnected) => break,
+                    },
+                };
+                if !is_admissible(&write_peers, &task) {
+                    write_pending[index] = Some(task);
+                    is_closed = false;
                     break;
                 }
+                if is_congested(&config, &mut codels[index], &task) {
+                    log::debug!("Dropped frame queued for {:?}", task.queued.elapsed());
+                    stats.lock().codel_drops += 1;
+                    task.congested();
+                    continue;
+                }
+                log::debug!(
+                    "Accepted frame from source with index {}",
+                    task.frame.header().seq
+                );
+                write_states.push(admit_task(
+                    &config,
+                    &mut rng,
+                    &mut write_peers,
+                    cipher.as_ref(),
+                    task,
+                ));
             }
         }
+        if is_closed && write_states.is_empty() && read_tx.is_closed() {
+            return Ok(());
+        }
+    }
+}
+
+fn create_rng(config: &AcsmaSocketConfig) -> SmallRng {
+    match config.seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
     }
-    Ok(())
 }
 
 fn is_for_self(config: &AcsmaSocketConfig, header: &FrameHeader) -> bool {
@@ -434,30 +1177,55 @@ fn is_for_self(config: &AcsmaSocketConfig, header: &FrameHeader) -> bool {
 
 fn create_backoff(
     rng: &mut SmallRng,
+    config: &AcsmaSocketConfig,
+    stats: &AcsmaSocketStatsHandle,
     inner: AcsmaSocketWriteTimerInner,
     retry: usize,
 ) -> AcsmaSocketWriteTimer {
-    let duration = generate_backoff(rng, retry);
-    AcsmaSocketWriteTimer::backoff(Some(inner), retry, duration)
+    let backoff = &config.backoff[inner.task.class.index()];
+    let slots = generate_backoff(rng, &config.timing, backoff, retry);
+    stats.lock().backoff_slots.record(slots as u64);
+    let duration = slots * backoff_slot(config, &inner.task.frame);
+    AcsmaSocketWriteTimer::backoff(inner, retry, duration)
+}
+
+/// The ACK timeout of a frame, from the round trip time to its destination once measured.
+fn ack_timeout(
+    config: &AcsmaSocketConfig,
+    write_peers: &HashMap<usize, AcsmaSocketWritePeer>,
+    frame: &NonAckFrame,
+) -> Duration {
+    match frame {
+        NonAckFrame::Data(data) => write_peers
+            .get(&data.header().dest)
+            .map(|peer| peer.rtt.timeout()),
+        _ => None,
+    }
+    .unwrap_or(config.timing.ack_timeout)
+}
+
+/// A backoff slot covers the airtime of the frame and its ACK, so that slots grow with long
+/// frames and slow bit rates. It does not follow the round trip time of the peer, which backs
+/// off on its own while contention goes on.
+fn backoff_slot(config: &AcsmaSocketConfig, frame: &NonAckFrame) -> Duration {
+    let bits = BitVec::from(frame.clone());
+    let exchange = airtime(config, &bits) + config.timing.ack_timeout;
+    config.timing.slot.max(exchange)
 }
 
 fn create_resp(config: &AcsmaSocketConfig, non_ack: &NonAckFrame) -> Option<BitVec> {
     let header = non_ack.header();
     match non_ack {
-        NonAckFrame::Data(_) => {
-            // log::debug!("Receive data for index {}", header.seq);
-            Some(Into::<BitVec>::into(AckFrame::new(
-                header.src, config.mac, header.seq,
-            )))
-        }
+        // Data frames are acknowledged by their read window.
+        NonAckFrame::Data(_) => None,
         NonAckFrame::MacPingReq(_) => {
-            // log::debug!("Receive MacPingReq for index {}", header.seq);
+            log::debug!("Receive MacPingReq for index {}", header.seq);
             Some(Into::<BitVec>::into(MacPingRespFrame::new(
                 header.src, config.mac,
             )))
         }
         NonAckFrame::MacArpReq(_) => {
-            // log::debug!("Receive MacArpReq for index {}", header.seq);
+            log::debug!("Receive MacArpReq for index {}", header.seq);
             config
                 .ip
                 .map(|ip| Into::<BitVec>::into(MacArpRespFrame::new(header.src, config.mac, ip)))
@@ -465,123 +1233,447 @@ fn create_resp(config: &AcsmaSocketConfig, non_ack: &NonAckFrame) -> Option<BitV
     }
 }
 
-async fn is_channel_free(
+fn clear_timer(
+    write_states: &mut Vec<AcsmaSocketWriteTimer>,
+    write_peers: &mut HashMap<usize, AcsmaSocketWritePeer>,
+    stats: &AcsmaSocketStatsHandle,
+    window: usize,
+    frame: &AcsmaFrame,
+) {
+    let header = frame.header();
+    let index = write_states.iter().position(|timer| {
+        let task = &timer.inner().task.frame;
+        let mut conditions = vec![];
+        conditions.push(task.corresponds(header));
+        conditions.push(task.header().seq == header.seq);
+        conditions.push(
+            task.header().dest == SOCKET_BROADCAST_ADDRESS || task.header().dest == header.src,
+        );
+        if let AcsmaFrame::MacArpResp(resp) = frame {
+            if let NonAckFrame::MacArpReq(req) = task {
+                conditions.push(req.target() == resp.sender());
+            }
+        }
+        conditions.into_iter().all(|x| x)
+    });
+
+    if let Some(index) = index {
+        let timer = write_states.remove(index);
+        let window = match frame {
+            AcsmaFrame::Ack(ack) => Some(ack.window().clamp(1, window)),
+            _ => None,
+        };
+        acknowledge(write_peers, stats, timer, header, window);
+    }
+}
+
+/// Clears every data frame to the sender of the block ACK that it acknowledges.
+fn clear_block(
+    write_states: &mut Vec<AcsmaSocketWriteTimer>,
+    write_peers: &mut HashMap<usize, AcsmaSocketWritePeer>,
+    stats: &AcsmaSocketStatsHandle,
+    window: usize,
+    block: &BlockAckFrame,
+) {
+    let header = block.header();
+    let (acked, pending) = mem::take(write_states).into_iter().partition(|timer| {
+        let task = &timer.inner().task.frame;
+        matches!(task, NonAckFrame::Data(_))
+            && task.header().dest == header.src
+            && block.contains(task.header().seq)
+    });
+    *write_states = pending;
+    for timer in acked {
+        let window = Some(block.window().clamp(1, window));
+        acknowledge(write_peers, stats, timer, header, window);
+    }
+}
+
+/// Completes an acknowledged frame. Data frames also update the send window of their peer with
+/// the advertised receive window.
+fn acknowledge(
+    write_peers: &mut HashMap<usize, AcsmaSocketWritePeer>,
+    stats: &AcsmaSocketStatsHandle,
+    timer: AcsmaSocketWriteTimer,
+    header: &FrameHeader,
+    window: Option<usize>,
+) {
+    let rtt = match &timer {
+        AcsmaSocketWriteTimer::Timeout { start, .. } => Some(start.elapsed()),
+        _ => None,
+    };
+    let mut stats = stats.lock();
+    stats.frames_acked += 1;
+    if let Some(rtt) = rtt {
+        stats.record_rtt(rtt);
+    }
+    let inner = timer.into_inner();
+    if let Some(payload) = inner.task.frame.payload() {
+        stats.peer(header.src).bytes_sent += payload.len() / 8;
+    }
+    drop(stats);
+    if let Some(window) = window {
+        release_peer(write_peers, &inner.task.frame, true);
+        if let Some(peer) = write_peers.get_mut(&inner.task.frame.header().dest) {
+            peer.window = window;
+            if let Some(rtt) = rtt.filter(|_| !inner.timed_out) {
+                peer.rtt.sample(rtt);
+            }
+        }
+    }
+    inner.ok(header);
+}
+
+/// With block ACKs, the other data frames to the same peer whose backoff has expired follow the
+/// first one back to back, keeping their own retry counts.
+fn take_burst(
+    config: &AcsmaSocketConfig,
+    write_states: &mut Vec<AcsmaSocketWriteTimer>,
+    first: &NonAckFrame,
+) -> Vec<(AcsmaSocketWriteTimerInner, usize)> {
+    let dest = match first {
+        NonAckFrame::Data(data) if data.header().dest != SOCKET_BROADCAST_ADDRESS => {
+            data.header().dest
+        }
+        _ => return vec![],
+    };
+    let mut burst = vec![];
+    while burst.len() + 1 < SOCKET_MAX_BURST_LEN {
+        let index = write_states.iter().position(|timer| {
+            let inner = timer.inner();
+            timer.is_backoff()
+                && timer.is_expired()
+                && matches!(&inner.task.frame, NonAckFrame::Data(data) if data.header().dest == dest)
+                && inner.resends <= config.timing.max_resends
+                && !is_rts_required(config, &inner.task.frame)
+        });
+        match index.map(|index| write_states.remove(index)) {
+            Some(AcsmaSocketWriteTimer::Backoff { inner, retry, .. }) => burst.push((inner, retry)),
+            _ => break,
+        }
+    }
+    burst
+}
+
+/// Unicast data frames with a payload above the threshold reserve the medium with an RTS first,
+/// so that stations which cannot hear us but can hear the receiver defer to the exchange.
+fn is_rts_required(config: &AcsmaSocketConfig, frame: &NonAckFrame) -> bool {
+    match (frame, config.rts_threshold) {
+        (NonAckFrame::Data(data), Some(threshold)) => {
+            data.header().dest != SOCKET_BROADCAST_ADDRESS
+                && data.payload().map_or(0, |payload| payload.len()) > threshold
+        }
+        _ => false,
+    }
+}
+
+/// The RTS reserves the medium for the CTS, the data frame and its ACK.
+fn create_rts(config: &AcsmaSocketConfig, frame: &NonAckFrame) -> BitVec {
+    let header = frame.header();
+    let bits = Into::<BitVec>::into(frame.clone());
+    let duration = airtime(config, &bits) + config.timing.ack_timeout * 2;
+    BitVec::from(RtsFrame::new(header.dest, config.mac, header.seq, duration))
+}
+
+fn airtime(config: &AcsmaSocketConfig, bits: &BitSlice) -> Duration {
+    Duration::from_secs_f32(bits.len() as f32 / config.ather_config.bit_rate as f32)
+}
+
+/// RTS and CTS frames addressed to other stations set our network allocation vector.
+fn overheard_reservation(config: &AcsmaSocketConfig, frame: &AcsmaFrame) -> Option<Duration> {
+    match frame {
+        AcsmaFrame::Rts(rts) if rts.header().dest != config.mac => Some(rts.duration()),
+        AcsmaFrame::Cts(cts) if cts.header().dest != config.mac => Some(cts.duration()),
+        _ => None,
+    }
+}
+
+/// On a CTS, the data frame waiting for it is sent as soon as possible.
+fn grant_timer(write_states: &mut Vec<AcsmaSocketWriteTimer>, header: &FrameHeader) {
+    let index = write_states.iter().position(|timer| {
+        let inner = timer.inner();
+        let task = inner.task.frame.header();
+        !timer.is_backoff() && inner.rts && task.dest == header.src && task.seq == header.seq
+    });
+
+    if let Some(index) = index {
+        let mut inner = write_states.remove(index).into_inner();
+        inner.rts = false;
+        inner.reserved = true;
+        write_states.push(AcsmaSocketWriteTimer::backoff(inner, 0, Duration::ZERO));
+    }
+}
+
+/// A data frame can be admitted once the send window of its destination has room for it. The
+/// window of a peer stays at one frame until the peer has acknowledged our first frame (flagged
+/// with `SYN`) and advertised its receive window. Other frames are never held back.
+fn is_admissible(
+    write_peers: &HashMap<usize, AcsmaSocketWritePeer>,
+    task: &AcsmaSocketWriteTask,
+) -> bool {
+    match &task.frame {
+        NonAckFrame::Data(data) => write_peers
+            .get(&data.header().dest)
+            .filter(|peer| peer.outstanding >= peer.window)
+            .is_none(),
+        _ => true,
+    }
+}
+
+/// Control frames are never dropped, they are few and everything else depends on them.
+fn is_congested(
     config: &AcsmaSocketConfig,
-    write_monitor: &mut AcsmaSocketWriteMonitor,
+    codel: &mut AcsmaCodel,
+    task: &AcsmaSocketWriteTask,
 ) -> bool {
-    let sample_rate = config.ather_config.stream_config.sample_rate().0;
-    if let Some(sample) = write_monitor.sample().await {
-        // log::debug!("Energy: {}", sample.energy(sample_rate));
-        sample.energy(sample_rate) < SOCKET_FREE_THRESHOLD
-    } else {
-        // log::debug!("No sample");
-        true
+    config.discipline == AcsmaQueueDiscipline::Codel
+        && task.class != AcsmaTrafficClass::Control
+        && codel.dequeue(task.queued, Instant::now())
+}
+
+/// Open a sealed data frame, dropping it if it fails authentication.
+fn open_frame(
+    cipher: Option<&mut AcsmaCipher>,
+    stats: &AcsmaSocketStatsHandle,
+    mut data: DataFrame,
+) -> Option<DataFrame> {
+    let Some(cipher) = cipher else {
+        return Some(data);
+    };
+    match cipher.open(&mut data) {
+        Ok(()) => Some(data),
+        Err(err) => {
+            log::debug!(
+                "Drop frame {} from {}: {}",
+                data.header().seq,
+                data.header().src,
+                err
+            );
+            let mut stats = stats.lock();
+            match err.downcast_ref() {
+                Some(AcsmaIoError::Replayed(_)) => stats.replays += 1,
+                _ => stats.auth_failures += 1,
+            }
+            None
+        }
     }
 }
 
-fn clear_timer(
+fn admit_task(
+    config: &AcsmaSocketConfig,
     rng: &mut SmallRng,
-    frame: &AcsmaFrame,
-    mut timer: AcsmaSocketWriteTimer,
+    write_peers: &mut HashMap<usize, AcsmaSocketWritePeer>,
+    cipher: Option<&AcsmaCipher>,
+    mut task: AcsmaSocketWriteTask,
 ) -> AcsmaSocketWriteTimer {
-    let inner = match &timer {
-        AcsmaSocketWriteTimer::Timeout { inner, .. } => Some(inner),
-        AcsmaSocketWriteTimer::Backoff {
-            inner: Some(inner), ..
-        } => Some(inner),
-        _ => None,
-    };
-    let header = frame.header();
-    if let Some(inner) = inner {
-        let mut conditions = vec![];
-        conditions.push(inner.task.0.corresponds(header));
-        conditions.push(inner.task.0.header().seq == header.seq);
-        if let AcsmaFrame::MacArpResp(resp) = frame {
-            if let NonAckFrame::MacArpReq(req) = &inner.task.0 {
-                conditions.push(req.target() == resp.sender());
+    if let NonAckFrame::Data(data) = &mut task.frame {
+        let peer = write_peers
+            .entry(data.header().dest)
+            .or_insert_with(|| AcsmaSocketWritePeer::new(rng, config.timing.ack_timeout));
+        let header = data.header_mut();
+        header.seq = peer.next_seq;
+        if !peer.synced {
+            header.flag |= FrameFlag::SYN;
+        }
+        if let Some(cipher) = cipher {
+            cipher.seal(data, peer.epoch);
+        }
+        peer.next_seq = (peer.next_seq + 1) % (1 << SEQ_BITS_LEN);
+        if peer.next_seq == 0 {
+            peer.epoch = (peer.epoch + 1) % (1 << CRYPTO_EPOCH_BITS_LEN);
+        }
+        peer.outstanding += 1;
+    }
+    let slot = backoff_slot(config, &task.frame);
+    let aifs = config.backoff[task.class.index()].aifs as u32 * slot;
+    AcsmaSocketWriteTimer::backoff(AcsmaSocketWriteTimerInner::new(task), 0, aifs)
+}
+
+fn release_peer(
+    write_peers: &mut HashMap<usize, AcsmaSocketWritePeer>,
+    frame: &NonAckFrame,
+    acked: bool,
+) {
+    if let NonAckFrame::Data(data) = frame {
+        if let Some(peer) = write_peers.get_mut(&data.header().dest) {
+            peer.outstanding = peer.outstanding.saturating_sub(1);
+            if acked {
+                peer.synced |= data.header().flag.contains(FrameFlag::SYN);
+            } else {
+                // The receiver is now waiting for a frame that will never come. Start over with
+                // a new SYN, so that it drops whatever it has buffered.
+                peer.synced = false;
+                peer.window = 1;
             }
         }
+    }
+}
+
+/// The earliest instant at which a timer needs attention. Backoff timers do not count while the
+/// medium is reserved, an ACK or the expiry of the reserving timer comes first.
+/// Backoff timers are not served while we keep listening, so they count from its end.
+fn next_deadline(write_states: &[AcsmaSocketWriteTimer], listen: Instant) -> Option<Instant> {
+    let is_reserved = is_medium_reserved(write_states);
+    write_states
+        .iter()
+        .filter(|timer| !(is_reserved && timer.is_backoff()))
+        .map(|timer| {
+            if timer.is_backoff() {
+                timer.deadline().max(listen)
+            } else {
+                timer.deadline()
+            }
+        })
+        .min()
+}
 
-        if conditions.into_iter().all(|x| x) {
-            let duration = generate_backoff(rng, 0);
-            match mem::replace(
-                &mut timer,
-                AcsmaSocketWriteTimer::backoff(None, 0, duration),
-            ) {
-                AcsmaSocketWriteTimer::Timeout { inner, .. } => inner.ok(header),
-                AcsmaSocketWriteTimer::Backoff { inner, .. } => inner.unwrap().ok(header),
+/// Wait for a task of any class that has no task pending admission yet. Classes whose senders are
+/// all gone are remembered, as their receivers would otherwise be ready forever.
+fn poll_write_rxs(
+    cx: &mut Context<'_>,
+    write_rxs: &mut [mpsc::Receiver<AcsmaSocketWriteTask>],
+    write_pending: &[Option<AcsmaSocketWriteTask>],
+    write_closed: &mut [bool],
+) -> Poll<AcsmaSocketEvent> {
+    for (index, write_rx) in write_rxs.iter_mut().enumerate() {
+        if write_pending[index].is_some() || write_closed[index] {
+            continue;
+        }
+        match write_rx.poll_recv(cx) {
+            Poll::Ready(Some(task)) => return Poll::Ready(AcsmaSocketEvent::Task(index, task)),
+            Poll::Ready(None) => {
+                // Nothing wakes us up for a closed receiver again, so the daemon has to get the
+                // chance to notice that it may be done.
+                write_closed[index] = true;
+                return Poll::Ready(AcsmaSocketEvent::WriterClosed);
             }
-            return timer;
+            Poll::Pending => {}
         }
     }
+    Poll::Pending
+}
 
-    timer
+/// After a unicast data frame is sent, the medium is left to the receiver for its ACK until the
+/// ACK timer expires. Sending the next frame of the window right away would collide with it.
+fn is_medium_reserved(write_states: &[AcsmaSocketWriteTimer]) -> bool {
+    write_states.iter().any(|timer| match timer {
+        AcsmaSocketWriteTimer::Timeout { inner, .. } => {
+            inner.task.frame.header().dest != SOCKET_BROADCAST_ADDRESS && !timer.is_expired()
+        }
+        _ => false,
+    })
 }
 
-async fn write_bits(
-    _config: &AcsmaSocketConfig,
-    write_ather: &AtherOutputStream,
-    _colision_monitor: &mut AcsmaSocketWriteMonitor,
+/// Write the bits to the medium, returning whether they went through without a collision.
+async fn write_bits<P: AcsmaPhy>(
+    phy: &mut P,
+    stats: &AcsmaSocketStatsHandle,
+    capture: &AcsmaCaptureTap,
     bits: &BitSlice,
 ) -> Result<bool> {
-    write_ather.write(bits).await?;
-    Ok(true)
+    let result = phy.write(bits).await?;
+    if result {
+        capture.record(AcsmaCaptureDirection::Sent, bits, None);
+    } else {
+        capture.record_aborted(bits);
+        stats.lock().collisions += 1;
+    }
+    Ok(result)
+}
+
+async fn write_frame<P: AcsmaPhy>(
+    phy: &mut P,
+    capture: &AcsmaCaptureTap,
+    bits: &BitSlice,
+) -> Result<()> {
+    phy.write_unchecked(bits).await?;
+    capture.record(AcsmaCaptureDirection::Sent, bits, None);
+    Ok(())
+}
+
+/// What woke the daemon up.
+enum AcsmaSocketEvent {
+    Frame(BitVec),
+    Task(usize, AcsmaSocketWriteTask),
+    Deadline,
+    ReaderClosed,
+    WriterClosed,
+    Shutdown,
 }
+
 enum AcsmaSocketWriteTimer {
     Timeout {
         start: Instant,
         inner: AcsmaSocketWriteTimerInner,
+        duration: Duration,
     },
     Backoff {
         start: Instant,
-        inner: Option<AcsmaSocketWriteTimerInner>,
+        inner: AcsmaSocketWriteTimerInner,
         retry: usize,
         duration: Duration,
     },
 }
 
+/// `rts` tells whether the last frame sent for the task was an RTS, `reserved` whether the
+/// receiver has answered it with a CTS and the data frame may go out right away. `timed_out`
+/// tells whether an ACK timer of the task has expired, its ACK is then no RTT sample.
+/// `retransmit` tells whether the data frame is due again since its last ACK timeout.
 struct AcsmaSocketWriteTimerInner {
     task: AcsmaSocketWriteTask,
     resends: usize,
+    rts: bool,
+    reserved: bool,
+    timed_out: bool,
+    retransmit: bool,
 }
 
 impl AcsmaSocketWriteTimerInner {
+    fn new(task: AcsmaSocketWriteTask) -> Self {
+        Self {
+            task,
+            resends: 0,
+            rts: false,
+            reserved: false,
+            timed_out: false,
+            retransmit: false,
+        }
+    }
+
     fn ok(self, header: &FrameHeader) {
-        self.task.1.send(Ok(header.clone())).ok();
+        self.task.tx.send(Ok(header.clone())).ok();
     }
 
     fn link_error(self) {
         let _ = self
             .task
-            .1
+            .tx
             .send(Err(AcsmaIoError::LinkError(self.resends).into()));
     }
 }
 
-fn generate_backoff(rng: &mut SmallRng, factor: usize) -> Duration {
-    let range = if 1 << factor > SOCKET_MAX_RANGE {
-        SOCKET_MAX_RANGE
-    } else {
-        1 << factor
-    };
+fn generate_backoff(
+    rng: &mut SmallRng,
+    timing: &AcsmaTimingConfig,
+    backoff: &AcsmaBackoffConfig,
+    factor: usize,
+) -> u32 {
+    let range = backoff.range(factor).min(timing.max_range);
     let k = rng.gen_range(0..=range as u32);
-    // log::debug!("Set timer to {} slots by {}", k, range);
-    k * SOCKET_SLOT_TIMEOUT
+    log::debug!("Set timer to {} slots by {}", k, range);
+    backoff.aifs as u32 + k
 }
 
 impl AcsmaSocketWriteTimer {
-    fn timeout(task: AcsmaSocketWriteTask, resends: usize) -> Self {
+    fn timeout(inner: AcsmaSocketWriteTimerInner, duration: Duration) -> Self {
         Self::Timeout {
             start: Instant::now(),
-            inner: AcsmaSocketWriteTimerInner { task, resends },
+            duration,
+            inner,
         }
     }
 
-    fn backoff(
-        inner: Option<AcsmaSocketWriteTimerInner>,
-        retry: usize,
-        duration: Duration,
-    ) -> Self {
+    fn backoff(inner: AcsmaSocketWriteTimerInner, retry: usize, duration: Duration) -> Self {
         Self::Backoff {
             start: Instant::now(),
             inner,
@@ -592,65 +1684,260 @@ impl AcsmaSocketWriteTimer {
 }
 
 impl AcsmaSocketWriteTimer {
+    fn inner(&self) -> &AcsmaSocketWriteTimerInner {
+        match self {
+            Self::Timeout { inner, .. } => inner,
+            Self::Backoff { inner, .. } => inner,
+        }
+    }
+
+    fn into_inner(self) -> AcsmaSocketWriteTimerInner {
+        match self {
+            Self::Timeout { inner, .. } => inner,
+            Self::Backoff { inner, .. } => inner,
+        }
+    }
+
+    fn is_backoff(&self) -> bool {
+        matches!(self, Self::Backoff { .. })
+    }
+
     fn is_expired(&self) -> bool {
-        self.elapsed() > self.duration()
+        Instant::now() >= self.deadline()
+    }
+
+    fn deadline(&self) -> Instant {
+        self.start() + self.duration()
     }
 
-    fn elapsed(&self) -> Duration {
+    fn start(&self) -> Instant {
         match self {
-            Self::Timeout { start, .. } => start.elapsed(),
-            Self::Backoff { start, .. } => start.elapsed(),
+            Self::Timeout { start, .. } => *start,
+            Self::Backoff { start, .. } => *start,
         }
     }
 
     fn duration(&self) -> Duration {
         match self {
-            Self::Timeout { .. } => SOCKET_ACK_TIMEOUT,
+            Self::Timeout { duration, .. } => *duration,
             Self::Backoff { duration, .. } => *duration,
         }
     }
 }
 
-struct AcsmaSocketWriteMonitor {
-    req_tx: UnboundedSender<()>,
-    resp_rx: UnboundedReceiver<Option<Box<[f32]>>>,
+/// Send state of a destination. `window` is the negotiated send window, i.e. the smaller one of
+/// our own window and the receive window advertised in the ACKs of the peer.
+struct AcsmaSocketWritePeer {
+    next_seq: usize,
+    /// Nonces of sealed frames are derived from the sequence number and the epoch, which
+    /// advances whenever the sequence numbers wrap.
+    epoch: usize,
+    window: usize,
+    outstanding: usize,
+    synced: bool,
+    rtt: AcsmaRttEstimator,
 }
 
-impl AcsmaSocketWriteMonitor {
-    fn new(mut write_monitor: AudioInputStream<f32>) -> Self {
-        let (req_tx, mut req_rx) = mpsc::unbounded_channel();
-        let (resp_tx, resp_rx) = mpsc::unbounded_channel();
+impl AcsmaSocketWritePeer {
+    fn new(rng: &mut SmallRng, ack_timeout: Duration) -> Self {
+        Self {
+            next_seq: rng.gen_range(0..(1 << SEQ_BITS_LEN)),
+            epoch: initial_epoch(),
+            window: 1,
+            outstanding: 0,
+            synced: false,
+            rtt: AcsmaRttEstimator::new(ack_timeout),
+        }
+    }
+}
 
-        tokio::spawn(async move {
-            let mut sample = None;
-            loop {
-                tokio::select! {
-                    cmd = req_rx.recv() => {
-                        if cmd.is_some() && resp_tx.send(sample.clone()).is_ok() {
-                            continue;
-                        }
-                        break;
-                    },
-                    data = write_monitor.next() => {
-                        sample = data
-                    }
-                }
+/// Receive state of a source for selective repeat. Frames inside the window are buffered until
+/// the frames before them have arrived, so that they are delivered in order. The last `history`
+/// sequence numbers behind the window have already been delivered, frames carrying them are
+/// dropped as duplicates. Offsets are taken modulo the sequence space, so wrap-around is handled.
+struct AcsmaSocketReadWindow {
+    len: usize,
+    history: usize,
+    base: Option<usize>,
+    syn: Option<usize>,
+    buffer: BTreeMap<usize, NonAckFrame>,
+    /// When the burst in progress should have ended. It is answered with a block ACK at its
+    /// end, or once this has passed if its last frame got lost.
+    burst: Option<Instant>,
+}
+
+impl AcsmaSocketReadWindow {
+    fn new(len: usize, history: usize) -> Self {
+        Self {
+            len,
+            history,
+            base: None,
+            syn: None,
+            buffer: BTreeMap::new(),
+            burst: None,
+        }
+    }
+
+    fn receive(
+        &mut self,
+        config: &AcsmaSocketConfig,
+        frame: NonAckFrame,
+        stats: &AcsmaSocketStatsHandle,
+    ) -> Vec<NonAckFrame> {
+        let space = 1 << SEQ_BITS_LEN;
+        let header = frame.header();
+        let seq = header.seq;
+        if header.flag.contains(FrameFlag::BURST) {
+            // The frames of a burst follow each other right away.
+            let gap = frame_duration(config.ather_config.bit_rate) * 2;
+            self.burst = Some(Instant::now() + gap);
+        }
+
+        if header.flag.contains(FrameFlag::SYN) && self.syn != Some(seq) {
+            self.syn = Some(seq);
+            self.base = Some(seq);
+            self.buffer.clear();
+        }
+
+        let base = *self.base.get_or_insert(seq);
+        let offset = (seq + space - base) % space;
+        if offset >= self.len {
+            if offset >= space - self.history {
+                log::debug!("Recieve frame {} but already delivered", seq);
+                stats.lock().duplicates += 1;
+                return vec![];
             }
-        });
+            // The peer is far ahead of us, which only happens if we have missed its SYN.
+            stats.lock().resyncs += 1;
+            self.base = Some(seq);
+            self.buffer.clear();
+        }
+        if self.buffer.contains_key(&seq) {
+            stats.lock().duplicates += 1;
+            return vec![];
+        }
+        self.buffer.insert(seq, frame);
+
+        let mut base = self.base.unwrap();
+        let mut result = vec![];
+        while let Some(frame) = self.buffer.remove(&base) {
+            result.push(frame);
+            base = (base + 1) % space;
+        }
+        self.base = Some(base);
+        result
+    }
+
+    /// Frames within a burst are not acknowledged on their own. The last frame of a burst is
+    /// answered with a block ACK of everything received so far, any other frame with a plain ACK.
+    fn create_ack(
+        &mut self,
+        config: &AcsmaSocketConfig,
+        window: usize,
+        header: &FrameHeader,
+    ) -> Option<BitVec> {
+        if header.flag.contains(FrameFlag::BURST) {
+            return None;
+        }
+        if self.burst.take().is_none() {
+            let ack = AckFrame::new(header.src, config.mac, header.seq, window);
+            return Some(ack.into());
+        }
+        Some(self.create_block_ack(config, window, header.src, header.seq))
+    }
+
+    /// A block ACK for a burst whose last frame has not arrived in time.
+    fn expire_burst(
+        &mut self,
+        config: &AcsmaSocketConfig,
+        window: usize,
+        src: usize,
+    ) -> Option<BitVec> {
+        self.burst.take_if(|burst| *burst <= Instant::now())?;
+        let base = self.base?;
+        Some(self.create_block_ack(config, window, src, base))
+    }
 
-        Self { req_tx, resp_rx }
+    fn create_block_ack(
+        &self,
+        config: &AcsmaSocketConfig,
+        window: usize,
+        dest: usize,
+        seq: usize,
+    ) -> BitVec {
+        let space = 1 << SEQ_BITS_LEN;
+        let base = self.base.unwrap_or(seq);
+        let bitmap = self
+            .buffer
+            .keys()
+            .map(|seq| (seq + space - base) % space)
+            .filter(|offset| (1..=BLOCK_ACK_BITMAP_LEN).contains(offset))
+            .fold(0, |bitmap, offset| bitmap | 1 << (offset - 1));
+        BlockAckFrame::new(dest, config.mac, base, bitmap, window).into()
     }
+}
+
+/// Duplicate address detection at startup. We ping our own MAC address and resolve our own IP
+/// address with the usual request frames. Any answer means that another node uses the address.
+/// While probing, the echo of our own ping must not be answered by ourselves.
+struct AcsmaSocketProbe {
+    probes: Vec<(String, oneshot::Receiver<Result<FrameHeader>>)>,
+    conflicts: Vec<String>,
+}
+
+impl AcsmaSocketProbe {
+    fn new(config: &AcsmaSocketConfig) -> (Self, Vec<AcsmaSocketWriteTask>) {
+        let mut frames = vec![(
+            format!("MAC {}", config.mac),
+            NonAckFrame::MacPingReq(MacPingReqFrame::new(config.mac, config.mac)),
+        )];
+        if let Some(ip) = config.ip {
+            frames.push((
+                format!("IP {}", Ipv4Addr::from(ip as u32)),
+                NonAckFrame::MacArpReq(MacArpReqFrame::new(config.mac, ip, None)),
+            ));
+        }
 
-    async fn sample(&mut self) -> Option<Box<[f32]>> {
-        self.clear();
-        self.req_tx.send(()).unwrap();
-        match self.resp_rx.recv().await {
-            Some(inner) => inner,
-            None => None,
+        let mut probes = vec![];
+        let mut tasks = vec![];
+        for (name, frame) in frames {
+            let (tx, rx) = oneshot::channel();
+            probes.push((name, rx));
+            tasks.push(AcsmaSocketWriteTask::new(
+                frame,
+                tx,
+                AcsmaTrafficClass::Control,
+            ));
         }
+        let probe = Self {
+            probes,
+            conflicts: vec![],
+        };
+        (probe, tasks)
+    }
+
+    fn is_probing(&self) -> bool {
+        !self.probes.is_empty()
     }
 
-    fn clear(&mut self) {
-        while self.resp_rx.try_recv().is_ok() {}
+    /// Returns the conflicting addresses once, right after the last probe has completed.
+    fn poll(&mut self) -> Option<Vec<String>> {
+        if !self.is_probing() {
+            return None;
+        }
+        let mut probes = vec![];
+        for (name, mut rx) in self.probes.drain(..) {
+            match rx.try_recv() {
+                Ok(Ok(_)) => self.conflicts.push(name),
+                Ok(Err(_)) | Err(oneshot::error::TryRecvError::Closed) => {}
+                Err(oneshot::error::TryRecvError::Empty) => probes.push((name, rx)),
+            }
+        }
+        self.probes = probes;
+        if self.is_probing() {
+            None
+        } else {
+            Some(mem::take(&mut self.conflicts))
+        }
     }
 }
@@ -0,0 +1,193 @@
+use parking_lot::{Mutex, MutexGuard};
+use std::{collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+const HISTOGRAM_BUCKETS_LEN: usize = 16;
+
+/// A histogram over power-of-two buckets: bucket `i` counts the samples below `2^i`, and the
+/// last bucket everything above.
+#[derive(Debug, Clone, Default)]
+pub struct AcsmaHistogram {
+    buckets: [usize; HISTOGRAM_BUCKETS_LEN],
+    count: usize,
+    sum: u64,
+    max: u64,
+}
+
+impl AcsmaHistogram {
+    pub fn record(&mut self, value: u64) {
+        let index = (u64::BITS - value.leading_zeros()) as usize;
+        self.buckets[index.min(HISTOGRAM_BUCKETS_LEN - 1)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+
+    /// Upper bound of the bucket holding the given quantile, never above the largest sample.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((self.count as f64 * quantile).ceil() as usize).max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(((1u64 << index) - 1).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+}
+
+impl fmt::Display for AcsmaHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mean() {
+            Some(mean) => write!(
+                f,
+                "n={} mean={:.1} p50={} p90={} p99={} max={}",
+                self.count,
+                mean,
+                self.quantile(0.5).unwrap_or_default(),
+                self.quantile(0.9).unwrap_or_default(),
+                self.quantile(0.99).unwrap_or_default(),
+                self.max
+            ),
+            None => write!(f, "n=0"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AcsmaPeerStats {
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AcsmaSocketStats {
+    pub frames_sent: usize,
+    pub frames_received: usize,
+    pub frames_acked: usize,
+    pub retransmissions: usize,
+    pub collisions: usize,
+    /// Slots waited per backoff.
+    pub backoff_slots: AcsmaHistogram,
+    /// Time from sending a frame to its ACK or response, in milliseconds.
+    pub ack_rtt: AcsmaHistogram,
+    pub link_errors: usize,
+    pub duplicates: usize,
+    pub crc_failures: usize,
+    /// Data frames dropped for failing authentication, or for being replayed from an old epoch.
+    pub auth_failures: usize,
+    pub replays: usize,
+    pub resyncs: usize,
+    pub conflicts: usize,
+    pub tail_drops: usize,
+    pub codel_drops: usize,
+    pub peers: BTreeMap<usize, AcsmaPeerStats>,
+}
+
+impl AcsmaSocketStats {
+    pub(super) fn record_rtt(&mut self, rtt: Duration) {
+        self.ack_rtt.record(rtt.as_millis() as u64);
+    }
+
+    pub(super) fn peer(&mut self, peer: usize) -> &mut AcsmaPeerStats {
+        self.peers.entry(peer).or_default()
+    }
+}
+
+impl fmt::Display for AcsmaSocketStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "frames: sent={} received={} acked={} retransmitted={}",
+            self.frames_sent, self.frames_received, self.frames_acked, self.retransmissions
+        )?;
+        writeln!(
+            f,
+            "errors: collisions={} link={} crc={} auth={} replays={} duplicates={} resyncs={} \
+             conflicts={}",
+            self.collisions,
+            self.link_errors,
+            self.crc_failures,
+            self.auth_failures,
+            self.replays,
+            self.duplicates,
+            self.resyncs,
+            self.conflicts
+        )?;
+        writeln!(
+            f,
+            "queues: tail_drops={} codel_drops={}",
+            self.tail_drops, self.codel_drops
+        )?;
+        writeln!(f, "backoff slots: {}", self.backoff_slots)?;
+        write!(f, "ack rtt (ms): {}", self.ack_rtt)?;
+        for (peer, stats) in self.peers.iter() {
+            write!(
+                f,
+                "\npeer {}: sent={} B received={} B",
+                peer, stats.bytes_sent, stats.bytes_received
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Shared handle to the live statistics of a socket.
+#[derive(Debug, Clone, Default)]
+pub struct AcsmaSocketStatsHandle(Arc<Mutex<AcsmaSocketStats>>);
+
+impl AcsmaSocketStatsHandle {
+    pub fn snapshot(&self) -> AcsmaSocketStats {
+        self.0.lock().clone()
+    }
+
+    pub fn reset(&self) {
+        *self.0.lock() = AcsmaSocketStats::default();
+    }
+
+    pub(super) fn lock(&self) -> MutexGuard<'_, AcsmaSocketStats> {
+        self.0.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = AcsmaHistogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for value in [0, 1, 3, 5, 40, 100] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 6);
+        assert_eq!(histogram.sum(), 149);
+        assert_eq!(histogram.quantile(0.5), Some(3));
+        assert_eq!(histogram.quantile(0.8), Some(63));
+        assert_eq!(histogram.quantile(1.0), Some(100));
+
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.max(), u64::MAX);
+    }
+}
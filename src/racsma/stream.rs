@@ -1,15 +1,32 @@
 use super::{
-    builtin::{PAYLOAD_BITS_LEN, SOCKET_ACK_TIMEOUT, SOCKET_MAX_RESENDS},
-    frame::{AckFrame, DataFrame, Frame, FrameFlag},
+    builtin::{
+        PAYLOAD_BITS_LEN, SEQ_BITS_LEN, SOCKET_ACK_TIMEOUT, SOCKET_BROADCAST_ADDRESS,
+        SOCKET_MAX_RESENDS,
+    },
+    frame::{payload_bits_len, AckFrame, AcsmaFrame, DataFrame, Frame, FrameFlag, NonAckFrame},
+    link::{Link, LinkReader, LinkWriter},
+    qos::AcsmaTrafficClass,
     AcsmaIoError,
 };
 use crate::rather::{AtherInputStream, AtherOutputStream};
 use anyhow::Result;
 use bitvec::prelude::*;
 use log;
-use std::collections::BTreeMap;
-use tokio::time;
-use tokio_stream::StreamExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, UnboundedReceiver},
+        Mutex,
+    },
+    time,
+};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream, StreamExt};
+
+/// Every frame of a packet carries its index as sequence number.
+const STREAM_MTU: usize = (1 << SEQ_BITS_LEN) * PAYLOAD_BITS_LEN;
 
 #[derive(Clone)]
 pub struct AcsmaStreamConfig {
@@ -25,7 +42,7 @@ impl AcsmaStreamConfig {
 pub struct AcsmaIoStream {
     config: AcsmaStreamConfig,
     istream: AtherInputStream,
-    ostream: AtherOutputStream,
+    ostream: Arc<AtherOutputStream>,
 }
 
 impl AcsmaIoStream {
@@ -37,96 +54,234 @@ impl AcsmaIoStream {
         Self {
             config,
             istream,
-            ostream,
+            ostream: Arc::new(ostream),
         }
     }
 }
 
 impl AcsmaIoStream {
     pub async fn write(&mut self, dest: usize, bits: &BitSlice) -> Result<()> {
-        let frames = bits.chunks(PAYLOAD_BITS_LEN);
-        let len = frames.len();
-        let frames = frames.enumerate().map(|(index, chunk)| {
-            let flag = if index == len - 1 {
-                FrameFlag::EOP
-            } else {
-                FrameFlag::empty()
-            };
-            Into::<BitVec>::into(DataFrame::new(
-                dest,
-                self.config.address,
-                index,
-                flag,
-                chunk.to_owned(),
-            ))
-        });
+        let acks = (&mut self.istream).filter_map(|bits| AckFrame::try_from(bits).ok());
+        write_frames(&self.config, &self.ostream, acks, dest, bits).await
+    }
 
-        for (index, frame) in frames.enumerate() {
-            let mut retry = 0usize;
-            log::info!("Writing frame {}", index);
-            loop {
-                // log::debug!("Sending frame {} for the {} time", index, retry);
-                self.ostream.write(&frame).await?;
-                // log::debug!("Sent frame {} for the {} time", index, retry);
-                let ack_future = async {
-                    while let Some(bits) = self.istream.next().await {
-                        if let Ok(frame) = AckFrame::try_from(bits) {
-                            let header = frame.header();
-                            if header.src == dest
-                                && header.dest == self.config.address
-                                && header.seq == index
-                            {
-                                // log::debug!("Recieve ACK for index {}", header.seq);
-                                break;
-                            }
-                        }
+    pub async fn read(&mut self, src: usize) -> Result<BitVec> {
+        let mut buckets = HashMap::new();
+        while let Some(bits) = self.istream.next().await {
+            // log::debug!("Got frame {}", bits.len());
+            if let Ok(frame) = DataFrame::try_from(bits) {
+                let header = frame.header();
+                if header.src == src && header.dest == self.config.address {
+                    if let Some((_, packet)) =
+                        receive_frame(&self.ostream, &mut buckets, frame).await?
+                    {
+                        return Ok(packet);
                     }
+                }
+            }
+        }
+
+        Ok(buckets.remove(&src).map(concat_bucket).unwrap_or_default())
+    }
+}
+
+impl Link for AcsmaIoStream {
+    type Writer = AcsmaIoStreamWriter;
+    type Reader = AcsmaIoStreamReader;
+
+    fn split(self) -> (Self::Writer, Self::Reader) {
+        let (ack_tx, ack_rx) = mpsc::unbounded_channel();
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let address = self.config.address;
+        let mut istream = self.istream;
+        // The halves share the input stream, the writer waits for ACKs and the reader for data.
+        tokio::spawn(async move {
+            let closed = async {
+                ack_tx.closed().await;
+                data_tx.closed().await;
+            };
+            tokio::pin!(closed);
+            loop {
+                let bits = tokio::select! {
+                    bits = istream.next() => match bits {
+                        Some(bits) => bits,
+                        None => break,
+                    },
+                    _ = &mut closed => break,
                 };
-                if time::timeout(SOCKET_ACK_TIMEOUT, ack_future).await.is_ok() {
-                    break;
-                } else {
-                    // log::debug!("Timeout ACK for index");
-                    retry += 1;
-                    if retry >= SOCKET_MAX_RESENDS {
-                        return Err(AcsmaIoError::LinkError(retry).into());
+                match AcsmaFrame::try_from(bits) {
+                    Ok(AcsmaFrame::Ack(ack)) => {
+                        let _ = ack_tx.send(ack);
                     }
+                    Ok(AcsmaFrame::NonAck(NonAckFrame::Data(data))) => {
+                        let dest = data.header().dest;
+                        if dest == address || dest == SOCKET_BROADCAST_ADDRESS {
+                            let _ = data_tx.send(data);
+                        }
+                    }
+                    _ => {}
                 }
             }
-            log::info!("Wrote frame {}", index);
-        }
+        });
 
-        Ok(())
+        let writer = AcsmaIoStreamWriter {
+            config: self.config,
+            ostream: self.ostream.clone(),
+            acks: Mutex::new(UnboundedReceiverStream::new(ack_rx)),
+        };
+        let reader = AcsmaIoStreamReader {
+            ostream: self.ostream,
+            frames: data_rx,
+            buckets: HashMap::new(),
+        };
+        (writer, reader)
     }
+}
 
-    pub async fn read(&mut self, src: usize) -> Result<BitVec> {
-        let mut bucket = BTreeMap::new();
-        while let Some(bits) = self.istream.next().await {
-            // log::debug!("Got frame {}", bits.len());
-            if let Ok(frame) = DataFrame::try_from(bits) {
-                let header = frame.header();
-                if header.src == src && header.dest == self.config.address {
-                    // log::debug!("Recieve frame with index {}", header.seq);
-                    let ack = AckFrame::new(header.src, header.dest, header.seq);
-                    // log::debug!("Sending ACK for index {}", header.seq);
-                    self.ostream.write(&Into::<BitVec>::into(ack)).await?;
+pub struct AcsmaIoStreamWriter {
+    config: AcsmaStreamConfig,
+    ostream: Arc<AtherOutputStream>,
+    /// Held for a whole packet, as the stream only has one frame in flight.
+    acks: Mutex<UnboundedReceiverStream<AckFrame>>,
+}
 
-                    let payload = frame.payload().unwrap();
-                    bucket.entry(header.seq).or_insert(payload.to_owned());
+impl LinkWriter for AcsmaIoStreamWriter {
+    fn address(&self) -> usize {
+        self.config.address
+    }
+
+    fn mtu(&self) -> usize {
+        STREAM_MTU
+    }
 
-                    if header.flag.contains(FrameFlag::EOP) {
+    async fn write_packet(
+        &self,
+        dest: usize,
+        packet: &BitSlice,
+        _class: AcsmaTrafficClass,
+    ) -> Result<()> {
+        let mut acks = self.acks.lock().await;
+        write_frames(&self.config, &self.ostream, &mut *acks, dest, packet).await
+    }
+}
+
+pub struct AcsmaIoStreamReader {
+    ostream: Arc<AtherOutputStream>,
+    frames: UnboundedReceiver<DataFrame>,
+    buckets: HashMap<usize, BTreeMap<usize, BitVec>>,
+}
+
+impl LinkReader for AcsmaIoStreamReader {
+    async fn read_packet(&mut self) -> Result<(usize, BitVec)> {
+        while let Some(frame) = self.frames.recv().await {
+            if let Some(packet) = receive_frame(&self.ostream, &mut self.buckets, frame).await? {
+                return Ok(packet);
+            }
+        }
+
+        Err(AcsmaIoError::SocketClosed.into())
+    }
+}
+
+/// Stop and wait: every frame is sent until its ACK arrives, broadcasts are sent once.
+async fn write_frames<S: Stream<Item = AckFrame> + Unpin>(
+    config: &AcsmaStreamConfig,
+    ostream: &AtherOutputStream,
+    mut acks: S,
+    dest: usize,
+    bits: &BitSlice,
+) -> Result<()> {
+    if bits.len() > STREAM_MTU {
+        return Err(AcsmaIoError::PacketTooLarge(bits.len()).into());
+    }
+    let frames = bits.chunks(payload_bits_len(dest, config.address));
+    let len = frames.len();
+    let frames = frames.enumerate().map(|(index, chunk)| {
+        let flag = if index == len - 1 {
+            FrameFlag::EOP
+        } else {
+            FrameFlag::empty()
+        };
+        Into::<BitVec>::into(DataFrame::new(
+            dest,
+            config.address,
+            index,
+            flag,
+            chunk.to_owned(),
+        ))
+    });
+
+    for (index, frame) in frames.enumerate() {
+        let mut retry = 0usize;
+        log::info!("Writing frame {}", index);
+        loop {
+            // log::debug!("Sending frame {} for the {} time", index, retry);
+            ostream.write(&frame).await?;
+            // log::debug!("Sent frame {} for the {} time", index, retry);
+            if dest == SOCKET_BROADCAST_ADDRESS {
+                break;
+            }
+            let ack_future = async {
+                while let Some(frame) = acks.next().await {
+                    let header = frame.header();
+                    if header.src == dest && header.dest == config.address && header.seq == index {
+                        // log::debug!("Recieve ACK for index {}", header.seq);
                         break;
                     }
                 }
+            };
+            if time::timeout(SOCKET_ACK_TIMEOUT, ack_future).await.is_ok() {
+                break;
+            } else {
+                // log::debug!("Timeout ACK for index");
+                retry += 1;
+                if retry >= SOCKET_MAX_RESENDS {
+                    return Err(AcsmaIoError::LinkError(retry).into());
+                }
             }
         }
+        log::info!("Wrote frame {}", index);
+    }
 
-        let result = bucket.iter().fold(bitvec![], |mut acc, (_, payload)| {
-            acc.extend_from_bitslice(payload);
-            acc
-        });
+    Ok(())
+}
+
+/// Acknowledge a data frame and put its payload into the bucket of its source. Returns the
+/// packet of the source once the frame ends it.
+async fn receive_frame(
+    ostream: &AtherOutputStream,
+    buckets: &mut HashMap<usize, BTreeMap<usize, BitVec>>,
+    frame: DataFrame,
+) -> Result<Option<(usize, BitVec)>> {
+    let header = frame.header().clone();
+    // log::debug!("Recieve frame with index {}", header.seq);
+    if header.dest != SOCKET_BROADCAST_ADDRESS {
+        let ack = AckFrame::new(header.src, header.dest, header.seq, 1);
+        // log::debug!("Sending ACK for index {}", header.seq);
+        ostream.write(&Into::<BitVec>::into(ack)).await?;
+    }
 
-        log::info!("Read {} frames, total {}", bucket.len(), result.len());
+    let payload = frame.payload().unwrap();
+    buckets
+        .entry(header.src)
+        .or_default()
+        .entry(header.seq)
+        .or_insert(payload.to_owned());
 
-        Ok(result)
+    if !header.flag.contains(FrameFlag::EOP) {
+        return Ok(None);
     }
+    let bucket = buckets.remove(&header.src).unwrap_or_default();
+    let len = bucket.len();
+    let result = concat_bucket(bucket);
+    log::info!("Read {} frames, total {}", len, result.len());
+
+    Ok(Some((header.src, result)))
+}
+
+fn concat_bucket(bucket: BTreeMap<usize, BitVec>) -> BitVec {
+    bucket.values().fold(bitvec![], |mut acc, payload| {
+        acc.extend_from_bitslice(payload);
+        acc
+    })
 }
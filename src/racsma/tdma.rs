@@ -0,0 +1,136 @@
+use super::{
+    builtin::{
+        BEACON_COUNT_BITS_LEN, EXT_ADDRESS_BITS_LEN, NAV_BITS_LEN, PAYLOAD_BITS_LEN,
+        SOCKET_BEACON_TIMEOUT,
+    },
+    frame::BeaconFrame,
+    AcsmaIoError,
+};
+use anyhow::Result;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const TDMA_MAX_STATIONS_LEN: usize =
+    (PAYLOAD_BITS_LEN - NAV_BITS_LEN - BEACON_COUNT_BITS_LEN) / EXT_ADDRESS_BITS_LEN;
+
+#[derive(Debug, Clone)]
+pub struct AcsmaTdmaConfig {
+    /// Whether this node sends the beacons. There should be exactly one coordinator, usually the
+    /// gateway of a star.
+    pub coordinator: bool,
+    pub slot: Duration,
+    /// The owners of the slots in order, including the coordinator. Only used by the
+    /// coordinator, stations learn the schedule from its beacons.
+    pub stations: Vec<usize>,
+}
+
+impl AcsmaTdmaConfig {
+    pub fn new(coordinator: bool, slot: Duration, stations: Vec<usize>) -> Self {
+        Self {
+            coordinator,
+            slot,
+            stations,
+        }
+    }
+
+    /// Check that the beacons of the coordinator at `mac` fit into a frame and give it a slot.
+    pub fn validate(&self, mac: usize) -> Result<()> {
+        if !self.coordinator {
+            return Ok(());
+        }
+        let max_len = TDMA_MAX_STATIONS_LEN.min((1 << BEACON_COUNT_BITS_LEN) - 1);
+        if self.stations.len() > max_len {
+            return Err(AcsmaIoError::InvalidSchedule(format!(
+                "{} stations, at most {} fit into a beacon",
+                self.stations.len(),
+                max_len
+            ))
+            .into());
+        }
+        if !self.stations.contains(&mac) {
+            return Err(AcsmaIoError::InvalidSchedule(format!(
+                "the coordinator {} has no slot",
+                mac
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The TDMA schedule as known by a node. Slots are counted from the end of the last beacon, so
+/// stations align to the coordinator without synchronized clocks. Without a coordinator to align
+/// to, stations fall back to CSMA/CD.
+pub(super) struct AcsmaTdmaSchedule {
+    mac: usize,
+    config: AcsmaTdmaConfig,
+    created: Instant,
+    current: Option<(Instant, Duration, Vec<usize>)>,
+}
+
+impl AcsmaTdmaSchedule {
+    pub fn new(mac: usize, config: AcsmaTdmaConfig) -> Self {
+        Self {
+            mac,
+            config,
+            created: Instant::now(),
+            current: None,
+        }
+    }
+}
+
+impl AcsmaTdmaSchedule {
+    /// When the next beacon is due, if this node is the coordinator. That is the end of the last
+    /// superframe.
+    pub fn next_beacon(&self) -> Option<Instant> {
+        if !self.config.coordinator {
+            return None;
+        }
+        Some(match &self.current {
+            Some((start, slot, stations)) => *start + *slot * stations.len().max(1) as u32,
+            None => Instant::now(),
+        })
+    }
+
+    /// The beacon to send, if it is due.
+    pub fn beacon(&self) -> Option<BeaconFrame> {
+        let is_due = self.next_beacon()? <= Instant::now();
+        is_due.then(|| BeaconFrame::new(self.mac, self.config.slot, self.config.stations.clone()))
+    }
+
+    pub fn receive(&mut self, beacon: &BeaconFrame, is_own: bool) {
+        if self.config.coordinator && !is_own {
+            log::warn!("Ignore beacon of another coordinator");
+            return;
+        }
+        self.current = Some((Instant::now(), beacon.slot(), beacon.stations().to_vec()));
+    }
+
+    /// How long to wait before a frame exchange taking `airtime` (the frame plus its ACK) fits
+    /// into our own slot, or `None` if it may be sent right away.
+    pub fn wait(&self, airtime: Duration) -> Option<Duration> {
+        let current = self.current.as_ref().filter(|(start, slot, stations)| {
+            start.elapsed() < *slot * stations.len().max(1) as u32 + SOCKET_BEACON_TIMEOUT
+        });
+        let Some((start, slot, stations)) = current else {
+            // Give the coordinator time to show up before contending.
+            let elapsed = self.created.elapsed();
+            return (elapsed < SOCKET_BEACON_TIMEOUT).then_some(self.config.slot);
+        };
+        // Stations left out of the schedule contend for the medium like CSMA/CD.
+        let index = stations.iter().position(|station| *station == self.mac)?;
+
+        let elapsed = start.elapsed();
+        let begin = *slot * index as u32;
+        let end = begin + *slot;
+        if elapsed < begin {
+            Some(begin - elapsed)
+        } else if elapsed + airtime <= end {
+            None
+        } else {
+            // Our slot is over, wait for it in the next superframe.
+            let period = *slot * stations.len() as u32;
+            Some((period + begin).saturating_sub(elapsed).max(*slot))
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use super::builtin::{
+    SOCKET_ACK_TIMEOUT, SOCKET_COLISION_THRESHOLD, SOCKET_FREE_THRESHOLD, SOCKET_MAX_ACK_TIMEOUT,
+    SOCKET_MAX_RANGE, SOCKET_MAX_RESENDS, SOCKET_MIN_ACK_TIMEOUT, SOCKET_RECIEVE_TIMEOUT,
+    SOCKET_REFERENCE_BIT_RATE, SOCKET_SLOT_TIMEOUT,
+};
+use crate::rather::{
+    builtin::{LENGTH_BITS_LEN, PAYLOAD_BITS_LEN, PREAMBLE_SYMBOL_LEN, WARMUP_SYMBOL_LEN},
+    AtherStreamConfig,
+};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Timing and threshold parameters of the MAC.
+#[derive(Debug, Clone)]
+pub struct AcsmaTimingConfig {
+    /// Minimum length of a backoff slot. The slot of a frame is stretched to cover its airtime
+    /// and the ACK timeout.
+    pub slot: Duration,
+    /// How long to wait for the ACK of a frame before backing off and resending it, until the
+    /// round trip time to the peer has been measured.
+    pub ack_timeout: Duration,
+    /// How long the daemon keeps listening after receiving a data frame before it sends frames
+    /// of its own, so that the rest of a window from the peer is not met by a collision.
+    pub receive_timeout: Duration,
+    pub max_resends: usize,
+    /// Upper bound of the backoff range, in slots.
+    pub max_range: usize,
+    /// Energy below which the medium is considered free.
+    pub free_threshold: f32,
+    /// Energy above which our own transmission is considered to collide.
+    pub collision_threshold: f32,
+}
+
+impl AcsmaTimingConfig {
+    pub fn new(
+        slot: Duration,
+        ack_timeout: Duration,
+        receive_timeout: Duration,
+        max_resends: usize,
+        max_range: usize,
+        free_threshold: f32,
+        collision_threshold: f32,
+    ) -> Self {
+        Self {
+            slot,
+            ack_timeout,
+            receive_timeout,
+            max_resends,
+            max_range,
+            free_threshold,
+            collision_threshold,
+        }
+    }
+
+    /// The builtin timings scaled from the frame duration at `SOCKET_REFERENCE_BIT_RATE` to the
+    /// frame duration of the given PHY.
+    pub fn from_ather(config: &AtherStreamConfig) -> Self {
+        let scale = frame_duration(config.bit_rate).as_secs_f64()
+            / frame_duration(SOCKET_REFERENCE_BIT_RATE).as_secs_f64();
+        Self::new(
+            SOCKET_SLOT_TIMEOUT.mul_f64(scale),
+            SOCKET_ACK_TIMEOUT.mul_f64(scale),
+            SOCKET_RECIEVE_TIMEOUT.mul_f64(scale),
+            SOCKET_MAX_RESENDS,
+            SOCKET_MAX_RANGE,
+            SOCKET_FREE_THRESHOLD,
+            SOCKET_COLISION_THRESHOLD,
+        )
+    }
+}
+
+/// Airtime of the longest frame the PHY sends at the given bit rate.
+pub fn frame_duration(bit_rate: u32) -> Duration {
+    let symbols = WARMUP_SYMBOL_LEN + PREAMBLE_SYMBOL_LEN + LENGTH_BITS_LEN + PAYLOAD_BITS_LEN;
+    Duration::from_secs_f64(symbols as f64 / bit_rate as f64)
+}
+
+/// Smoothed round trip time and its variation as in RFC 6298, giving the ACK timeout of a peer.
+/// Only frames that were sent once are sampled (Karn's algorithm), and the timeout doubles with
+/// every loss event until the next sample. The frames of a window lost at once are one event.
+pub(super) struct AcsmaRttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    timeout: Duration,
+    backed_off: Option<Instant>,
+}
+
+impl AcsmaRttEstimator {
+    pub(super) fn new(timeout: Duration) -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            timeout,
+            backed_off: None,
+        }
+    }
+
+    pub(super) fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.timeout =
+            (srtt + self.rttvar * 4).clamp(SOCKET_MIN_ACK_TIMEOUT, SOCKET_MAX_ACK_TIMEOUT);
+    }
+
+    /// Back off at `now` for a frame sent at `sent` that timed out. Frames sent until the last
+    /// back off belong to the same loss event.
+    pub(super) fn back_off(&mut self, sent: Instant, now: Instant) {
+        if self.backed_off.is_some_and(|backed_off| sent <= backed_off) {
+            return;
+        }
+        self.backed_off = Some(now);
+        self.timeout = (self.timeout * 2).min(SOCKET_MAX_ACK_TIMEOUT);
+    }
+
+    pub(super) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_estimator() {
+        let mut estimator = AcsmaRttEstimator::new(SOCKET_ACK_TIMEOUT);
+        assert_eq!(estimator.timeout(), SOCKET_ACK_TIMEOUT);
+
+        estimator.sample(Duration::from_millis(40));
+        assert_eq!(estimator.timeout(), Duration::from_millis(120));
+
+        for _ in 0..32 {
+            estimator.sample(Duration::from_millis(40));
+        }
+        assert!(estimator.timeout() < Duration::from_millis(45));
+        assert!(estimator.timeout() >= Duration::from_millis(40));
+
+        let timeout = estimator.timeout();
+        let base = Instant::now();
+        let at = |ms| base + Duration::from_millis(ms);
+        estimator.back_off(at(0), at(100));
+        estimator.back_off(at(0), at(200));
+        assert_eq!(estimator.timeout(), timeout * 2);
+        for i in 0..16 {
+            estimator.back_off(at(300 + i * 100), at(350 + i * 100));
+        }
+        assert_eq!(estimator.timeout(), SOCKET_MAX_ACK_TIMEOUT);
+    }
+}
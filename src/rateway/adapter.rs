@@ -1,16 +1,16 @@
-use super::AtewayIoError;
+use super::{builtin::ADAPTER_QUEUE_LEN, AtewayIoError};
 use crate::{
     racsma::{
-        builtin::SOCKET_BROADCAST_ADDRESS, AcsmaIoSocket, AcsmaSocketConfig, AcsmaSocketReader,
-        AcsmaSocketWriter,
+        builtin::SOCKET_BROADCAST_ADDRESS, AcsmaIoSocket, AcsmaSocketConfig, AcsmaTrafficClass,
+        Link, LinkReader, LinkWriter,
     },
     rather::encode::{DecodeToBytes, EncodeFromBytes},
     raudio::AsioDevice,
 };
 use anyhow::Result;
 use futures::{
-    future::BoxFuture,
-    stream::{SplitSink, SplitStream},
+    future::{self, BoxFuture},
+    stream::{FuturesUnordered, SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use ipnet::Ipv4Net;
@@ -27,12 +27,12 @@ use std::{
 };
 use tokio::{
     sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        mpsc,
         oneshot::{self, Sender},
     },
     task::JoinHandle,
 };
-use tokio_util::codec::Framed;
+use tokio_util::{codec::Framed, sync::CancellationToken};
 use tun::{AsyncDevice, Configuration, TunPacket, TunPacketCodec};
 
 #[derive(Clone)]
@@ -65,6 +65,7 @@ impl AtewayAdapterConfig {
 pub struct AtewayIoAdaper {
     config: AtewayAdapterConfig,
     device: AsioDevice,
+    token: CancellationToken,
     inner: Option<BoxFuture<'static, Result<()>>>,
 }
 
@@ -73,9 +74,15 @@ impl AtewayIoAdaper {
         Self {
             config,
             device,
+            token: CancellationToken::new(),
             inner: None,
         }
     }
+
+    /// Cancelling the token shuts the adapter down, after which it completes with `Ok`.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
 }
 
 impl Future for AtewayIoAdaper {
@@ -86,7 +93,7 @@ impl Future for AtewayIoAdaper {
         if this.inner.is_none() {
             let config = this.config.clone();
             let device = this.device.clone();
-            let inner = Box::pin(adapter_daemon(config, device));
+            let inner = Box::pin(adapter_daemon(config, device, this.token.clone()));
             this.inner.replace(inner);
         }
         let inner = this.inner.as_mut().unwrap();
@@ -100,9 +107,25 @@ impl Future for AtewayIoAdaper {
     }
 }
 
-async fn adapter_daemon(config: AtewayAdapterConfig, device: AsioDevice) -> Result<()> {
-    let (tx_socket, rx_socket) =
-        AcsmaIoSocket::try_from_device(config.socket_config.clone(), &device)?;
+async fn adapter_daemon(
+    config: AtewayAdapterConfig,
+    device: AsioDevice,
+    token: CancellationToken,
+) -> Result<()> {
+    let (tx_socket, rx_socket, socket) =
+        AcsmaIoSocket::try_from_device(config.socket_config.clone(), &device).await?;
+    let result = run_adapter(config, (tx_socket, rx_socket), token).await;
+    let shutdown = socket.shutdown().await;
+    result.and(shutdown)
+}
+
+/// Run the adapter over any link, until one of its daemons fails or the token is cancelled.
+pub async fn run_adapter<L: Link>(
+    config: AtewayAdapterConfig,
+    link: L,
+    token: CancellationToken,
+) -> Result<()> {
+    let (tx_link, rx_link) = link.split();
 
     let dev = {
         let mut tun_config = Configuration::default();
@@ -119,23 +142,18 @@ async fn adapter_daemon(config: AtewayAdapterConfig, device: AsioDevice) -> Resu
     }?;
     let (tx_tun, rx_tun) = dev.into_framed().split();
 
-    let (write_tx, write_rx) = mpsc::unbounded_channel();
+    let (write_tx, write_rx) = mpsc::channel(ADAPTER_QUEUE_LEN);
 
-    let write_handle = tokio::spawn(write_daemon(config.clone(), tx_socket, write_rx));
+    let write_handle = tokio::spawn(write_daemon(config.clone(), tx_link, write_rx));
     let receive_handle = tokio::spawn(receive_daemon(
         config.clone(),
         write_tx.clone(),
-        rx_socket,
+        rx_link,
         tx_tun,
     ));
     let send_handle = tokio::spawn(send_daemon(config, write_tx, rx_tun));
 
-    tokio::try_join!(
-        flatten(write_handle),
-        flatten(receive_handle),
-        flatten(send_handle)
-    )?;
-    Ok(())
+    join_daemons(token, vec![write_handle, receive_handle, send_handle]).await
 }
 
 pub(super) async fn flatten<T>(handle: JoinHandle<Result<T>>) -> Result<T> {
@@ -146,50 +164,102 @@ pub(super) async fn flatten<T>(handle: JoinHandle<Result<T>>) -> Result<T> {
     }
 }
 
+/// Run the daemons of a gateway until one of them fails or the token is cancelled, then stop the
+/// others.
+pub(super) async fn join_daemons(
+    token: CancellationToken,
+    daemons: Vec<JoinHandle<Result<()>>>,
+) -> Result<()> {
+    let aborts = daemons
+        .iter()
+        .map(|daemon| daemon.abort_handle())
+        .collect::<Vec<_>>();
+    let result = tokio::select! {
+        result = future::try_join_all(daemons.into_iter().map(flatten)) => result.map(|_| ()),
+        _ = token.cancelled() => Ok(()),
+    };
+    for abort in aborts {
+        abort.abort();
+    }
+    result
+}
+
 pub(super) type AtewayAdapterTask = (Ipv4Packet<Vec<u8>>, Sender<Result<()>>);
 
-async fn write_daemon(
+/// Packets up to this many bytes are small enough to be ACKs or keystrokes, and are sent ahead
+/// of bulk transfers.
+const INTERACTIVE_PACKET_LEN: usize = 128;
+
+pub(super) fn classify_packet(packet: &Ipv4Packet<Vec<u8>>) -> AcsmaTrafficClass {
+    if packet.protocol() == Protocol::Icmp || packet.as_ref().len() <= INTERACTIVE_PACKET_LEN {
+        AcsmaTrafficClass::Interactive
+    } else {
+        AcsmaTrafficClass::Bulk
+    }
+}
+
+async fn write_daemon<W: LinkWriter>(
     config: AtewayAdapterConfig,
-    tx_socket: AcsmaSocketWriter,
-    mut write_rx: UnboundedReceiver<AtewayAdapterTask>,
+    tx_link: W,
+    mut write_rx: mpsc::Receiver<AtewayAdapterTask>,
 ) -> Result<()> {
-    let gateway_mac = tx_socket
-        .arp(u32::from_be_bytes(config.gateway.octets()) as usize)
-        .await
-        .map_err(|_| AtewayIoError::GatewayUnreachable(config.gateway));
     let net = Ipv4Net::with_netmask(config.address, config.netmask)?;
-    while let Some((packet, tx)) = write_rx.recv().await {
-        let ip = packet.destination();
-        let dest = if ip == net.broadcast() || ip.is_broadcast() {
-            Ok(SOCKET_BROADCAST_ADDRESS)
-        } else if net.contains(&ip) {
-            log::debug!("Resolving MAC address: {}", ip);
-            tx_socket
-                .arp(u32::from_be_bytes(ip.octets()) as usize)
-                .await
-        } else {
-            gateway_mac.map_err(|err| err.into())
-        };
-
-        let result = match dest {
-            Ok(inner) => {
-                log::debug!("Resolve MAC address: {} -> {}", ip, inner);
-                tx_socket.write(inner, &packet.as_ref().encode()).await
-            }
-            Err(err) => Err(err),
-        };
-        tx.send(result).ok();
+    let (config, tx_link) = (&config, &tx_link);
+    // Packets are written concurrently, so that the socket can schedule them by their class.
+    let mut writes = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            task = write_rx.recv() => match task {
+                Some((packet, tx)) => writes.push(async move {
+                    let result = write_task(config, net, tx_link, &packet).await;
+                    tx.send(result).ok();
+                }),
+                None => break,
+            },
+            Some(()) = writes.next(), if !writes.is_empty() => {}
+        }
     }
+    while writes.next().await.is_some() {}
     Ok(())
 }
 
-async fn receive_daemon(
+async fn write_task<W: LinkWriter>(
+    config: &AtewayAdapterConfig,
+    net: Ipv4Net,
+    tx_link: &W,
+    packet: &Ipv4Packet<Vec<u8>>,
+) -> Result<()> {
+    let ip = packet.destination();
+    let dest = if ip == net.broadcast() || ip.is_broadcast() {
+        SOCKET_BROADCAST_ADDRESS
+    } else if net.contains(&ip) {
+        log::debug!("Resolving MAC address: {}", ip);
+        tx_link
+            .resolve(u32::from_be_bytes(ip.octets()) as usize)
+            .await?
+    } else {
+        // Resolutions are cached by the socket, so this only goes to the medium once per TTL.
+        tx_link
+            .resolve(u32::from_be_bytes(config.gateway.octets()) as usize)
+            .await
+            .map_err(|_| AtewayIoError::GatewayUnreachable(config.gateway))?
+    };
+
+    log::debug!("Resolve MAC address: {} -> {}", ip, dest);
+    let bits = packet.as_ref().encode();
+    // Drop instead of waiting when the link is congested, so that TCP backs off.
+    tx_link
+        .try_write_packet(dest, &bits, classify_packet(packet))
+        .await
+}
+
+async fn receive_daemon<R: LinkReader>(
     config: AtewayAdapterConfig,
-    write_tx: UnboundedSender<AtewayAdapterTask>,
-    mut rx_socket: AcsmaSocketReader,
+    write_tx: mpsc::Sender<AtewayAdapterTask>,
+    mut rx_link: R,
     mut tx_tun: SplitSink<Framed<AsyncDevice, TunPacketCodec>, TunPacket>,
 ) -> Result<()> {
-    while let Ok(packet) = rx_socket.read_unchecked().await {
+    while let Ok((_, packet)) = rx_link.read_packet().await {
         let bytes = DecodeToBytes::decode(&packet);
         if let Ok(ip::Packet::V4(mut packet)) = ip::Packet::new(bytes) {
             let src = packet.source();
@@ -231,7 +301,7 @@ async fn receive_daemon(
 
 async fn send_daemon(
     config: AtewayAdapterConfig,
-    write_tx: UnboundedSender<AtewayAdapterTask>,
+    write_tx: mpsc::Sender<AtewayAdapterTask>,
     mut rx_tun: SplitStream<Framed<AsyncDevice, TunPacketCodec>>,
 ) -> Result<()> {
     while let Some(Ok(packet)) = rx_tun.next().await {
@@ -260,11 +330,42 @@ async fn send_daemon(
 }
 
 pub(super) async fn write_packet(
-    write_tx: &UnboundedSender<AtewayAdapterTask>,
+    write_tx: &mpsc::Sender<AtewayAdapterTask>,
     packet: Ipv4Packet<Vec<u8>>,
 ) -> Result<()> {
     let (tx, rx) = oneshot::channel();
-    write_tx.send((packet, tx))?;
+    write_tx.send((packet, tx)).await?;
     rx.await??;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::tests::{echo, socket_config},
+        *,
+    };
+    use crate::racsma::AcsmaMemoryLink;
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs the privileges to create a TUN device"]
+    async fn test_adapter() {
+        let address = Ipv4Addr::new(10, 0, 0, 2);
+        let peer = Ipv4Addr::new(10, 0, 0, 1);
+        let raw = |ip: Ipv4Addr| u32::from_be_bytes(ip.octets()) as usize;
+        let (link, far) = AcsmaMemoryLink::pair((2, raw(address)), (1, raw(peer)), 1 << 12);
+        let config = AtewayAdapterConfig::new(
+            "rateway-test".to_string(),
+            address,
+            Ipv4Addr::new(255, 255, 255, 0),
+            peer,
+            socket_config(),
+        );
+        let token = CancellationToken::new();
+        let adapter = tokio::spawn(run_adapter(config, link, token.clone()));
+
+        echo(far, peer, address, 2).await.unwrap();
+        token.cancel();
+        adapter.await.unwrap().unwrap();
+    }
+}
@@ -7,6 +7,9 @@ use std::{
 pub const NAT_PORT_RANGE: Range<u16> = 10000..14999;
 pub const NAT_SENDTO_PLACEHOLDER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
 
+/// Packets waiting for the link in a gateway, beyond which reading from the host waits.
+pub const ADAPTER_QUEUE_LEN: usize = 64;
+
 pub const SOCKET_ARP_TIMEOUT: Duration = Duration::from_millis(1000);
 
 pub const TCP_BUFFER_LEN: u16 = 1024;
@@ -6,8 +6,8 @@ pub mod builtin;
 pub mod tcp;
 pub mod tools;
 
-pub use adapter::{AtewayAdapterConfig, AtewayIoAdaper};
-pub use nat::{AtewayIoNat, AtewayNatConfig};
+pub use adapter::{run_adapter, AtewayAdapterConfig, AtewayIoAdaper};
+pub use nat::{run_nat, AtewayIoNat, AtewayNatConfig};
 pub use socket::AtewayIoSocket;
 
 use std::net::Ipv4Addr;
@@ -22,3 +22,69 @@ pub enum AtewayIoError {
     #[error("Gateway {0} unreachable")]
     GatewayUnreachable(Ipv4Addr),
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        racsma::{
+            AcsmaMemoryLink, AcsmaSocketConfig, AcsmaTrafficClass, Link, LinkReader, LinkWriter,
+        },
+        rather::{
+            encode::{DecodeToBytes, EncodeFromBytes},
+            AtherStreamConfig,
+        },
+    };
+    use anyhow::Result;
+    use cpal::{SampleFormat, SampleRate, SupportedBufferSize, SupportedStreamConfig};
+    use packet::{icmp, ip, Builder, Packet};
+    use std::{net::Ipv4Addr, time::Duration};
+    use tokio::time;
+
+    /// The gateways only take the socket config to open their socket, which a link replaces.
+    pub(super) fn socket_config() -> AcsmaSocketConfig {
+        let stream_config = SupportedStreamConfig::new(
+            1,
+            SampleRate(48000),
+            SupportedBufferSize::Unknown,
+            SampleFormat::F32,
+        );
+        AcsmaSocketConfig::new(0, None, AtherStreamConfig::new(24000, stream_config))
+    }
+
+    /// Ping `dest` from `src` at the far end of a link, whose other end has the address `mac`.
+    pub(super) async fn echo(
+        link: AcsmaMemoryLink,
+        src: Ipv4Addr,
+        dest: Ipv4Addr,
+        mac: usize,
+    ) -> Result<()> {
+        let (tx_link, mut rx_link) = link.split();
+        let request = ip::v4::Builder::default()
+            .id(1)?
+            .ttl(64)?
+            .source(src)?
+            .destination(dest)?
+            .icmp()?
+            .echo()?
+            .request()?
+            .identifier(42)?
+            .sequence(0)?
+            .payload(b"rateway")?
+            .build()?;
+        tx_link
+            .write_packet(mac, &request.encode(), AcsmaTrafficClass::Interactive)
+            .await?;
+
+        let (from, bits) = time::timeout(Duration::from_secs(5), rx_link.read_packet()).await??;
+        assert_eq!(from, mac);
+        let ip::Packet::V4(reply) = ip::Packet::new(DecodeToBytes::decode(&bits))? else {
+            panic!("expected an IPv4 packet");
+        };
+        assert_eq!((reply.source(), reply.destination()), (dest, src));
+        let icmp = icmp::Packet::new(reply.payload())?;
+        let echo = icmp.echo()?;
+        assert!(echo.is_reply());
+        assert_eq!(echo.identifier(), 42);
+        Ok(())
+    }
+}
@@ -1,23 +1,23 @@
 use super::{
-    adapter::{flatten, write_packet, AtewayAdapterTask},
-    builtin::NAT_PORT_RANGE,
+    adapter::{classify_packet, join_daemons, write_packet, AtewayAdapterTask},
+    builtin::{ADAPTER_QUEUE_LEN, NAT_PORT_RANGE},
     AtewayIoError, AtewayIoSocket,
 };
 use crate::{
     racsma::{
-        builtin::SOCKET_BROADCAST_ADDRESS, AcsmaIoSocket, AcsmaSocketConfig, AcsmaSocketReader,
-        AcsmaSocketWriter,
+        builtin::SOCKET_BROADCAST_ADDRESS, AcsmaIoSocket, AcsmaSocketConfig, Link, LinkReader,
+        LinkWriter,
     },
     rather::encode::{DecodeToBytes, EncodeFromBytes},
     raudio::AsioDevice,
 };
 use anyhow::Result;
-use futures::future::BoxFuture;
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
 use ipnet::Ipv4Net;
 use lru::LruCache;
 use packet::{
     ether, icmp,
-    ip::{self, Protocol},
+    ip::{self, v4::Packet as Ipv4Packet, Protocol},
     tcp, udp, Packet, PacketMut,
 };
 use parking_lot::Mutex;
@@ -32,10 +32,8 @@ use std::{
     sync::Arc,
     task::{Context, Poll},
 };
-use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    task,
-};
+use tokio::{sync::mpsc, task};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Clone)]
 pub struct AtewayNatConfig {
@@ -70,6 +68,7 @@ impl AtewayNatConfig {
 pub struct AtewayIoNat {
     config: AtewayNatConfig,
     device: AsioDevice,
+    token: CancellationToken,
     inner: Option<BoxFuture<'static, Result<()>>>,
 }
 
@@ -78,9 +77,15 @@ impl AtewayIoNat {
         Self {
             config,
             device,
+            token: CancellationToken::new(),
             inner: None,
         }
     }
+
+    /// Cancelling the token shuts the NAT down, after which it completes with `Ok`.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
 }
 
 impl Future for AtewayIoNat {
@@ -91,7 +96,7 @@ impl Future for AtewayIoNat {
         if this.inner.is_none() {
             let config = this.config.clone();
             let device = this.device.clone();
-            let inner = Box::pin(nat_daemon(config, device));
+            let inner = Box::pin(nat_daemon(config, device, this.token.clone()));
             this.inner.replace(inner);
         }
         let inner = this.inner.as_mut().unwrap();
@@ -114,14 +119,30 @@ pub(super) fn find_device(ip: Ipv4Addr) -> Result<Device> {
     Err(AtewayIoError::DeviceNotFound(ip).into())
 }
 
-async fn nat_daemon(config: AtewayNatConfig, device: AsioDevice) -> Result<()> {
+async fn nat_daemon(
+    config: AtewayNatConfig,
+    device: AsioDevice,
+    token: CancellationToken,
+) -> Result<()> {
+    let (tx_socket, rx_socket, socket) =
+        AcsmaIoSocket::try_from_device(config.socket_config.clone(), &device).await?;
+    let result = run_nat(config, (tx_socket, rx_socket), token).await;
+    let shutdown = socket.shutdown().await;
+    result.and(shutdown)
+}
+
+/// Run the NAT over any link, until one of its daemons fails or the token is cancelled.
+pub async fn run_nat<L: Link>(
+    config: AtewayNatConfig,
+    link: L,
+    token: CancellationToken,
+) -> Result<()> {
     let table = Arc::new(Mutex::new(AtewayNatTable::new(
         NAT_PORT_RANGE,
         config.route_config.clone(),
     )));
 
-    let (tx_socket, rx_socket) =
-        AcsmaIoSocket::try_from_device(config.socket_config.clone(), &device)?;
+    let (tx_link, rx_link) = link.split();
 
     let raw_socket = AtewayIoSocket::try_new(config.host)?;
 
@@ -129,67 +150,81 @@ async fn nat_daemon(config: AtewayNatConfig, device: AsioDevice) -> Result<()> {
     let mut cap = Capture::from_device(device)?.immediate_mode(true).open()?;
     cap.filter(&format!("ip dst host {}", config.host), true)?;
 
-    let (write_tx, write_rx) = mpsc::unbounded_channel();
+    let (write_tx, write_rx) = mpsc::channel(ADAPTER_QUEUE_LEN);
 
-    let write_handle = tokio::spawn(write_daemon(config.clone(), tx_socket, write_rx));
+    let write_handle = tokio::spawn(write_daemon(config.clone(), tx_link, write_rx));
     let receive_handle = tokio::spawn(receive_daemon(
         config.clone(),
         write_tx.clone(),
-        rx_socket,
+        rx_link,
         raw_socket,
         table.clone(),
     ));
     let send_handle = tokio::spawn(send_daemon(config, write_tx, cap, table));
 
-    tokio::try_join!(
-        flatten(write_handle),
-        flatten(receive_handle),
-        flatten(send_handle)
-    )?;
-    Ok(())
+    join_daemons(token, vec![write_handle, receive_handle, send_handle]).await
 }
 
-async fn write_daemon(
+async fn write_daemon<W: LinkWriter>(
     config: AtewayNatConfig,
-    tx_socket: AcsmaSocketWriter,
-    mut write_rx: UnboundedReceiver<AtewayAdapterTask>,
+    tx_link: W,
+    mut write_rx: mpsc::Receiver<AtewayAdapterTask>,
 ) -> Result<()> {
     let net = Ipv4Net::with_netmask(config.address, config.netmask)?;
-    while let Some((packet, tx)) = write_rx.recv().await {
-        let ip = packet.destination();
-        let dest = if ip == net.broadcast() || ip.is_broadcast() {
-            Ok(SOCKET_BROADCAST_ADDRESS)
-        } else if net.contains(&ip) {
-            log::debug!("Resolving MAC address: {}", ip);
-            tx_socket
-                .arp(u32::from_be_bytes(ip.octets()) as usize)
-                .await
-        } else {
-            Ok(config.socket_config.mac)
-        };
-
-        let result = match dest {
-            Ok(inner) => {
-                log::debug!("Resolve MAC address: {} -> {}", ip, inner);
-                tx_socket.write(inner, &packet.as_ref().encode()).await
-            }
-            Err(err) => Err(err),
-        };
-        let _ = tx.send(result);
+    let (config, tx_link) = (&config, &tx_link);
+    let mut writes = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            task = write_rx.recv() => match task {
+                Some((packet, tx)) => writes.push(async move {
+                    let result = write_task(config, net, tx_link, &packet).await;
+                    let _ = tx.send(result);
+                }),
+                None => break,
+            },
+            Some(()) = writes.next(), if !writes.is_empty() => {}
+        }
     }
+    while writes.next().await.is_some() {}
     Ok(())
 }
 
-async fn receive_daemon(
+async fn write_task<W: LinkWriter>(
+    config: &AtewayNatConfig,
+    net: Ipv4Net,
+    tx_link: &W,
+    packet: &Ipv4Packet<Vec<u8>>,
+) -> Result<()> {
+    let ip = packet.destination();
+    let dest = if ip == net.broadcast() || ip.is_broadcast() {
+        SOCKET_BROADCAST_ADDRESS
+    } else if net.contains(&ip) {
+        log::debug!("Resolving MAC address: {}", ip);
+        tx_link
+            .resolve(u32::from_be_bytes(ip.octets()) as usize)
+            .await?
+    } else {
+        tx_link.address()
+    };
+
+    log::debug!("Resolve MAC address: {} -> {}", ip, dest);
+    let bits = packet.as_ref().encode();
+    // Drop instead of waiting when the link is congested, so that TCP backs off.
+    tx_link
+        .try_write_packet(dest, &bits, classify_packet(packet))
+        .await
+}
+
+async fn receive_daemon<R: LinkReader>(
     config: AtewayNatConfig,
-    write_tx: UnboundedSender<AtewayAdapterTask>,
-    mut rx_socket: AcsmaSocketReader,
+    write_tx: mpsc::Sender<AtewayAdapterTask>,
+    mut rx_link: R,
     mut raw_socket: AtewayIoSocket,
     table: Arc<Mutex<AtewayNatTable>>,
 ) -> Result<()> {
     let net = Ipv4Net::with_netmask(config.address, config.netmask)?;
 
-    while let Ok(packet) = rx_socket.read_unchecked().await {
+    while let Ok((_, packet)) = rx_link.read_packet().await {
         let bytes = DecodeToBytes::decode(&packet);
         if let Ok(ip::Packet::V4(mut packet)) = ip::Packet::new(bytes) {
             let src = packet.source();
@@ -275,7 +310,7 @@ async fn receive_daemon(
 
 async fn send_daemon(
     config: AtewayNatConfig,
-    write_tx: UnboundedSender<AtewayAdapterTask>,
+    write_tx: mpsc::Sender<AtewayAdapterTask>,
     mut cap: Capture<Active>,
     table: Arc<Mutex<AtewayNatTable>>,
 ) -> Result<()> {
@@ -456,3 +491,37 @@ impl AtewayNatTable {
             .or_else(|| self.dyn_table.backward(port))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::tests::{echo, socket_config},
+        *,
+    };
+    use crate::racsma::AcsmaMemoryLink;
+    use std::env;
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs RATEWAY_TEST_HOST, the address of a host adapter with a gateway"]
+    async fn test_nat() {
+        let host = env::var("RATEWAY_TEST_HOST").unwrap().parse().unwrap();
+        let address = Ipv4Addr::new(10, 0, 0, 254);
+        let peer = Ipv4Addr::new(10, 0, 0, 1);
+        let raw = |ip: Ipv4Addr| u32::from_be_bytes(ip.octets()) as usize;
+        let (link, far) = AcsmaMemoryLink::pair((2, raw(address)), (1, raw(peer)), 1 << 12);
+        let config = AtewayNatConfig::new(
+            "rateway-test".to_string(),
+            address,
+            Ipv4Addr::new(255, 255, 255, 0),
+            host,
+            socket_config(),
+            None,
+        );
+        let token = CancellationToken::new();
+        let nat = tokio::spawn(run_nat(config, link, token.clone()));
+
+        echo(far, peer, address, 2).await.unwrap();
+        token.cancel();
+        nat.await.unwrap().unwrap();
+    }
+}
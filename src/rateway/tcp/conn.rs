@@ -1,5 +1,8 @@
 use crate::rather::encode::EncodeFromBytes;
-use crate::{racsma::AcsmaSocketWriter, rateway::builtin::TCP_BUFFER_LEN};
+use crate::{
+    racsma::{builtin::SOCKET_BROADCAST_ADDRESS, AcsmaTrafficClass, LinkWriter},
+    rateway::builtin::TCP_BUFFER_LEN,
+};
 use anyhow::Result;
 use bitflags::bitflags;
 use etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
@@ -127,8 +130,8 @@ struct RecvSequenceSpace {
 }
 
 impl Connection {
-    pub async fn accept<'a>(
-        nic: &mut AcsmaSocketWriter,
+    pub async fn accept<'a, W: LinkWriter>(
+        nic: &mut W,
         iph: Ipv4HeaderSlice<'a>,
         tcph: TcpHeaderSlice<'a>,
         _data: &'a [u8],
@@ -199,9 +202,9 @@ impl Connection {
         Ok(Some(c))
     }
 
-    async fn write(
+    async fn write<W: LinkWriter>(
         &mut self,
-        nic: &mut AcsmaSocketWriter,
+        nic: &mut W,
         seq: u32,
         mut limit: usize,
     ) -> Result<usize> {
@@ -302,13 +305,17 @@ impl Connection {
         }
         self.timers.send_times.insert(seq, time::Instant::now());
 
-        nic.write_unchecked(&buf[..payload_ends_at].encode())
-            .await?;
+        nic.write_packet(
+            SOCKET_BROADCAST_ADDRESS,
+            &buf[..payload_ends_at].encode(),
+            AcsmaTrafficClass::Bulk,
+        )
+        .await?;
         // nic.send(&buf[..payload_ends_at])?;
         Ok(payload_bytes)
     }
 
-    async fn _send_rst(&mut self, nic: &mut AcsmaSocketWriter) -> Result<()> {
+    async fn _send_rst<W: LinkWriter>(&mut self, nic: &mut W) -> Result<()> {
         self.tcp.rst = true;
         // TODO: fix sequence numbers here
         // If the incoming segment has an ACK field, the reset takes its
@@ -331,7 +338,7 @@ impl Connection {
         Ok(())
     }
 
-    pub(crate) async fn _on_tick(&mut self, nic: &mut AcsmaSocketWriter) -> Result<()> {
+    pub(crate) async fn _on_tick<W: LinkWriter>(&mut self, nic: &mut W) -> Result<()> {
         if let State::FinWait2 | State::TimeWait = self.state {
             // we have shutdown our write side and the other side acked, no need to (re)transmit anything
             return Ok(());
@@ -391,9 +398,9 @@ impl Connection {
         Ok(())
     }
 
-    pub(crate) async fn on_packet<'a>(
+    pub(crate) async fn on_packet<'a, W: LinkWriter>(
         &mut self,
-        nic: &mut AcsmaSocketWriter,
+        nic: &mut W,
         _iph: Ipv4HeaderSlice<'a>,
         tcph: TcpHeaderSlice<'a>,
         data: &'a [u8],
@@ -4,7 +4,7 @@
 mod conn;
 
 use crate::{
-    racsma::{AcsmaIoSocket, AcsmaSocketConfig, AcsmaSocketReader, AcsmaSocketWriter},
+    racsma::{AcsmaIoSocket, AcsmaSocketConfig, AcsmaSocketHandle, Link, LinkReader, LinkWriter},
     rateway::builtin::TCP_BUFFER_LEN,
     rather::encode::DecodeToBytes,
     raudio::AsioDevice,
@@ -41,6 +41,7 @@ type InterfaceHandle = Arc<InterfaceHandleInner>;
 pub struct Interface {
     ih: Option<InterfaceHandle>,
     jh: Option<JoinHandle<Result<()>>>,
+    sh: Option<AcsmaSocketHandle>,
 }
 
 impl Drop for Interface {
@@ -48,6 +49,13 @@ impl Drop for Interface {
         self.ih.as_mut().unwrap().manager.blocking_lock().terminate = true;
 
         drop(self.ih.take());
+        // The packet loop only returns once the socket is closed. Other links are not closed by
+        // the interface, so the loop is stopped instead.
+        if let Some(socket) = self.sh.take() {
+            let _ = task::block_in_place(move || Handle::current().block_on(socket.shutdown()));
+        } else if let Some(inner) = self.jh.as_ref() {
+            inner.abort();
+        }
         if let Some(inner) = self.jh.take() {
             let _ = task::block_in_place(move || Handle::current().block_on(inner));
         }
@@ -61,15 +69,16 @@ struct ConnectionManager {
     pending: HashMap<u16, VecDeque<Quad>>,
 }
 
-async fn packet_loop(
-    mut write_socket: AcsmaSocketWriter,
-    mut read_socket: AcsmaSocketReader,
+async fn packet_loop<W: LinkWriter, R: LinkReader>(
+    mut write_link: W,
+    mut read_link: R,
     ih: InterfaceHandle,
 ) -> Result<()> {
     loop {
         // we want to read from nic, but we want to make sure that we'll wake up when the next
         // timer has to be triggered!
-        let buf = read_socket.read_unchecked().await?.decode();
+        let (_, packet) = read_link.read_packet().await?;
+        let buf = packet.decode();
         let nbytes = buf.len();
 
         // TODO: if self.terminate && Arc::get_strong_refs(ih) == 1; then tear down all connections and return.
@@ -110,7 +119,7 @@ async fn packet_loop(
                                 eprintln!("got packet for known quad {:?}", q);
                                 let a = c
                                     .get_mut()
-                                    .on_packet(&mut write_socket, iph, tcph, &buf[datai..nbytes])
+                                    .on_packet(&mut write_link, iph, tcph, &buf[datai..nbytes])
                                     .await?;
 
                                 // TODO: compare before/after
@@ -128,7 +137,7 @@ async fn packet_loop(
                                 {
                                     eprintln!("listening, so accepting");
                                     if let Some(c) = conn::Connection::accept(
-                                        &mut write_socket,
+                                        &mut write_link,
                                         iph,
                                         tcph,
                                         &buf[datai..nbytes],
@@ -157,19 +166,30 @@ async fn packet_loop(
 }
 
 impl Interface {
-    pub fn new(config: AcsmaSocketConfig, device: &AsioDevice) -> Result<Self> {
-        let (write_socket, read_socket) = AcsmaIoSocket::try_from_device(config, device)?;
+    pub async fn new(config: AcsmaSocketConfig, device: &AsioDevice) -> Result<Self> {
+        let (write_socket, read_socket, sh) =
+            AcsmaIoSocket::try_from_device(config, device).await?;
+
+        let mut interface = Self::with_link((write_socket, read_socket));
+        interface.sh = Some(sh);
+        Ok(interface)
+    }
+
+    /// An interface over any link. The link is dropped once the interface is.
+    pub fn with_link<L: Link>(link: L) -> Self {
+        let (write_link, read_link) = link.split();
 
         let ih: InterfaceHandle = Arc::default();
         let jh = {
             let ih = ih.clone();
-            tokio::spawn(packet_loop(write_socket, read_socket, ih))
+            tokio::spawn(packet_loop(write_link, read_link, ih))
         };
 
-        Ok(Interface {
+        Interface {
             ih: Some(ih),
             jh: Some(jh),
-        })
+            sh: None,
+        }
     }
 
     pub async fn bind(&mut self, port: u16) -> Result<TcpListener> {
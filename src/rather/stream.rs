@@ -7,20 +7,24 @@ use super::{
     signal, Preamble, Symbol, Warmup,
 };
 use crate::raudio::{
-    AudioInputStream, AudioOutputStream, AudioSamples, AudioTrack, ContinuousStream,
+    AudioInputStream, AudioOutputStream, AudioSamples, ContinuousStream, SharedSamples, SharedTrack,
 };
 use anyhow::Result;
 use bitvec::prelude::*;
 use cpal::SupportedStreamConfig;
+use rodio::{Sample, Source};
 // use log;
 use std::{
     mem,
     pin::Pin,
     sync::{Arc, Mutex},
     task::{self, Poll, Waker},
-    time::Duration,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedSender},
+    task::JoinHandle,
 };
-use tokio::sync::mpsc::{self, UnboundedSender};
 use tokio_stream::{Stream, StreamExt};
 
 #[derive(Debug, Clone)]
@@ -60,22 +64,89 @@ impl AtherOutputStream {
 
 impl AtherOutputStream {
     pub async fn write(&self, bits: &BitSlice) -> Result<()> {
+        self.write_samples(self.encode(bits)).await
+    }
+
+    pub async fn write_timeout(&self, bits: &BitSlice, timeout: Duration) -> Result<()> {
+        let track = SharedTrack::new(self.config.stream_config.clone(), self.encode(bits));
+        self.stream.write_timeout(track, timeout).await?;
+        Ok(())
+    }
+
+    /// Encode the bits into the exact waveform `write` would play, so that the caller can keep a
+    /// copy of it (e.g. for echo cancellation) before handing it to `write_samples`.
+    pub fn encode(&self, bits: &BitSlice) -> SharedSamples<f32> {
         let mut frame = vec![self.config.warmup.0.clone()];
         frame.push(encode_frame(&self.config, bits));
-        let track = AudioTrack::new(self.config.stream_config.clone(), frame.concat().into());
+        frame.concat().into()
+    }
+
+    pub async fn write_samples(&self, samples: SharedSamples<f32>) -> Result<()> {
+        let track = SharedTrack::new(self.config.stream_config.clone(), samples);
         self.stream.write(track).await?;
         Ok(())
     }
 
-    pub async fn write_timeout(&self, bits: &BitSlice, timeout: Duration) -> Result<()> {
-        let mut frame = vec![self.config.warmup.0.clone()];
-        frame.push(encode_frame(&self.config, bits));
-        let track = AudioTrack::new(self.config.stream_config.clone(), frame.concat().into());
-        self.stream.write_timeout(track, timeout).await?;
+    /// Like `write_samples`, calling `on_start` with the instant the output pulls the first
+    /// sample, which is when the waveform actually starts playing rather than when it was queued.
+    pub async fn write_samples_with<F>(
+        &self,
+        samples: SharedSamples<f32>,
+        on_start: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(Instant) + Send + 'static,
+    {
+        let track = SharedTrack::new(self.config.stream_config.clone(), samples);
+        let source = AtherStartedSource {
+            inner: track,
+            on_start: Some(Box::new(on_start)),
+        };
+        self.stream.write(source).await?;
         Ok(())
     }
 }
 
+struct AtherStartedSource<S> {
+    inner: S,
+    on_start: Option<Box<dyn FnOnce(Instant) + Send>>,
+}
+
+impl<S: Source> Iterator for AtherStartedSource<S>
+where
+    S::Item: Sample,
+{
+    type Item = S::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(on_start) = self.on_start.take() {
+            on_start(Instant::now());
+        }
+        self.inner.next()
+    }
+}
+
+impl<S: Source> Source for AtherStartedSource<S>
+where
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.inner.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
 fn encode_frame(config: &AtherStreamConfig, bits: &BitSlice) -> AudioSamples<f32> {
     assert!(bits.len() <= PAYLOAD_BITS_LEN);
 
@@ -127,21 +198,26 @@ impl AtherSymbolEncoding for BitSlice {
 pub struct AtherInputStream {
     task: AtherInputTask,
     sender: UnboundedSender<AtherInputTaskCmd>,
+    snr: Arc<Mutex<Option<f32>>>,
+    decoder: JoinHandle<()>,
 }
 
 impl AtherInputStream {
     pub fn new(config: AtherStreamConfig, mut stream: AudioInputStream<f32>) -> Self {
         let (sender, mut reciever) = mpsc::unbounded_channel();
         let task = Arc::new(Mutex::new(AtherInputTaskState::Pending));
-        tokio::spawn({
+        let snr = Arc::new(Mutex::new(None));
+        let decoder = tokio::spawn({
             let task = task.clone();
+            let snr = snr.clone();
             async move {
                 let mut buf = vec![];
                 while let Some(cmd) = reciever.recv().await {
                     match cmd {
                         AtherInputTaskCmd::Running => {
                             match decode_frame(&config, &mut stream, &mut buf).await {
-                                Some(bits) => {
+                                Some((bits, value)) => {
+                                    *snr.lock().unwrap() = value;
                                     let mut guard = task.lock().unwrap();
                                     match guard.take() {
                                         AtherInputTaskState::Running(waker) => {
@@ -188,11 +264,28 @@ impl AtherInputStream {
                 }
             }
         });
-        Self { sender, task }
+        Self {
+            sender,
+            task,
+            snr,
+            decoder,
+        }
+    }
+}
+
+impl Drop for AtherInputStream {
+    fn drop(&mut self) {
+        // The decoder may wait for a preamble forever, which would keep the audio stream open.
+        self.decoder.abort();
     }
 }
 
 impl AtherInputStream {
+    /// Estimated SNR in dB of the last frame returned by the stream.
+    pub fn snr(&self) -> Option<f32> {
+        *self.snr.lock().unwrap()
+    }
+
     pub async fn read(&mut self) -> BitVec {
         let mut result = bitvec![];
         while let Some(data) = self.next().await {
@@ -271,7 +364,7 @@ async fn decode_frame(
     config: &AtherStreamConfig,
     stream: &mut AudioInputStream<f32>,
     buf: &mut Vec<f32>,
-) -> Option<BitVec> {
+) -> Option<(BitVec, Option<f32>)> {
     let preamble_len = config.preamble.0.len();
     let symbol_len = config.symbols.0 .0.len();
 
@@ -314,11 +407,13 @@ async fn decode_frame(
     let length = DecodeToInt::<usize>::decode(&length).min(PAYLOAD_BITS_LEN);
 
     let mut payload = bitvec![];
+    let mut values = vec![];
     while payload.len() < length {
         if buf.len() >= symbol_len {
             let symbol = buf[..symbol_len].to_owned();
             let value = signal::dot_product(&config.symbols.1 .0, &symbol);
             payload.push(value > 0.);
+            values.push(value.abs());
 
             *buf = buf.split_off(symbol_len);
         } else {
@@ -329,7 +424,22 @@ async fn decode_frame(
         }
     }
 
-    Some(payload)
+    Some((payload, estimate_snr(&values)))
+}
+
+/// The correlator outputs of the symbols spread around the signal amplitude, with the noise as
+/// their variance.
+fn estimate_snr(values: &[f32]) -> Option<f32> {
+    if values.len() < 2 {
+        return None;
+    }
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f32>()
+        / (values.len() - 1) as f32;
+    Some(10. * (mean.powi(2) / variance.max(f32::EPSILON)).log10())
 }
 
 impl ContinuousStream for AtherInputStream {
@@ -0,0 +1,20 @@
+use rathernet::racsma::AcsmaSimScenarioFile;
+use std::{fs, path::Path};
+
+#[test]
+fn test_scenario_files() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/acsma/sim");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("toml") {
+            continue;
+        }
+        let file = AcsmaSimScenarioFile::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        let report = file.translate().run().unwrap();
+        if let Some(expect) = &file.expect {
+            expect
+                .check(&report)
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        }
+    }
+}